@@ -0,0 +1,343 @@
+//! Headless tem, for scripting the same vault operations as the app. It isn't
+//! called tem because that's the app binary.
+//!
+//! Usage: tem-cli [--vault <dir>] [--json] <command> [args]
+//!
+//!   diff <a> <b> [--word-diff=plain] [--context <n>] [--width <n>] [--patch]
+//!                                      word diff of two files, or the temsync patch with --patch
+//!   patch apply <patch> <file> [--in-place]
+//!                                      applies a temsync patch, printing the result
+//!   tags [<query>]                     tags with their note counts, or the notes matching a query
+//!   search <query> [--limit <n>]       full text search
+//!   log <note>                         revisions captured for a note, with what each changed
+//!   check                              broken links, orphans and duplicate names
+//!   export (--query <q> | --folder <f>) <out-dir>
+//!                                      static site export
+//!
+//! The vault defaults to $TEM_VAULT, or ~/tem. Diffs are colored when stdout is
+//! a terminal (and $NO_COLOR isn't set), and wrapped to $COLUMNS. With --json
+//! every command prints one JSON value instead. Usage errors exit with 2, failures with 1, and check
+//! exits with 1 when it found anything.
+
+use std::{
+    env,
+    error::Error,
+    fs,
+    io::{stdout, IsTerminal},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use serde::Serialize;
+use tem_lib::{
+    temsync::{
+        meyers::get_diff,
+        patch::{apply_patch, generate_patch, read_patch, tokens_to_string},
+        tokenizer::parse_string_tokens,
+        worddiff::{render_word_diff, WordDiffOptions, WordDiffStyle},
+    },
+    vault::{
+        self,
+        check::check_vault,
+        diff::diff_stats,
+        export::{export_site, ExportSelection},
+        files::write_atomic,
+        history::{History, RevisionInfo, RevisionPolicy},
+        links::LinkIndex,
+        query::query_notes,
+        search::SearchIndex,
+        tags::TagIndex,
+    },
+};
+
+const USAGE: &str = "usage: tem-cli [--vault <dir>] [--json] <command> [args]
+commands:
+  diff <a> <b> [--word-diff=plain] [--context <n>] [--width <n>] [--patch]
+  patch apply <patch> <file> [--in-place]
+  tags [<query>]
+  search <query> [--limit <n>]
+  log <note>
+  check
+  export (--query <query> | --folder <folder>) <out-dir>";
+
+/// Results printed by search when no limit is given
+const SEARCH_LIMIT: usize = 20;
+
+/// Options every command takes
+struct Cli {
+    root: PathBuf,
+    json: bool,
+}
+
+/// Why a command didn't finish
+enum Failure {
+    Usage,
+    Error(Box<dyn Error>),
+}
+
+impl<E: Into<Box<dyn Error>>> From<E> for Failure {
+    fn from(e: E) -> Self {
+        Failure::Error(e.into())
+    }
+}
+
+type CommandResult = Result<ExitCode, Failure>;
+
+fn main() -> ExitCode {
+    let mut cli = Cli {
+        root: vault::default_dir(),
+        json: false,
+    };
+    let mut args = Vec::<String>::new();
+
+    // Parse the global options, anything else belongs to the command
+    let mut raw = env::args().skip(1);
+    while let Some(arg) = raw.next() {
+        match arg.as_str() {
+            "--json" => cli.json = true,
+            "--vault" => match raw.next() {
+                Some(v) => cli.root = PathBuf::from(v),
+                None => return usage(),
+            },
+            _ => args.push(arg),
+        }
+    }
+
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => return usage(),
+    };
+    let result = match command {
+        "diff" => diff(&cli, args),
+        "patch" => patch(&cli, args),
+        "tags" => tags(&cli, args),
+        "search" => search(&cli, args),
+        "log" => log(&cli, args),
+        "check" => check(&cli, args),
+        "export" => export(&cli, args),
+        _ => Err(Failure::Usage),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(Failure::Usage) => usage(),
+        Err(Failure::Error(e)) => {
+            eprintln!("tem-cli {}: {}", command, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{}", USAGE);
+    ExitCode::from(2)
+}
+
+/// Prints a value as JSON, or runs the human readable printer
+fn print<T: Serialize>(cli: &Cli, value: &T, human: impl FnOnce(&T)) -> CommandResult {
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    } else {
+        human(value);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn diff(cli: &Cli, args: &[String]) -> CommandResult {
+    let terminal = stdout().is_terminal();
+    let mut options = WordDiffOptions {
+        style: match terminal && env::var_os("NO_COLOR").is_none() {
+            true => WordDiffStyle::Color,
+            false => WordDiffStyle::Plain,
+        },
+        width: env::var("COLUMNS")
+            .ok()
+            .filter(|_| terminal)
+            .and_then(|c| c.parse().ok()),
+        context: None,
+    };
+    let mut patch = false;
+    let mut files = Vec::<&String>::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut number = || -> Result<usize, Failure> {
+            args.next()
+                .and_then(|n| n.parse().ok())
+                .ok_or(Failure::Usage)
+        };
+        match arg.as_str() {
+            "--word-diff=plain" => options.style = WordDiffStyle::Plain,
+            "--context" => options.context = Some(number()?),
+            "--width" => options.width = Some(number()?),
+            "--patch" => patch = true,
+            _ if !arg.starts_with("--") => files.push(arg),
+            _ => return Err(Failure::Usage),
+        }
+    }
+    let [a, b] = files[..] else {
+        return Err(Failure::Usage);
+    };
+
+    let (prev, curr) = (fs::read_to_string(a)?, fs::read_to_string(b)?);
+    let edits = get_diff(&parse_string_tokens(&prev)?, &parse_string_tokens(&curr)?)?;
+    if cli.json {
+        return print(cli, &edits, |_| ());
+    }
+    let out = match patch {
+        true => generate_patch(&edits),
+        false => render_word_diff(&edits, &prev, &curr, &options)?,
+    };
+    print!("{}", out);
+    if !out.is_empty() && !out.ends_with('\n') {
+        println!();
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+#[derive(Serialize)]
+struct Patched {
+    file: String,
+    content: String,
+}
+
+fn patch(cli: &Cli, args: &[String]) -> CommandResult {
+    let (patch, file, in_place) = match args {
+        [apply, patch, file] if apply == "apply" => (patch, file, false),
+        [apply, patch, file, flag] if apply == "apply" && flag == "--in-place" => {
+            (patch, file, true)
+        }
+        _ => return Err(Failure::Usage),
+    };
+    let edits = read_patch(&fs::read_to_string(patch)?)?;
+    let base = parse_string_tokens(&fs::read_to_string(file)?)?;
+    let content = tokens_to_string(&apply_patch(&base, &edits)?);
+    if in_place {
+        write_atomic(Path::new(file), &content)?;
+    }
+
+    let patched = Patched {
+        file: file.clone(),
+        content,
+    };
+    print(cli, &patched, |p| match in_place {
+        true => println!("patched {}", p.file),
+        false => print!("{}", p.content),
+    })
+}
+
+fn tags(cli: &Cli, args: &[String]) -> CommandResult {
+    let index = TagIndex::scan(&cli.root)?;
+    match args {
+        [] => print(cli, &index.tags(), |tags| {
+            for tag in tags.iter() {
+                println!("{}\t#{}", tag.count, tag.name);
+            }
+        }),
+        [query] => print(cli, &query_notes(&index, query)?, |notes| {
+            for note in notes.iter() {
+                println!("{}", note);
+            }
+        }),
+        _ => Err(Failure::Usage),
+    }
+}
+
+fn search(cli: &Cli, args: &[String]) -> CommandResult {
+    let (query, limit) = match args {
+        [query] => (query, SEARCH_LIMIT),
+        [query, flag, n] | [flag, n, query] if flag == "--limit" => {
+            (query, n.parse().map_err(|_| Failure::Usage)?)
+        }
+        _ => return Err(Failure::Usage),
+    };
+
+    // The app's saved index is only a cache, a missing one is rebuilt here
+    let index = SearchIndex::open(&cli.root).or_else(|_| SearchIndex::scan(&cli.root))?;
+    print(cli, &index.search(query, limit), |hits| {
+        for hit in hits.iter() {
+            let snippet = hit
+                .snippet
+                .iter()
+                .map(|p| match p.highlight {
+                    true => format!("[{}]", p.text),
+                    false => p.text.clone(),
+                })
+                .collect::<String>();
+            println!(
+                "{}\t{:.2}\t{}",
+                hit.note,
+                hit.score,
+                snippet.replace('\n', " ")
+            );
+        }
+    })
+}
+
+fn log(cli: &Cli, args: &[String]) -> CommandResult {
+    let [note] = args else {
+        return Err(Failure::Usage);
+    };
+    let history = History::new(&cli.root, RevisionPolicy::default());
+
+    // Each revision is summed up against the one before it
+    let mut log = Vec::<LogEntry>::new();
+    let mut prev = String::new();
+    for info in history.log(note)? {
+        let curr = history.content_at(note, info.revision)?;
+        let edits = get_diff(&parse_string_tokens(&prev)?, &parse_string_tokens(&curr)?)?;
+        let summary = diff_stats(&edits, &prev, &curr)?.summary;
+        log.push(LogEntry { info, summary });
+        prev = curr;
+    }
+    print(cli, &log, |log| {
+        for LogEntry { info: r, summary } in log.iter() {
+            println!(
+                "{}\t{}\t+{} -{}\t{}",
+                r.revision, r.at, r.inserted, r.deleted, summary
+            );
+        }
+    })
+}
+
+#[derive(Serialize)]
+struct LogEntry {
+    #[serde(flatten)]
+    info: RevisionInfo,
+    summary: String,
+}
+
+fn check(cli: &Cli, args: &[String]) -> CommandResult {
+    if !args.is_empty() {
+        return Err(Failure::Usage);
+    }
+    let diagnostics = check_vault(&LinkIndex::scan(&cli.root)?)?;
+    print(cli, &diagnostics, |diagnostics| {
+        for d in diagnostics.iter() {
+            println!("{}", d);
+        }
+    })?;
+    match diagnostics.is_empty() {
+        true => Ok(ExitCode::SUCCESS),
+        false => Ok(ExitCode::FAILURE),
+    }
+}
+
+fn export(cli: &Cli, args: &[String]) -> CommandResult {
+    let (selection, out) = match args {
+        [flag, value, out] if flag == "--query" => (ExportSelection::Query(value.clone()), out),
+        [flag, value, out] if flag == "--folder" => (ExportSelection::Folder(value.clone()), out),
+        _ => return Err(Failure::Usage),
+    };
+    let tags = TagIndex::scan(&cli.root)?;
+    let links = LinkIndex::scan(&cli.root)?;
+    let summary = export_site(&tags, &links, &selection, &PathBuf::from(out))?;
+    print(cli, &summary, |s| {
+        println!(
+            "exported {} notes and {} attachments to {}",
+            s.notes.len(),
+            s.attachments.len(),
+            out
+        )
+    })
+}
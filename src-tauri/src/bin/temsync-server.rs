@@ -0,0 +1,92 @@
+//! Standalone temsync server, holds the canonical revision store for syncing clients.
+//!
+//! Usage: temsync-server [--addr 127.0.0.1:7878] [--store ./store.temsync] [--tokens <file>]
+//!        temsync-server --tokens <file> --add-device <name>
+//!
+//! With --tokens, every request needs one of the device tokens in the file.
+//! --add-device registers a device, prints its token and exits.
+
+use std::{env, path::PathBuf, process::ExitCode};
+
+use tem_lib::temsync::{
+    auth::DeviceTokens,
+    server::{RevisionStore, SyncServer},
+};
+
+const USAGE: &str = "usage: temsync-server [--addr <host:port>] [--store <dir>] [--tokens <file>] [--add-device <name>]";
+
+fn main() -> ExitCode {
+    let mut addr = "127.0.0.1:7878".to_string();
+    let mut store_dir = PathBuf::from("./store.temsync");
+    let mut tokens_file = None::<PathBuf>;
+    let mut add_device = None::<String>;
+
+    // Parse args
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--addr", Some(v)) => addr = v,
+            ("--store", Some(v)) => store_dir = PathBuf::from(v),
+            ("--tokens", Some(v)) => tokens_file = Some(PathBuf::from(v)),
+            ("--add-device", Some(v)) => add_device = Some(v),
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let tokens = match &tokens_file {
+        Some(path) => match DeviceTokens::open(path) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                eprintln!("unable to read tokens {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    // Register a device and exit
+    if let Some(device) = add_device {
+        let mut tokens = match tokens {
+            Some(t) => t,
+            None => {
+                eprintln!("--add-device needs --tokens <file>");
+                return ExitCode::FAILURE;
+            }
+        };
+        return match tokens.add_device(&device) {
+            Ok(token) => {
+                println!("{}", token);
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("unable to add device: {}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let store = match RevisionStore::open(&store_dir) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("unable to open store {}: {}", store_dir.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let server = match SyncServer::bind(&addr, store) {
+        Ok(s) => match tokens {
+            Some(t) => s.with_auth(t),
+            None => s,
+        },
+        Err(e) => {
+            eprintln!("unable to bind {}: {}", addr, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!("temsync server listening on http://{}", server.local_addr());
+    server.run();
+    ExitCode::SUCCESS
+}
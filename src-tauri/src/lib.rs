@@ -0,0 +1,5 @@
+//! Shared library for the tem app and its companion binaries (eg. the temsync server).
+//! Anything that needs to run outside of the Tauri window lives here.
+
+pub mod temsync;
+pub mod vault;
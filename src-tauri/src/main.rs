@@ -1,25 +1,774 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-mod temsync;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use serde::Serialize;
+use serde_json::json;
+use tauri::{
+    http::{header, Response, StatusCode},
+    AppHandle, Emitter, Manager, RunEvent, State,
+};
+use tem_lib::temsync::{
+    edit::Edit,
+    meyers::get_diff,
+    refine::{refine, Refinement},
+    tokenizer::parse_string_tokens,
+};
+use tem_lib::vault::{
+    self,
+    check::{self, Diagnostic},
+    diff::{self, DiffLayout, DiffStats},
+    export::{self, ExportSelection, ExportSummary},
+    files::{self, NoteInfo},
+    frontmatter::{self, Frontmatter, Property, PropertyIssue, PropertyValue, Schema},
+    history::{History, RevisionPolicy},
+    links::{Backlink, LinkIndex, Mention, OutgoingLink},
+    markdown::{self, OutlineItem},
+    query::{self, QueryError, SavedQueries, SavedQuery},
+    refactor::{self, Refactor},
+    render::{self, AppUrls},
+    search::{SearchHit, SearchIndex},
+    share::{ShareHandle, ShareInfo, ShareServer},
+    tags::{TagIndex, TagNode},
+    watcher::{self, OwnWrites, VaultEvent, VaultWatcher, DEBOUNCE_WINDOW},
+};
+
+/// Everything the commands share, one vault per window
+struct AppState {
+    root: PathBuf,
+    tags: Mutex<TagIndex>,
+    queries: Mutex<SavedQueries>,
+    history: Mutex<History>,
+    search: Mutex<SearchIndex>,
+    links: Mutex<LinkIndex>,
+    /// Share server, once it's been started
+    share: Mutex<Option<ShareHandle>>,
+    /// Saves from the editor, whose watcher events are dropped
+    own_writes: Mutex<OwnWrites>,
+}
+
+/// Results returned by search_notes when no limit is given
+const SEARCH_LIMIT: usize = 50;
+
+#[tauri::command]
+fn load_tags(state: State<'_, AppState>) -> Vec<TagNode> {
+    state.tags.lock().unwrap().tree()
+}
+
+#[tauri::command]
+fn list_notes_for_tag(tag: String, state: State<'_, AppState>) -> Vec<String> {
+    state.tags.lock().unwrap().notes_for_tag(&tag)
+}
+
+#[tauri::command]
+fn query_notes(query: String, state: State<'_, AppState>) -> Result<Vec<String>, QueryError> {
+    query::query_notes(&state.tags.lock().unwrap(), &query)
+}
+
+#[tauri::command]
+fn list_saved_queries(state: State<'_, AppState>) -> Vec<SavedQuery> {
+    state.queries.lock().unwrap().list().to_vec()
+}
+
+#[tauri::command]
+fn save_query(
+    name: String,
+    query: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    state
+        .queries
+        .lock()
+        .unwrap()
+        .save(&name, &query)
+        .map_err(|e| e.to_string())?;
+    notify(&app, "queries-changed");
+    Ok(())
+}
+
+#[tauri::command]
+fn delete_saved_query(
+    name: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    state
+        .queries
+        .lock()
+        .unwrap()
+        .remove(&name)
+        .map_err(|e| e.to_string())?;
+    notify(&app, "queries-changed");
+    Ok(())
+}
+
+/// Renames a tag in every note. With dry_run, only returns the planned edits.
+#[tauri::command]
+fn rename_tag(
+    old: String,
+    new: String,
+    dry_run: bool,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Refactor, String> {
+    refactor_tags(&app, &state, &old, &new, false, dry_run)
+}
+
+/// Like rename_tag, but into a tag that may already be in use
+#[tauri::command]
+fn merge_tags(
+    from: String,
+    into: String,
+    dry_run: bool,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Refactor, String> {
+    refactor_tags(&app, &state, &from, &into, true, dry_run)
+}
+
+#[tauri::command]
+fn undo_refactor(
+    id: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Refactor, String> {
+    let undone = refactor::undo_refactor(
+        &mut state.tags.lock().unwrap(),
+        &mut state.history.lock().unwrap(),
+        &id,
+    )?;
+    reindex_search(&state, &undone);
+    notify(&app, "tags-changed");
+    Ok(undone)
+}
+
+fn refactor_tags(
+    app: &AppHandle,
+    state: &AppState,
+    old: &str,
+    new: &str,
+    merge: bool,
+    dry_run: bool,
+) -> Result<Refactor, String> {
+    let mut tags = state.tags.lock().unwrap();
+    let plan = refactor::plan_rename(&tags, old, new, merge)?;
+    if !dry_run {
+        refactor::apply_refactor(&mut tags, &mut state.history.lock().unwrap(), &plan)?;
+        reindex_search(state, &plan);
+        notify(app, "tags-changed");
+    }
+    Ok(plan)
+}
+
+/// Full text search, best matches first
+#[tauri::command]
+fn search_notes(query: String, limit: Option<usize>, state: State<'_, AppState>) -> Vec<SearchHit> {
+    state
+        .search
+        .lock()
+        .unwrap()
+        .search(&query, limit.unwrap_or(SEARCH_LIMIT))
+}
+
+#[tauri::command]
+fn get_backlinks(note: String, state: State<'_, AppState>) -> Vec<Backlink> {
+    state.links.lock().unwrap().backlinks(&note)
+}
+
+#[tauri::command]
+fn get_outgoing_links(note: String, state: State<'_, AppState>) -> Vec<OutgoingLink> {
+    state.links.lock().unwrap().outgoing(&note)
+}
+
+/// Other notes that mention the note's name without linking to it
+#[tauri::command]
+fn get_unlinked_mentions(note: String, state: State<'_, AppState>) -> Vec<Mention> {
+    state.links.lock().unwrap().unlinked_mentions(&note)
+}
+
+/// Broken links, orphans and duplicate names across the vault
+#[tauri::command]
+fn check_vault(state: State<'_, AppState>) -> Result<Vec<Diagnostic>, String> {
+    check::check_vault(&state.links.lock().unwrap()).map_err(|e| e.to_string())
+}
+
+/// Renders a note to HTML for the preview, from unsaved content when given
+#[tauri::command]
+fn render_note(
+    note: String,
+    content: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<String, String> {
+    let content = match content {
+        Some(content) => content,
+        None => files::read_note(&state.root, &note).map_err(|e| e.to_string())?,
+    };
+    let links = state.links.lock().unwrap();
+    Ok(render::render_html(&links, &note, &content, &AppUrls))
+}
+
+/// Stylesheet for the code blocks in rendered notes
+#[tauri::command]
+fn get_highlight_css() -> String {
+    render::highlight_css()
+}
+
+/// Writes the selected notes to a folder outside the vault as a static site
+#[tauri::command]
+fn export_site(
+    selection: ExportSelection,
+    out: String,
+    state: State<'_, AppState>,
+) -> Result<ExportSummary, String> {
+    let tags = state.tags.lock().unwrap();
+    let links = state.links.lock().unwrap();
+    export::export_site(&tags, &links, &selection, Path::new(&out)).map_err(|e| e.to_string())
+}
+
+/// Starts serving shares on a local port, 0 picks a free one. Returns the address it listens on.
+#[tauri::command]
+fn start_share_server(port: u16, state: State<'_, AppState>) -> Result<String, String> {
+    let mut share = state.share.lock().unwrap();
+    if let Some(handle) = share.as_ref() {
+        return Err(format!(
+            "share server is already running on {}",
+            handle.addr()
+        ));
+    }
+    let server = ShareServer::bind(&format!("127.0.0.1:{}", port), &state.root)
+        .map_err(|e| e.to_string())?;
+    let handle = server.spawn();
+    let addr = handle.addr().to_string();
+    *share = Some(handle);
+    Ok(addr)
+}
+
+/// Stops the share server, every share ends with it
+#[tauri::command]
+fn stop_share_server(state: State<'_, AppState>) {
+    if let Some(handle) = state.share.lock().unwrap().take() {
+        handle.shutdown();
+    }
+}
+
+#[tauri::command]
+fn share_notes(
+    selection: ExportSelection,
+    state: State<'_, AppState>,
+) -> Result<ShareInfo, String> {
+    let share = state.share.lock().unwrap();
+    let handle = share.as_ref().ok_or("share server isn't running")?;
+    let tags = state.tags.lock().unwrap();
+    let links = state.links.lock().unwrap();
+    handle
+        .share(&tags, &links, &selection)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn unshare(token: String, state: State<'_, AppState>) -> bool {
+    match state.share.lock().unwrap().as_ref() {
+        Some(handle) => handle.unshare(&token),
+        None => false,
+    }
+}
+
+#[tauri::command]
+fn list_shares(state: State<'_, AppState>) -> Vec<ShareInfo> {
+    match state.share.lock().unwrap().as_ref() {
+        Some(handle) => handle.shares(),
+        None => Vec::new(),
+    }
+}
+
+/// Serves files in the vault to the preview over the attachment protocol. The
+/// sandbox keeps scripts in an SVG from running if one is opened directly.
+fn attachment_response(root: &Path, uri_path: &str) -> Response<Vec<u8>> {
+    let error = |status: StatusCode, message: String| {
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(message.into_bytes())
+            .unwrap()
+    };
+    let path = match render::attachment_file(root, uri_path) {
+        Ok(path) => path,
+        Err(e) => return error(StatusCode::NOT_FOUND, e.to_string()),
+    };
+    match fs::read(&path) {
+        Ok(data) => Response::builder()
+            .header(header::CONTENT_TYPE, render::content_type(&path))
+            .header(header::CONTENT_SECURITY_POLICY, "sandbox")
+            .body(data)
+            .unwrap(),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+/// Headings of a note as a tree, each with the source range of its section
+#[tauri::command]
+fn get_outline(note: String, state: State<'_, AppState>) -> Result<Vec<OutlineItem>, String> {
+    let content = files::read_note(&state.root, &note).map_err(|e| e.to_string())?;
+    Ok(markdown::outline(&content))
+}
+
+#[tauri::command]
+fn list_notes(state: State<'_, AppState>) -> Result<Vec<NoteInfo>, String> {
+    files::list_notes(&state.root).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn read_note(note: String, state: State<'_, AppState>) -> Result<String, String> {
+    files::read_note(&state.root, &note).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn create_note(
+    note: String,
+    content: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    files::create_note(&state.root, &note, &content).map_err(|e| e.to_string())?;
+    index_note(&state, &note, &content);
+    notify(&app, "tags-changed");
+    Ok(())
+}
+
+/// Writes the note and captures a revision if the change is big or old enough.
+/// Returns the new revision, if there is one.
+#[tauri::command]
+fn save_note(
+    note: String,
+    content: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Option<usize>, String> {
+    // The tab already has this content, so it doesn't need to hear about it
+    state.own_writes.lock().unwrap().record(&note, &content);
+    let revision = store_note(&state, &note, &content)?;
+    notify(&app, "tags-changed");
+    Ok(revision)
+}
+
+/// Frontmatter properties of a note, with anything that doesn't fit the vault's schema
+#[derive(Serialize)]
+struct NoteProperties {
+    properties: Vec<Property>,
+    issues: Vec<PropertyIssue>,
+}
+
+#[tauri::command]
+fn get_properties(note: String, state: State<'_, AppState>) -> Result<NoteProperties, String> {
+    let content = files::read_note(&state.root, &note).map_err(|e| e.to_string())?;
+    note_properties(&state, &content)
+}
+
+/// Sets a frontmatter property, or removes it when value is null. Values that
+/// don't fit the vault's schema are refused.
+#[tauri::command]
+fn set_property(
+    note: String,
+    name: String,
+    value: Option<PropertyValue>,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<NoteProperties, String> {
+    if let Some(value) = &value {
+        let schema = Schema::open(&state.root).map_err(|e| e.to_string())?;
+        schema.check(&name, value)?;
+    }
+    let content = files::read_note(&state.root, &note).map_err(|e| e.to_string())?;
+    let content =
+        frontmatter::set_property(&content, &name, value.as_ref()).map_err(|e| e.to_string())?;
+    store_note(&state, &note, &content)?;
+    notify(&app, "tags-changed");
+    note_properties(&state, &content)
+}
+
+fn note_properties(state: &AppState, content: &str) -> Result<NoteProperties, String> {
+    let schema = Schema::open(&state.root).map_err(|e| e.to_string())?;
+    match Frontmatter::parse(content) {
+        Ok(frontmatter) => Ok(NoteProperties {
+            issues: schema.validate(&frontmatter),
+            properties: frontmatter.properties().to_vec(),
+        }),
+        Err(e) => Ok(NoteProperties {
+            properties: vec![],
+            issues: vec![PropertyIssue {
+                name: String::new(),
+                line: 1,
+                message: e.to_string(),
+            }],
+        }),
+    }
+}
+
+#[tauri::command]
+fn note_revisions(note: String, state: State<'_, AppState>) -> Result<usize, String> {
+    state.history.lock().unwrap().revisions(&note)
+}
+
+#[tauri::command]
+fn note_at_revision(
+    note: String,
+    revision: usize,
+    state: State<'_, AppState>,
+) -> Result<String, String> {
+    state.history.lock().unwrap().content_at(&note, revision)
+}
+
+/// Changes between two revisions of a note as HTML, up to the note as it is
+/// now if `to` isn't given
+#[tauri::command]
+fn render_revision_diff(
+    note: String,
+    from: usize,
+    to: Option<usize>,
+    layout: DiffLayout,
+    state: State<'_, AppState>,
+) -> Result<String, String> {
+    let (prev, curr) = {
+        let history = state.history.lock().unwrap();
+        let prev = history.content_at(&note, from)?;
+        let curr = match to {
+            Some(to) => history.content_at(&note, to)?,
+            None => files::read_note(&state.root, &note).map_err(|e| e.to_string())?,
+        };
+        (prev, curr)
+    };
+    diff::render_diff_html(&diff_texts(&prev, &curr)?, &prev, &curr, layout)
+}
+
+/// What a revision changed since the one before it, for the revision list
+#[tauri::command]
+fn revision_stats(
+    note: String,
+    revision: usize,
+    state: State<'_, AppState>,
+) -> Result<DiffStats, String> {
+    let history = state.history.lock().unwrap();
+    let prev = history.content_at(&note, revision.saturating_sub(1))?;
+    let curr = history.content_at(&note, revision)?;
+    diff::diff_stats(&diff_texts(&prev, &curr)?, &prev, &curr)
+}
+
+/// Word edits between two texts, with the characters that changed inside
+/// replaced words
+#[derive(Serialize)]
+struct RefinedDiff {
+    edits: Vec<Edit>,
+    refinements: Vec<Refinement>,
+}
+
+/// Diffs two texts for the editor to highlight, refining replaced words down to
+/// characters when asked
+#[tauri::command]
+fn get_text_diff(prev: String, curr: String, refined: bool) -> Result<RefinedDiff, String> {
+    let edits = diff_texts(&prev, &curr)?;
+    let refinements = match refined {
+        true => refine(&edits, &prev, &curr)?,
+        false => vec![],
+    };
+    Ok(RefinedDiff { edits, refinements })
+}
+
+fn diff_texts(prev: &str, curr: &str) -> Result<Vec<Edit>, String> {
+    let a = parse_string_tokens(prev).map_err(|e| e.to_string())?;
+    let b = parse_string_tokens(curr).map_err(|e| e.to_string())?;
+    get_diff(&a, &b)
+}
+
+/// Stylesheet for the diffs from render_revision_diff
+#[tauri::command]
+fn get_diff_css() -> String {
+    diff::DIFF_CSS.into()
+}
+
+#[tauri::command]
+fn rename_note(
+    from: String,
+    to: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    files::rename_note(&state.root, &from, &to).map_err(|e| e.to_string())?;
+    reindex_moved(&state, &from, &to)?;
+    notify(&app, "tags-changed");
+    Ok(())
+}
+
+/// Moves a note into a folder ("" for the vault root), returning its new path
+#[tauri::command]
+fn move_note(
+    note: String,
+    folder: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<String, String> {
+    let to = files::move_note(&state.root, &note, &folder).map_err(|e| e.to_string())?;
+    reindex_moved(&state, &note, &to)?;
+    notify(&app, "tags-changed");
+    Ok(to)
+}
+
+/// Moves a note to the trash, returning its path in there
+#[tauri::command]
+fn delete_note(note: String, app: AppHandle, state: State<'_, AppState>) -> Result<String, String> {
+    let trashed = files::delete_note(&state.root, &note).map_err(|e| e.to_string())?;
+    state.tags.lock().unwrap().remove_note(&note);
+    state.search.lock().unwrap().remove_note(&note);
+    state.links.lock().unwrap().remove_note(&note);
+    refresh_shares(&state, &note);
+    notify(&app, "tags-changed");
+    Ok(trashed)
+}
+
+#[tauri::command]
+fn list_trash(state: State<'_, AppState>) -> Result<Vec<String>, String> {
+    files::list_trash(&state.root).map_err(|e| e.to_string())
+}
 
 #[tauri::command]
-fn load_tags() -> Vec<String> {
-    let out = vec![
-        "first".to_string(),
-        "second".to_string(),
-        "third".to_string(),
-    ];
-    return out;
+fn restore_note(
+    trashed: String,
+    to: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    files::restore_note(&state.root, &trashed, &to).map_err(|e| e.to_string())?;
+    let content = files::read_note(&state.root, &to).map_err(|e| e.to_string())?;
+    index_note(&state, &to, &content);
+    notify(&app, "tags-changed");
+    Ok(())
+}
+
+/// Merges a change made on disk into a tab with unsaved edits. base is the
+/// content the tab was loaded from. Returns null when the changes conflict.
+#[tauri::command]
+fn rebase_buffer(base: String, buffer: String, disk: String) -> Result<Option<String>, String> {
+    watcher::rebase_buffer(&base, &buffer, &disk)
+}
+
+/// Keeps the index in sync with changes from other programs and tells the front end
+fn on_vault_events(app: &AppHandle, events: Vec<VaultEvent>) {
+    let state = app.state::<AppState>();
+    for event in events.iter() {
+        // The app's own saves are already indexed and in their tab
+        if let VaultEvent::Changed { note } = event {
+            let content = files::read_note(&state.root, note).ok();
+            let mut own = state.own_writes.lock().unwrap();
+            match content {
+                Some(content) if own.is_own(note, &content) => continue,
+                Some(_) => (),
+                None => own.forget(note),
+            }
+        }
+        event.apply(&state.root, &mut *state.tags.lock().unwrap());
+        event.apply(&state.root, &mut *state.search.lock().unwrap());
+        event.apply(&state.root, &mut *state.links.lock().unwrap());
+        match event {
+            VaultEvent::Changed { note } | VaultEvent::Deleted { note } => {
+                refresh_shares(&state, note)
+            }
+            VaultEvent::Renamed { from, to } => {
+                refresh_shares(&state, from);
+                refresh_shares(&state, to);
+            }
+        }
+        let sent = match event {
+            VaultEvent::Changed { note } => match files::read_note(&state.root, note) {
+                Ok(content) => {
+                    app.emit("note-changed", json!({ "note": note, "content": content }))
+                }
+                Err(_) => continue,
+            },
+            VaultEvent::Deleted { note } => app.emit("note-deleted", json!({ "note": note })),
+            VaultEvent::Renamed { from, to } => {
+                app.emit("note-renamed", json!({ "from": from, "to": to }))
+            }
+        };
+        if let Err(e) = sent {
+            eprintln!("unable to send vault event: {}", e);
+        }
+    }
+    if !events.is_empty() {
+        notify(app, "tags-changed");
+    }
+}
+
+/// Tells the front end to reload a list it shows, eg. "tags-changed" for the tag tree
+fn notify(app: &AppHandle, event: &str) {
+    if let Err(e) = app.emit(event, ()) {
+        eprintln!("unable to send {}: {}", event, e);
+    }
+}
+
+fn reindex_moved(state: &AppState, from: &str, to: &str) -> Result<(), String> {
+    let mut history = state.history.lock().unwrap();
+    history.rename(from, to)?;
+    // Links to the note are rewritten to follow it
+    let rewritten = state.links.lock().unwrap().rename(from, to, &mut history)?;
+    drop(history);
+    let content = files::read_note(&state.root, to).map_err(|e| e.to_string())?;
+    state.tags.lock().unwrap().remove_note(from);
+    state.search.lock().unwrap().remove_note(from);
+    index_note(state, to, &content);
+    for note in rewritten.iter() {
+        let content = files::read_note(&state.root, note).map_err(|e| e.to_string())?;
+        index_note(state, note, &content);
+    }
+    Ok(())
+}
+
+/// Writes a note, updates the indexes and captures a revision if it's due
+fn store_note(state: &AppState, note: &str, content: &str) -> Result<Option<usize>, String> {
+    files::write_note(&state.root, note, content).map_err(|e| e.to_string())?;
+    index_note(state, note, content);
+    // The note is saved by now, so a revision that couldn't be captured isn't a failed save
+    let revision = state
+        .history
+        .lock()
+        .unwrap()
+        .record(note, content, SystemTime::now());
+    Ok(revision.unwrap_or_else(|e| {
+        eprintln!("unable to capture a revision of {}: {}", note, e);
+        None
+    }))
+}
+
+fn index_note(state: &AppState, note: &str, content: &str) {
+    state.tags.lock().unwrap().update_note(note, content);
+    state.search.lock().unwrap().update_note(note, content);
+    state.links.lock().unwrap().update_note(note, content);
+    refresh_shares(state, note);
+}
+
+/// Brings shared pages up to date with a note, once the indexes have it
+fn refresh_shares(state: &AppState, note: &str) {
+    if let Some(handle) = state.share.lock().unwrap().as_ref() {
+        let tags = state.tags.lock().unwrap();
+        let links = state.links.lock().unwrap();
+        handle.refresh(&tags, &links, note);
+    }
+}
+
+/// Brings the search index up to date with the notes a refactor rewrote
+fn reindex_search(state: &AppState, refactor: &Refactor) {
+    let mut search = state.search.lock().unwrap();
+    for edit in refactor.edits.iter() {
+        if let Ok(content) = files::read_note(&state.root, &edit.note) {
+            search.update_note(&edit.note, &content);
+        }
+    }
 }
 
 fn main() {
+    let root = vault::default_dir();
+    if let Err(e) = fs::create_dir_all(&root) {
+        eprintln!("unable to create vault {}: {}", root.display(), e);
+    }
+    let tags = TagIndex::scan(&root).unwrap_or_else(|e| {
+        eprintln!("unable to scan vault {}: {}", root.display(), e);
+        TagIndex::new(&root)
+    });
+    let queries = SavedQueries::open(&root).unwrap_or_else(|e| {
+        eprintln!("unable to read saved queries: {}", e);
+        SavedQueries::empty(&root)
+    });
+    let history = History::new(&root, RevisionPolicy::default());
+    let search = SearchIndex::open(&root).unwrap_or_else(|e| {
+        eprintln!("unable to load search index, rebuilding it: {}", e);
+        SearchIndex::scan(&root).unwrap_or_else(|_| SearchIndex::new(&root))
+    });
+    let links = LinkIndex::scan(&root).unwrap_or_else(|e| {
+        eprintln!("unable to scan links in {}: {}", root.display(), e);
+        LinkIndex::new(&root)
+    });
+
     tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
-        .invoke_handler(tauri::generate_handler![load_tags])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
-
-    // TEMP to get rid of the annoying unused code warnings
-    temsync::main();
+        .manage(AppState {
+            root,
+            tags: Mutex::new(tags),
+            queries: Mutex::new(queries),
+            history: Mutex::new(history),
+            search: Mutex::new(search),
+            links: Mutex::new(links),
+            share: Mutex::new(None),
+            own_writes: Mutex::new(OwnWrites::new()),
+        })
+        .register_uri_scheme_protocol(render::ATTACHMENT_SCHEME, |ctx, request| {
+            let state = ctx.app_handle().state::<AppState>();
+            attachment_response(&state.root, request.uri().path())
+        })
+        .setup(|app| {
+            let handle = app.handle().clone();
+            let root = app.state::<AppState>().root.clone();
+            let watcher = VaultWatcher::start(&root, DEBOUNCE_WINDOW, move |events| {
+                on_vault_events(&handle, events)
+            })?;
+            app.manage(Mutex::new(watcher));
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            load_tags,
+            list_notes_for_tag,
+            query_notes,
+            list_saved_queries,
+            save_query,
+            delete_saved_query,
+            rename_tag,
+            merge_tags,
+            undo_refactor,
+            list_notes,
+            read_note,
+            create_note,
+            save_note,
+            rename_note,
+            move_note,
+            delete_note,
+            list_trash,
+            restore_note,
+            rebase_buffer,
+            note_revisions,
+            note_at_revision,
+            render_revision_diff,
+            revision_stats,
+            get_text_diff,
+            get_diff_css,
+            search_notes,
+            get_backlinks,
+            get_outgoing_links,
+            get_unlinked_mentions,
+            check_vault,
+            get_outline,
+            render_note,
+            get_highlight_css,
+            export_site,
+            start_share_server,
+            stop_share_server,
+            share_notes,
+            unshare,
+            list_shares,
+            get_properties,
+            set_property
+        ])
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            // The search index is saved on the way out, rebuilding it is the slow part of startup
+            if let RunEvent::Exit = event {
+                if let Err(e) = app.state::<AppState>().search.lock().unwrap().save() {
+                    eprintln!("unable to save search index: {}", e);
+                }
+            }
+        });
 }
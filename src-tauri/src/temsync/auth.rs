@@ -0,0 +1,122 @@
+use std::{
+    error::Error,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use sha2::{Digest, Sha256};
+
+/// Per-device tokens the sync server accepts. Only a hash of each token is kept,
+/// one `<device> <sha256>` pair per line in the tokens file.
+#[derive(Debug, Default)]
+pub struct DeviceTokens {
+    path: Option<PathBuf>,
+    devices: Vec<(String, String)>,
+}
+
+impl DeviceTokens {
+    /// Creates an empty set that is only kept in memory
+    pub fn new() -> Self {
+        DeviceTokens::default()
+    }
+
+    /// Loads the tokens file, a missing file is treated as empty
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let raw = match fs::read_to_string(path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut devices = Vec::new();
+        for (i, line) in raw
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !l.trim().is_empty())
+        {
+            let (device, hash) = line
+                .rsplit_once(' ')
+                .ok_or_else(|| format!("expected <device> <hash> on line {}", i + 1))?;
+            devices.push((device.to_string(), hash.to_string()));
+        }
+
+        Ok(DeviceTokens {
+            path: Some(path.to_path_buf()),
+            devices,
+        })
+    }
+
+    /// Registers a device and returns its new token. The token itself is never stored.
+    pub fn add_device(&mut self, device: &str) -> Result<String, Box<dyn Error>> {
+        if device.is_empty() || device.contains('\n') {
+            return Err("device name cannot be empty or contain newlines".into());
+        }
+
+        let mut raw = [0u8; 32];
+        OsRng.fill_bytes(&mut raw);
+        let token = URL_SAFE_NO_PAD.encode(raw);
+        let hash = hash_token(&token);
+
+        if let Some(path) = &self.path {
+            let mut f = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            writeln!(f, "{} {}", device, hash)?;
+        }
+        self.devices.push((device.to_string(), hash));
+        Ok(token)
+    }
+
+    /// Returns the device the token belongs to
+    pub fn verify(&self, token: &str) -> Option<&str> {
+        let hash = hash_token(token);
+        self.devices
+            .iter()
+            .find(|(_, h)| constant_time_eq(h.as_bytes(), hash.as_bytes()))
+            .map(|(d, _)| d.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_file() {
+        let path = std::env::temp_dir().join(format!("tem-tokens-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut tokens = DeviceTokens::open(&path).expect("open failed");
+        let laptop = tokens.add_device("laptop").expect("add failed");
+        let phone = tokens.add_device("my phone").expect("add failed");
+
+        // Only hashes hit the disk, and they load back
+        let raw = fs::read_to_string(&path).unwrap();
+        assert!(!raw.contains(&laptop));
+        let tokens = DeviceTokens::open(&path).expect("reopen failed");
+        assert_eq!(tokens.verify(&laptop), Some("laptop"));
+        assert_eq!(tokens.verify(&phone), Some("my phone"));
+        assert_eq!(tokens.verify("nope"), None);
+
+        let _ = fs::remove_file(&path);
+    }
+}
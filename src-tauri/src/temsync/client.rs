@@ -0,0 +1,705 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    crypto::SyncKey,
+    edit::EditType,
+    merge::merge_tokens,
+    meyers::get_diff,
+    patch::{apply_patch, generate_patch, read_patch, tokens_to_string},
+    protocol::{
+        self, ClientMessage, Frame, NoteRevision, RevisionPatch, ServerMessage, CAP_ENCRYPTED,
+        PROTOCOL_VERSION, SUPPORTED_VERSIONS,
+    },
+    token::Token,
+    tokenizer::parse_string_tokens,
+};
+
+/// How many times a push is retried when someone else keeps pushing first
+const MAX_PUSH_ATTEMPTS: usize = 8;
+
+/// Requests that take longer than this are treated as the server being offline
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Errors from talking to the sync server
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncError {
+    /// The server couldn't be reached, local changes stay queued
+    Offline(String),
+    /// The server refused the request
+    Rejected(String),
+    /// Local state couldn't be read or written
+    Local(String),
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncError::Offline(e) => write!(f, "sync server unreachable: {}", e),
+            SyncError::Rejected(e) => write!(f, "sync server rejected request: {}", e),
+            SyncError::Local(e) => write!(f, "local sync state error: {}", e),
+        }
+    }
+}
+
+impl Error for SyncError {}
+
+/// Anything that can carry protocol frames to a temsync server and back
+pub trait SyncTransport {
+    fn send(&mut self, frame: &Frame<ClientMessage>) -> Result<Frame<ServerMessage>, SyncError>;
+}
+
+/// Talks to a temsync-server over HTTP
+pub struct HttpTransport {
+    url: String,
+    agent: ureq::Agent,
+    token: Option<String>,
+}
+
+impl HttpTransport {
+    /// Creates a transport for the server at base_url (eg. `http://127.0.0.1:7878`)
+    pub fn new(base_url: &str) -> Self {
+        let agent = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .timeout_global(Some(REQUEST_TIMEOUT))
+            .build()
+            .into();
+        HttpTransport {
+            url: format!("{}/temsync", base_url.trim_end_matches('/')),
+            agent,
+            token: None,
+        }
+    }
+
+    /// Sends the device token with every request, for servers that require one
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+}
+
+impl SyncTransport for HttpTransport {
+    fn send(&mut self, frame: &Frame<ClientMessage>) -> Result<Frame<ServerMessage>, SyncError> {
+        let mut req = self
+            .agent
+            .post(&self.url)
+            .header("Content-Type", "application/json");
+        if let Some(token) = &self.token {
+            req = req.header("Authorization", &format!("Bearer {}", token));
+        }
+        let mut res = req
+            .send(protocol::encode(frame))
+            .map_err(|e| SyncError::Offline(e.to_string()))?;
+
+        // Error replies are frames too, so the status only matters if the body isn't one
+        let status = res.status().as_u16();
+        let raw = res
+            .body_mut()
+            .read_to_string()
+            .map_err(|e| SyncError::Offline(e.to_string()))?;
+        protocol::decode(&raw).map_err(|e| SyncError::Rejected(format!("status {}, {}", status, e)))
+    }
+}
+
+/// What the client knows about one note
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct NoteState {
+    /// Last server revision this client has applied
+    revision: usize,
+    /// Content at that revision
+    base: String,
+    /// Local patches not yet accepted by the server, each made against the one before
+    queue: Vec<String>,
+    /// Set while queue[0] is being pushed. If the connection drops before the response,
+    /// the next pull tells us whether the server got it.
+    in_flight: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ClientState {
+    notes: BTreeMap<String, NoteState>,
+}
+
+/// Sync client that queues local patches while offline. The queue is written to
+/// disk after every change so it survives a crash, and is rebased onto whatever
+/// the server accepted in the meantime when syncing.
+pub struct SyncClient<T: SyncTransport> {
+    transport: T,
+    state_path: PathBuf,
+    state: ClientState,
+    /// Protocol version agreed on with the server, set by the first request
+    version: Option<u32>,
+    /// Encrypts patches and note names before they leave the client
+    key: Option<SyncKey>,
+}
+
+impl<T: SyncTransport> SyncClient<T> {
+    /// Opens the client, restoring any queue left over from a previous run
+    pub fn open(transport: T, state_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let state = match fs::read_to_string(state_path) {
+            Ok(raw) => serde_json::from_str(&raw)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ClientState::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(SyncClient {
+            transport,
+            state_path: state_path.to_path_buf(),
+            state,
+            version: None,
+            key: None,
+        })
+    }
+
+    /// Encrypts everything sent to the server with the key. Every client syncing
+    /// the same notes has to use the same key.
+    pub fn with_key(mut self, key: SyncKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Local view of a note: the last synced content plus the queued patches
+    pub fn content(&self, note: &str) -> Result<String, SyncError> {
+        match self.state.notes.get(note) {
+            Some(s) => Ok(tokens_to_string(&local_tokens(s)?)),
+            None => Ok(String::new()),
+        }
+    }
+
+    /// Number of local patches waiting to be pushed
+    pub fn pending(&self, note: &str) -> usize {
+        self.state
+            .notes
+            .get(note)
+            .map(|s| s.queue.len())
+            .unwrap_or(0)
+    }
+
+    /// Last server revision applied for the note
+    pub fn revision(&self, note: &str) -> usize {
+        self.state.notes.get(note).map(|s| s.revision).unwrap_or(0)
+    }
+
+    /// Records a local edit. This never touches the network.
+    pub fn record(&mut self, note: &str, content: &str) -> Result<(), SyncError> {
+        let state = self.state.notes.entry(note.to_string()).or_default();
+        let prev = local_tokens(state)?;
+        let curr = parse_tokens(content)?;
+        let edits = get_diff(&prev, &curr).map_err(SyncError::Local)?;
+
+        // Nothing changed
+        if edits.iter().all(|e| e.edit_type == EditType::SAME) {
+            return Ok(());
+        }
+
+        state.queue.push(generate_patch(&edits));
+        self.save()
+    }
+
+    /// Syncs every note the client or the server knows about. Stops at the first error.
+    pub fn sync_all(&mut self) -> Result<(), SyncError> {
+        let mut notes: Vec<String> = match self.request(ClientMessage::ListNotes)? {
+            ServerMessage::Notes { notes } => notes.into_iter().map(|n| n.note).collect(),
+            m => return Err(unexpected(m)),
+        };
+        notes.extend(self.state.notes.keys().cloned());
+        notes.sort();
+        notes.dedup();
+
+        for note in notes.iter() {
+            self.sync(note)?;
+        }
+        Ok(())
+    }
+
+    /// Pulls new patches for the note, rebases the local queue onto them and pushes
+    /// the result. Returns the synced content.
+    pub fn sync(&mut self, note: &str) -> Result<String, SyncError> {
+        for _ in 0..MAX_PUSH_ATTEMPTS {
+            let since = self.revision(note);
+            let pulled = match self.request(ClientMessage::PullSince {
+                note: note.to_string(),
+                since,
+            })? {
+                ServerMessage::Patches { patches, .. } => patches,
+                m => return Err(unexpected(m)),
+            };
+            self.absorb(note, pulled)?;
+
+            // Squash the queue into one patch so a push is all or nothing
+            let state = self.state.notes.entry(note.to_string()).or_default();
+            if state.queue.is_empty() {
+                return Ok(state.base.clone());
+            }
+            let local = local_tokens(state)?;
+            let base = parse_tokens(&state.base)?;
+            let patch = generate_patch(&get_diff(&base, &local).map_err(SyncError::Local)?);
+            state.queue = vec![patch.clone()];
+            state.in_flight = true;
+            let push = ClientMessage::PushPatch {
+                note: note.to_string(),
+                base_revision: state.revision,
+                patch,
+            };
+            self.save()?;
+
+            match self.request(push) {
+                Ok(ServerMessage::Ack { revision, .. }) => {
+                    let state = self.state.notes.get_mut(note).unwrap();
+                    state.base = tokens_to_string(&local);
+                    state.revision = revision;
+                    state.queue.clear();
+                    state.in_flight = false;
+                    self.save()?;
+                    return Ok(tokens_to_string(&local));
+                }
+                // Someone got there first, pull again and rebase
+                Ok(ServerMessage::Conflict { .. }) => continue,
+                Ok(m) => return Err(unexpected(m)),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(SyncError::Rejected(format!(
+            "gave up pushing {} after {} attempts",
+            note, MAX_PUSH_ATTEMPTS
+        )))
+    }
+
+    /// Sends a message using the negotiated version, saying hello first if needed
+    fn request(&mut self, message: ClientMessage) -> Result<ServerMessage, SyncError> {
+        let version = match self.version {
+            Some(v) => v,
+            None => {
+                let capabilities = match self.key {
+                    Some(_) => vec![CAP_ENCRYPTED.to_string()],
+                    None => vec![],
+                };
+                let hello = ClientMessage::Hello {
+                    versions: SUPPORTED_VERSIONS.to_vec(),
+                    capabilities,
+                };
+                let reply = self.transport.send(&Frame::new(PROTOCOL_VERSION, hello))?;
+                match reply.message {
+                    ServerMessage::Hello { capabilities } => {
+                        if self.key.is_some() && !capabilities.iter().any(|c| c == CAP_ENCRYPTED) {
+                            return Err(SyncError::Rejected(
+                                "server doesn't support encrypted payloads".into(),
+                            ));
+                        }
+                        *self.version.insert(reply.version)
+                    }
+                    m => return Err(unexpected(m)),
+                }
+            }
+        };
+
+        let message = match &self.key {
+            Some(key) => seal_message(key, message),
+            None => message,
+        };
+        match self.transport.send(&Frame::new(version, message))?.message {
+            ServerMessage::Error { message } => Err(SyncError::Rejected(message)),
+            m => match &self.key {
+                Some(key) => open_message(key, m),
+                None => Ok(m),
+            },
+        }
+    }
+
+    /// Applies pulled patches to the base and rebases the local queue on top
+    fn absorb(&mut self, note: &str, pulled: Vec<RevisionPatch>) -> Result<(), SyncError> {
+        let state = self.state.notes.entry(note.to_string()).or_default();
+        let mut patches = pulled.into_iter().peekable();
+
+        // If our last push landed before the connection dropped, it is the next patch
+        if state.in_flight {
+            if let Some(p) = patches.peek() {
+                if p.revision == state.revision + 1 && Some(&p.patch) == state.queue.first() {
+                    let base = apply_patch_str(&state.base, &p.patch)?;
+                    state.base = base;
+                    state.revision = p.revision;
+                    state.queue.remove(0);
+                    patches.next();
+                }
+            }
+            state.in_flight = false;
+        }
+
+        let patches = patches.collect::<Vec<_>>();
+        if patches.is_empty() {
+            return self.save();
+        }
+
+        // Rebuild the server's content, then merge our changes onto it
+        let base = parse_tokens(&state.base)?;
+        let local = local_tokens(state)?;
+        let mut theirs = state.base.clone();
+        for p in patches.iter() {
+            theirs = apply_patch_str(&theirs, &p.patch)?;
+            state.revision = p.revision;
+        }
+        let theirs_tokens = parse_tokens(&theirs)?;
+        // Conflicts are pushed with their markers, so every client sees both versions
+        let merged = merge_tokens(&base, &theirs_tokens, &local)
+            .map_err(SyncError::Local)?
+            .tokens;
+
+        state.queue.clear();
+        let edits = get_diff(&theirs_tokens, &merged).map_err(SyncError::Local)?;
+        if edits.iter().any(|e| e.edit_type != EditType::SAME) {
+            state.queue.push(generate_patch(&edits));
+        }
+        state.base = theirs;
+
+        self.save()
+    }
+
+    /// Writes the state to a temp file and renames it over the old one, so a crash
+    /// mid-write leaves the previous state intact
+    fn save(&self) -> Result<(), SyncError> {
+        let raw =
+            serde_json::to_string(&self.state).map_err(|e| SyncError::Local(e.to_string()))?;
+        let tmp = self.state_path.with_extension("tmp");
+        if let Some(parent) = self.state_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| SyncError::Local(e.to_string()))?;
+        }
+        fs::write(&tmp, raw)
+            .and_then(|_| fs::rename(&tmp, &self.state_path))
+            .map_err(|e| SyncError::Local(e.to_string()))
+    }
+}
+
+fn unexpected(message: ServerMessage) -> SyncError {
+    match message {
+        ServerMessage::Error { message } => SyncError::Rejected(message),
+        m => SyncError::Rejected(format!("unexpected reply {:?}", m)),
+    }
+}
+
+/// Encrypts the note names and patches in an outgoing message
+fn seal_message(key: &SyncKey, message: ClientMessage) -> ClientMessage {
+    match message {
+        ClientMessage::PushPatch {
+            note,
+            base_revision,
+            patch,
+        } => {
+            let note = key.encrypt_note(&note);
+            let patch = key.encrypt_patch(&note, base_revision + 1, &patch);
+            ClientMessage::PushPatch {
+                note,
+                base_revision,
+                patch,
+            }
+        }
+        ClientMessage::PullSince { note, since } => ClientMessage::PullSince {
+            note: key.encrypt_note(&note),
+            since,
+        },
+        m => m,
+    }
+}
+
+/// Decrypts the note names and patches in a reply. Notes that don't decrypt with
+/// this key belong to someone else and are left out of listings.
+fn open_message(key: &SyncKey, message: ServerMessage) -> Result<ServerMessage, SyncError> {
+    let open_note = |note: &str| key.decrypt_note(note).map_err(SyncError::Rejected);
+    Ok(match message {
+        ServerMessage::Notes { notes } => ServerMessage::Notes {
+            notes: notes
+                .into_iter()
+                .filter_map(|n| {
+                    let note = key.decrypt_note(&n.note).ok()?;
+                    Some(NoteRevision {
+                        note,
+                        revision: n.revision,
+                    })
+                })
+                .collect(),
+        },
+        ServerMessage::Patches {
+            note,
+            revision,
+            patches,
+        } => {
+            let patches = patches
+                .into_iter()
+                .map(|p| {
+                    let patch = key
+                        .decrypt_patch(&note, p.revision, &p.patch)
+                        .map_err(SyncError::Rejected)?;
+                    Ok(RevisionPatch {
+                        revision: p.revision,
+                        patch,
+                    })
+                })
+                .collect::<Result<Vec<_>, SyncError>>()?;
+            ServerMessage::Patches {
+                note: open_note(&note)?,
+                revision,
+                patches,
+            }
+        }
+        ServerMessage::Ack { note, revision } => ServerMessage::Ack {
+            note: open_note(&note)?,
+            revision,
+        },
+        ServerMessage::Conflict {
+            note,
+            base_revision,
+            revision,
+        } => ServerMessage::Conflict {
+            note: open_note(&note)?,
+            base_revision,
+            revision,
+        },
+        m => m,
+    })
+}
+
+fn parse_tokens(content: &str) -> Result<Vec<Token>, SyncError> {
+    parse_string_tokens(content).map_err(|e| SyncError::Local(e.to_string()))
+}
+
+fn apply_patch_str(content: &str, patch: &str) -> Result<String, SyncError> {
+    let base = parse_tokens(content)?;
+    let edits = read_patch(patch).map_err(|e| SyncError::Local(e.to_string()))?;
+    let out = apply_patch(&base, &edits).map_err(SyncError::Local)?;
+    Ok(tokens_to_string(&out))
+}
+
+/// Base content with every queued patch applied
+fn local_tokens(state: &NoteState) -> Result<Vec<Token>, SyncError> {
+    let mut content = state.base.clone();
+    for patch in state.queue.iter() {
+        content = apply_patch_str(&content, patch)?;
+    }
+    parse_tokens(&content)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::temsync::{
+        auth::DeviceTokens,
+        server::{handle_frame, RevisionStore, SyncServer},
+    };
+
+    use super::*;
+
+    /// In-process stand-in for the server that can be switched off
+    #[derive(Clone)]
+    struct LocalTransport {
+        store: Arc<Mutex<RevisionStore>>,
+        online: Arc<Mutex<bool>>,
+        /// Accept the next push but drop the connection before replying
+        drop_next_reply: Arc<Mutex<bool>>,
+    }
+
+    impl LocalTransport {
+        fn new() -> Self {
+            LocalTransport {
+                store: Arc::new(Mutex::new(RevisionStore::new())),
+                online: Arc::new(Mutex::new(true)),
+                drop_next_reply: Arc::new(Mutex::new(false)),
+            }
+        }
+    }
+
+    impl SyncTransport for LocalTransport {
+        fn send(
+            &mut self,
+            frame: &Frame<ClientMessage>,
+        ) -> Result<Frame<ServerMessage>, SyncError> {
+            if !*self.online.lock().unwrap() {
+                return Err(SyncError::Offline("connection refused".into()));
+            }
+            let reply = handle_frame(&mut self.store.lock().unwrap(), frame.clone());
+            let is_push = matches!(frame.message, ClientMessage::PushPatch { .. });
+            if is_push && std::mem::take(&mut *self.drop_next_reply.lock().unwrap()) {
+                return Err(SyncError::Offline("connection reset".into()));
+            }
+            Ok(reply)
+        }
+    }
+
+    fn state_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("tem-{}-{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_offline_queue_and_replay() {
+        let transport = LocalTransport::new();
+        let path = state_path("client-offline");
+        let mut client = SyncClient::open(transport.clone(), &path).expect("open failed");
+
+        // Edits made while offline are queued
+        *transport.online.lock().unwrap() = false;
+        client.record("poem", "A car ate my dog.").unwrap();
+        client.record("poem", "A red car ate my dog.").unwrap();
+        assert_eq!(client.pending("poem"), 2);
+        assert!(matches!(client.sync("poem"), Err(SyncError::Offline(_))));
+        assert_eq!(client.pending("poem"), 2);
+
+        // The queue is still there after a restart, and goes out on reconnect
+        drop(client);
+        let mut client = SyncClient::open(transport.clone(), &path).expect("reopen failed");
+        assert_eq!(client.pending("poem"), 2);
+        *transport.online.lock().unwrap() = true;
+        assert_eq!(client.sync("poem").unwrap(), "A red car ate my dog.");
+        assert_eq!(client.pending("poem"), 0);
+        assert_eq!(
+            transport.store.lock().unwrap().content("poem"),
+            Some("A red car ate my dog.")
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_rebase_onto_remote_changes() {
+        let transport = LocalTransport::new();
+        let (path_a, path_b) = (state_path("client-rebase-a"), state_path("client-rebase-b"));
+        let mut a = SyncClient::open(transport.clone(), &path_a).expect("open failed");
+        let mut b = SyncClient::open(transport.clone(), &path_b).expect("open failed");
+
+        a.record("poem", "A car ate my dog.").unwrap();
+        a.sync("poem").unwrap();
+        b.sync("poem").unwrap();
+
+        // Both edit different words, b syncs second and has to rebase
+        a.record("poem", "A red car ate my dog.").unwrap();
+        b.record("poem", "A car ate my cat.").unwrap();
+        a.sync("poem").unwrap();
+        assert_eq!(b.sync("poem").unwrap(), "A red car ate my cat.");
+        assert_eq!(a.sync("poem").unwrap(), "A red car ate my cat.");
+
+        let _ = fs::remove_file(&path_a);
+        let _ = fs::remove_file(&path_b);
+    }
+
+    #[test]
+    fn test_resume_after_dropped_push() {
+        let transport = LocalTransport::new();
+        let path = state_path("client-dropped");
+        let mut client = SyncClient::open(transport.clone(), &path).expect("open failed");
+
+        // The server takes the push but the reply never arrives, then the app dies
+        client.record("poem", "A car ate my dog.").unwrap();
+        *transport.drop_next_reply.lock().unwrap() = true;
+        assert!(client.sync("poem").is_err());
+        drop(client);
+
+        // On resume the patch is recognized instead of being applied twice
+        let mut client = SyncClient::open(transport.clone(), &path).expect("reopen failed");
+        assert_eq!(client.sync("poem").unwrap(), "A car ate my dog.");
+        assert_eq!(client.pending("poem"), 0);
+        assert_eq!(transport.store.lock().unwrap().revision("poem"), 1);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_http_transport() {
+        let handle = SyncServer::bind("127.0.0.1:0", RevisionStore::new())
+            .expect("bind failed")
+            .spawn();
+        let url = format!("http://{}", handle.addr());
+        let (path_a, path_b) = (state_path("client-http-a"), state_path("client-http-b"));
+        let mut a = SyncClient::open(HttpTransport::new(&url), &path_a).expect("open failed");
+        let mut b = SyncClient::open(HttpTransport::new(&url), &path_b).expect("open failed");
+
+        a.record("notes/a.md", "hello world").unwrap();
+        a.sync_all().unwrap();
+        b.sync_all().unwrap();
+        assert_eq!(b.content("notes/a.md").unwrap(), "hello world");
+
+        // Nothing is listening after shutdown, so changes queue up
+        handle.shutdown();
+        drop(b);
+        let mut b = SyncClient::open(HttpTransport::new(&url), &path_b).expect("reopen failed");
+        b.record("notes/a.md", "hello there world").unwrap();
+        assert!(matches!(b.sync_all(), Err(SyncError::Offline(_))));
+        assert_eq!(b.pending("notes/a.md"), 1);
+
+        let _ = fs::remove_file(&path_a);
+        let _ = fs::remove_file(&path_b);
+    }
+
+    #[test]
+    fn test_encrypted_sync() {
+        let transport = LocalTransport::new();
+        let (path_a, path_b) = (state_path("client-enc-a"), state_path("client-enc-b"));
+        let key = SyncKey::derive("correct horse", "vault").expect("derive failed");
+        let mut a = SyncClient::open(transport.clone(), &path_a)
+            .expect("open failed")
+            .with_key(key.clone());
+        let mut b = SyncClient::open(transport.clone(), &path_b)
+            .expect("open failed")
+            .with_key(key.clone());
+
+        a.record("diary.md", "A car ate my dog.").unwrap();
+        a.sync_all().unwrap();
+        b.sync_all().unwrap();
+        assert_eq!(b.content("diary.md").unwrap(), "A car ate my dog.");
+
+        // Rebasing works the same on top of decrypted patches
+        a.record("diary.md", "A red car ate my dog.").unwrap();
+        b.record("diary.md", "A car ate my cat.").unwrap();
+        a.sync("diary.md").unwrap();
+        assert_eq!(b.sync("diary.md").unwrap(), "A red car ate my cat.");
+
+        // The server only ever saw ciphertext
+        let store = transport.store.lock().unwrap();
+        let notes = store.list();
+        assert_eq!(notes.len(), 1);
+        assert!(protocol::is_encrypted(&notes[0].note));
+        for p in store.since(&notes[0].note, 0) {
+            assert!(protocol::is_encrypted(&p.patch));
+            assert!(!p.patch.contains("car"));
+        }
+
+        let _ = fs::remove_file(&path_a);
+        let _ = fs::remove_file(&path_b);
+    }
+
+    #[test]
+    fn test_device_tokens() {
+        let mut tokens = DeviceTokens::new();
+        let token = tokens.add_device("laptop").unwrap();
+        let handle = SyncServer::bind("127.0.0.1:0", RevisionStore::new())
+            .expect("bind failed")
+            .with_auth(tokens)
+            .spawn();
+        let url = format!("http://{}", handle.addr());
+        let (path_a, path_b) = (state_path("client-auth-a"), state_path("client-auth-b"));
+
+        let mut anon = SyncClient::open(HttpTransport::new(&url), &path_a).expect("open failed");
+        anon.record("a.md", "hello").unwrap();
+        assert!(matches!(anon.sync_all(), Err(SyncError::Rejected(_))));
+
+        let transport = HttpTransport::new(&url).with_token(&token);
+        let mut laptop = SyncClient::open(transport, &path_b).expect("open failed");
+        laptop.record("a.md", "hello").unwrap();
+        laptop.sync_all().unwrap();
+        assert_eq!(laptop.pending("a.md"), 0);
+
+        handle.shutdown();
+        let _ = fs::remove_file(&path_a);
+        let _ = fs::remove_file(&path_b);
+    }
+}
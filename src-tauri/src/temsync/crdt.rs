@@ -0,0 +1,364 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    edit::{Edit, EditType},
+    meyers::get_diff,
+    token::Token,
+};
+
+/// Unique id of an element, ordered as a Lamport timestamp with the replica as a tiebreak
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ElementId {
+    pub counter: u64,
+    pub replica: u32,
+}
+
+/// Operation on an RGA document. Ops can be applied in any order and any number
+/// of times, replicas that have seen the same set of ops end up with the same tokens.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Op {
+    /// Insert the token right after another element (or at the start if None)
+    Insert {
+        id: ElementId,
+        after: Option<ElementId>,
+        token: Token,
+    },
+    /// Hide an element. Deleted elements stay around as tombstones so inserts
+    /// that reference them can still be placed.
+    Delete { id: ElementId },
+}
+
+#[derive(Debug, Clone)]
+struct Element {
+    id: ElementId,
+    token: Token,
+    deleted: bool,
+}
+
+/// Replicated Growable Array of tokens. This is an alternative to patch based
+/// syncing for when there is no central server to order the patches.
+#[derive(Debug, Clone)]
+pub struct RgaDoc {
+    replica: u32,
+    clock: u64,
+    elements: Vec<Element>,
+    /// Every op applied so far, used to merge whole documents
+    log: Vec<Op>,
+    /// Ops whose element they depend on hasn't arrived yet
+    pending: Vec<Op>,
+    seen: HashSet<(ElementId, bool)>,
+}
+
+impl RgaDoc {
+    /// Creates an empty document. Every replica needs its own id.
+    pub fn new(replica: u32) -> Self {
+        RgaDoc {
+            replica,
+            clock: 0,
+            elements: vec![],
+            log: vec![],
+            pending: vec![],
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Tokens that haven't been deleted, in document order
+    pub fn tokens(&self) -> Vec<Token> {
+        self.elements
+            .iter()
+            .filter(|e| !e.deleted)
+            .map(|e| e.token.clone())
+            .collect()
+    }
+
+    /// Every op this document has applied
+    pub fn ops(&self) -> &[Op] {
+        &self.log
+    }
+
+    /// Inserts a token so it ends up at the given visible index
+    pub fn insert(&mut self, index: usize, token: Token) -> Op {
+        let after = match index {
+            0 => None,
+            i => Some(self.visible_id(i - 1).expect("insert index out of range")),
+        };
+        self.clock += 1;
+        let op = Op::Insert {
+            id: ElementId {
+                counter: self.clock,
+                replica: self.replica,
+            },
+            after,
+            token,
+        };
+        self.apply(op.clone());
+        op
+    }
+
+    /// Deletes the token at the given visible index
+    pub fn delete(&mut self, index: usize) -> Op {
+        let id = self.visible_id(index).expect("delete index out of range");
+        let op = Op::Delete { id };
+        self.apply(op.clone());
+        op
+    }
+
+    /// Turns the output of get_diff between this document's tokens and a newer
+    /// snapshot into ops, applying them locally as it goes
+    pub fn apply_edits(&mut self, edits: &[Edit]) -> Vec<Op> {
+        let mut ops = Vec::<Op>::new();
+        let mut pos = 0;
+
+        for e in edits.iter() {
+            match e.edit_type {
+                EditType::SAME => pos += e.tokens.len(),
+                EditType::DELETE => {
+                    for _ in e.tokens.iter() {
+                        ops.push(self.delete(pos));
+                    }
+                }
+                EditType::INSERT => {
+                    for t in e.tokens.iter() {
+                        ops.push(self.insert(pos, t.clone()));
+                        pos += 1;
+                    }
+                }
+            }
+        }
+
+        ops
+    }
+
+    /// Diffs the document against a new snapshot and returns the ops that get it there
+    pub fn update(&mut self, snapshot: &Vec<Token>) -> Result<Vec<Op>, String> {
+        let edits = get_diff(&self.tokens(), snapshot)?;
+        Ok(self.apply_edits(&edits))
+    }
+
+    /// Applies an op from any replica. Ops that arrive before the element they
+    /// depend on are held back until it shows up.
+    pub fn apply(&mut self, op: Op) {
+        if !self.try_apply(&op) {
+            self.pending.push(op);
+            return;
+        }
+
+        // Something new arrived, so held back ops may be ready now
+        let mut progress = true;
+        while progress {
+            progress = false;
+            let pending = std::mem::take(&mut self.pending);
+            for op in pending.into_iter() {
+                if self.try_apply(&op) {
+                    progress = true;
+                } else {
+                    self.pending.push(op);
+                }
+            }
+        }
+    }
+
+    /// Applies every op the other document has seen
+    pub fn merge(&mut self, other: &RgaDoc) {
+        for op in other.log.iter().chain(other.pending.iter()) {
+            self.apply(op.clone());
+        }
+    }
+
+    /// Returns false if the op depends on an element we don't have yet
+    fn try_apply(&mut self, op: &Op) -> bool {
+        let key = match op {
+            Op::Insert { id, .. } => (*id, false),
+            Op::Delete { id } => (*id, true),
+        };
+
+        // Already applied, ops are idempotent
+        if self.seen.contains(&key) {
+            return true;
+        }
+
+        match op {
+            Op::Insert { id, after, token } => {
+                // Start right after the reference element
+                let mut i = match after {
+                    None => 0,
+                    Some(after) => match self.position(after) {
+                        Some(p) => p + 1,
+                        None => return false,
+                    },
+                };
+
+                // Concurrent inserts at the same spot are ordered by id, newest first
+                while i < self.elements.len() && self.elements[i].id > *id {
+                    i += 1;
+                }
+                self.elements.insert(
+                    i,
+                    Element {
+                        id: *id,
+                        token: token.clone(),
+                        deleted: false,
+                    },
+                );
+                self.clock = self.clock.max(id.counter);
+            }
+            Op::Delete { id } => match self.position(id) {
+                Some(p) => self.elements[p].deleted = true,
+                None => return false,
+            },
+        }
+
+        self.seen.insert(key);
+        self.log.push(op.clone());
+        true
+    }
+
+    fn position(&self, id: &ElementId) -> Option<usize> {
+        self.elements.iter().position(|e| e.id == *id)
+    }
+
+    fn visible_id(&self, index: usize) -> Option<ElementId> {
+        self.elements
+            .iter()
+            .filter(|e| !e.deleted)
+            .nth(index)
+            .map(|e| e.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::temsync::{patch::tokens_to_string, tokenizer::parse_string_tokens};
+
+    use super::*;
+
+    /// Small xorshift generator so the interleavings are random but reproducible
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    const WORDS: &[&str] = &[
+        "the", "quick", "brown", "fox", "jumps", "over", "lazy", "dog",
+    ];
+
+    /// Makes a random edit to a text: replacing, inserting or removing a word
+    fn random_edit(rng: &mut Rng, text: &str) -> String {
+        let mut words = text
+            .split(' ')
+            .filter(|w| !w.is_empty())
+            .collect::<Vec<&str>>();
+        let word = WORDS[rng.below(WORDS.len())];
+        match (rng.below(3), words.len()) {
+            (_, 0) | (0, _) => words.insert(rng.below(words.len() + 1), word),
+            (1, n) => words[rng.below(n)] = word,
+            (_, n) => {
+                words.remove(rng.below(n));
+            }
+        }
+        words.join(" ")
+    }
+
+    fn text(doc: &RgaDoc) -> String {
+        tokens_to_string(&doc.tokens())
+    }
+
+    #[test]
+    fn test_ops_from_diff() {
+        let mut doc = RgaDoc::new(1);
+        let a = parse_string_tokens("A car ate my dog.").unwrap();
+        let b = parse_string_tokens("A red car ate my cat.").unwrap();
+        doc.update(&a).unwrap();
+        let ops = doc.update(&b).unwrap();
+        assert_eq!(text(&doc), "A red car ate my cat.");
+
+        // The same ops rebuild the change on a replica that had the first version
+        let mut other = RgaDoc::new(2);
+        for op in doc.ops().iter().take(doc.ops().len() - ops.len()) {
+            other.apply(op.clone());
+        }
+        assert_eq!(text(&other), "A car ate my dog.");
+        for op in ops.into_iter() {
+            other.apply(op);
+        }
+        assert_eq!(text(&other), "A red car ate my cat.");
+    }
+
+    #[test]
+    fn test_merge_commutes() {
+        let mut base = RgaDoc::new(0);
+        base.update(&parse_string_tokens("A car ate my dog.").unwrap())
+            .unwrap();
+
+        let mut a = base.clone();
+        a.replica = 1;
+        a.update(&parse_string_tokens("A red car ate my dog.").unwrap())
+            .unwrap();
+        let mut b = base.clone();
+        b.replica = 2;
+        b.update(&parse_string_tokens("A blue car ate my cat.").unwrap())
+            .unwrap();
+
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+        assert_eq!(text(&ab), text(&ba));
+        assert!(text(&ab).contains("cat."));
+    }
+
+    #[test]
+    fn test_three_replicas_converge() {
+        for seed in 1..20u64 {
+            let mut rng = Rng(seed.wrapping_mul(0x9E3779B97F4A7C15));
+            let mut replicas = (0..3).map(RgaDoc::new).collect::<Vec<RgaDoc>>();
+            let mut inboxes = vec![Vec::<Op>::new(); 3];
+
+            for _ in 0..40 {
+                // Either a random replica edits, or a random op gets delivered
+                let r = rng.below(3);
+                if rng.below(2) == 0 || inboxes[r].is_empty() {
+                    let edited = random_edit(&mut rng, &text(&replicas[r]));
+                    let ops = replicas[r]
+                        .update(&parse_string_tokens(&edited).unwrap())
+                        .unwrap();
+                    for (other, inbox) in inboxes.iter_mut().enumerate() {
+                        if other != r {
+                            inbox.extend(ops.iter().cloned());
+                        }
+                    }
+                } else {
+                    // Delivery order is random too, so ops can show up before their dependencies
+                    let i = rng.below(inboxes[r].len());
+                    replicas[r].apply(inboxes[r].remove(i));
+                }
+            }
+
+            // Flush everything that is left, in random order
+            for (r, inbox) in inboxes.iter_mut().enumerate() {
+                while !inbox.is_empty() {
+                    let op = inbox.remove(rng.below(inbox.len()));
+                    replicas[r].apply(op);
+                }
+            }
+
+            let expected = text(&replicas[0]);
+            for r in replicas.iter() {
+                assert_eq!(text(r), expected, "replicas diverged with seed {}", seed);
+                assert!(r.pending.is_empty());
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use argon2::Argon2;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use super::protocol::ENCRYPTED_PREFIX;
+
+const NONCE_LEN: usize = 12;
+
+/// Key for end-to-end encrypted sync, derived from a passphrase. The server
+/// only ever sees the ciphertext of patches and note names.
+#[derive(Clone)]
+pub struct SyncKey {
+    /// Encrypts patches and note names
+    cipher: ChaCha20Poly1305,
+    /// Derives the nonce for note names, so the same name always maps to the same id
+    names: [u8; 32],
+}
+
+impl SyncKey {
+    /// Derives the key with Argon2. Every device syncing the vault needs the same
+    /// passphrase and vault id (the vault id acts as the salt).
+    pub fn derive(passphrase: &str, vault_id: &str) -> Result<Self, String> {
+        let salt = Sha256::digest(format!("temsync:{}", vault_id).as_bytes());
+        let mut okm = [0u8; 64];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut okm)
+            .map_err(|e| format!("unable to derive key: {}", e))?;
+
+        let mut names = [0u8; 32];
+        names.copy_from_slice(&okm[32..]);
+        Ok(SyncKey {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&okm[..32])),
+            names,
+        })
+    }
+
+    /// Encrypts a patch. The note id and revision are authenticated along with it,
+    /// so the server can't move a patch to another note or revision.
+    pub fn encrypt_patch(&self, note_id: &str, revision: usize, patch: &str) -> String {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        self.seal(&nonce, patch.as_bytes(), &patch_aad(note_id, revision))
+    }
+
+    pub fn decrypt_patch(
+        &self,
+        note_id: &str,
+        revision: usize,
+        payload: &str,
+    ) -> Result<String, String> {
+        self.open(payload, &patch_aad(note_id, revision))
+    }
+
+    /// Encrypts a note name. This is deterministic (the nonce comes from an HMAC of
+    /// the name) so every device maps a note to the same id.
+    pub fn encrypt_note(&self, note: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.names)
+            .expect("hmac should accept any key length");
+        mac.update(note.as_bytes());
+        let tag = mac.finalize().into_bytes();
+        self.seal(
+            Nonce::from_slice(&tag[..NONCE_LEN]),
+            note.as_bytes(),
+            b"note",
+        )
+    }
+
+    pub fn decrypt_note(&self, note_id: &str) -> Result<String, String> {
+        self.open(note_id, b"note")
+    }
+
+    fn seal(&self, nonce: &Nonce, plaintext: &[u8], aad: &[u8]) -> String {
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("encrypting in memory should not fail");
+
+        let mut raw = nonce.to_vec();
+        raw.extend(ciphertext);
+        format!("{}{}", ENCRYPTED_PREFIX, URL_SAFE_NO_PAD.encode(raw))
+    }
+
+    fn open(&self, payload: &str, aad: &[u8]) -> Result<String, String> {
+        let encoded = payload
+            .strip_prefix(ENCRYPTED_PREFIX)
+            .ok_or("payload is not encrypted")?;
+        let raw = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .map_err(|e| format!("invalid encrypted payload: {}", e))?;
+        if raw.len() < NONCE_LEN {
+            return Err("encrypted payload is too short".into());
+        }
+
+        let (nonce, ciphertext) = raw.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| "unable to decrypt payload, wrong passphrase or tampered data")?;
+        String::from_utf8(plaintext).map_err(|e| e.to_string())
+    }
+}
+
+fn patch_aad(note_id: &str, revision: usize) -> Vec<u8> {
+    format!("patch:{}:{}", note_id, revision).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patch_roundtrip() {
+        let key = SyncKey::derive("correct horse", "vault").expect("derive failed");
+        let id = key.encrypt_note("journal/today.md");
+
+        let payload = key.encrypt_patch(&id, 3, "+1,2#secret\n");
+        assert!(!payload.contains("secret"));
+        assert_eq!(
+            key.decrypt_patch(&id, 3, &payload).unwrap(),
+            "+1,2#secret\n"
+        );
+
+        // Moving the patch to another revision or note breaks authentication
+        assert!(key.decrypt_patch(&id, 4, &payload).is_err());
+        assert!(key
+            .decrypt_patch(&key.encrypt_note("other.md"), 3, &payload)
+            .is_err());
+    }
+
+    #[test]
+    fn test_note_ids() {
+        let key = SyncKey::derive("correct horse", "vault").expect("derive failed");
+        let id = key.encrypt_note("journal/today.md");
+
+        assert_eq!(id, key.encrypt_note("journal/today.md"));
+        assert!(!id.contains("journal"));
+        assert_eq!(key.decrypt_note(&id).unwrap(), "journal/today.md");
+
+        let other = SyncKey::derive("wrong horse", "vault").expect("derive failed");
+        assert!(other.decrypt_note(&id).is_err());
+    }
+}
@@ -1,13 +1,16 @@
+use serde::Serialize;
+
 use super::token::Token;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum EditType {
     INSERT,
     DELETE,
     SAME,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Edit {
     pub old_index: usize,
     pub new_index: usize,
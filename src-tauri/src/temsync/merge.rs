@@ -0,0 +1,253 @@
+use super::{
+    edit::{Edit, EditType},
+    meyers::get_diff,
+    token::Token,
+    tokenizer::parse_string_tokens,
+};
+
+/// A single change to the base: replace base[start..end] with tokens
+#[derive(Debug, Clone)]
+struct Hunk {
+    start: usize,
+    end: usize,
+    tokens: Vec<Token>,
+}
+
+/// Marks the start of their version of a conflicting region
+pub const THEIRS_MARKER: &str = "\n<<<<<<< theirs\n";
+/// Separates their version of a conflicting region from ours
+pub const SPLIT_MARKER: &str = "\n=======\n";
+/// Marks the end of our version of a conflicting region
+pub const OURS_MARKER: &str = "\n>>>>>>> ours\n";
+
+/// Result of a three-way merge
+#[derive(Debug, Clone, PartialEq)]
+pub struct Merge {
+    pub tokens: Vec<Token>,
+    /// Regions both sides changed differently. Each one is in the tokens with
+    /// both versions of it between conflict markers, like git does.
+    pub conflicts: usize,
+}
+
+/// Three-way merges two sets of changes made to the same base.
+/// Changes that don't touch each other are both kept. When they overlap, the
+/// region is a conflict and both sides' versions of it are kept between markers.
+pub fn merge_tokens(
+    base: &Vec<Token>,
+    theirs: &Vec<Token>,
+    ours: &Vec<Token>,
+) -> Result<Merge, String> {
+    let theirs = get_hunks(&get_diff(base, theirs)?);
+    let ours = get_hunks(&get_diff(base, ours)?);
+
+    let mut out = Vec::<Token>::with_capacity(base.len());
+    let mut conflicts = 0;
+    let mut pos = 0;
+    let (mut i, mut j) = (0, 0);
+
+    while i < theirs.len() || j < ours.len() {
+        // Take whichever hunk comes first if they don't touch
+        let (a, b) = match (theirs.get(i), ours.get(j)) {
+            (Some(a), None) => {
+                apply_hunk(&mut out, &mut pos, base, a);
+                i += 1;
+                continue;
+            }
+            (None, Some(b)) => {
+                apply_hunk(&mut out, &mut pos, base, b);
+                j += 1;
+                continue;
+            }
+            (Some(a), Some(b)) => (a, b),
+            (None, None) => break,
+        };
+
+        // Both sides made the exact same change
+        if a.start == b.start && a.end == b.end && same_values(&a.tokens, &b.tokens) {
+            apply_hunk(&mut out, &mut pos, base, a);
+            (i, j) = (i + 1, j + 1);
+            continue;
+        }
+        if !overlaps(a, b) {
+            if a.start <= b.start {
+                apply_hunk(&mut out, &mut pos, base, a);
+                i += 1;
+            } else {
+                apply_hunk(&mut out, &mut pos, base, b);
+                j += 1;
+            }
+            continue;
+        }
+
+        // Overlap, so grow the region until no other hunk on either side touches it
+        let start = a.start.min(b.start);
+        let mut end = a.end.max(b.end);
+        let (theirs_from, ours_from) = (i, j);
+        (i, j) = (i + 1, j + 1);
+        loop {
+            if i < theirs.len() && theirs[i].start < end {
+                end = end.max(theirs[i].end);
+                i += 1;
+            } else if j < ours.len() && ours[j].start < end {
+                end = end.max(ours[j].end);
+                j += 1;
+            } else {
+                break;
+            }
+        }
+
+        // Each side's version of the region is the base with only its own hunks
+        out.extend_from_slice(&base[pos..start]);
+        out.extend(marker(THEIRS_MARKER)?);
+        out.extend(region(base, &theirs[theirs_from..i], start, end));
+        out.extend(marker(SPLIT_MARKER)?);
+        out.extend(region(base, &ours[ours_from..j], start, end));
+        out.extend(marker(OURS_MARKER)?);
+        conflicts += 1;
+        pos = end;
+    }
+
+    // Copy the unchanged tail
+    out.extend_from_slice(&base[pos..]);
+    Ok(Merge {
+        tokens: out,
+        conflicts,
+    })
+}
+
+/// The base tokens in start..end with the given hunks applied
+fn region(base: &[Token], hunks: &[Hunk], start: usize, end: usize) -> Vec<Token> {
+    let mut out = Vec::<Token>::new();
+    let mut pos = start;
+    for hunk in hunks.iter() {
+        apply_hunk(&mut out, &mut pos, base, hunk);
+    }
+    out.extend_from_slice(&base[pos..end]);
+    out
+}
+
+fn marker(text: &str) -> Result<Vec<Token>, String> {
+    parse_string_tokens(text).map_err(|e| e.to_string())
+}
+
+/// Groups the DELETE/INSERT runs between SAME edits into hunks
+fn get_hunks(edits: &[Edit]) -> Vec<Hunk> {
+    let mut out = Vec::<Hunk>::new();
+    let mut open = false;
+
+    for e in edits.iter() {
+        if e.edit_type == EditType::SAME {
+            open = false;
+            continue;
+        }
+
+        // Start a new hunk at the current old position
+        if !open {
+            out.push(Hunk {
+                start: e.old_index,
+                end: e.old_index,
+                tokens: vec![],
+            });
+            open = true;
+        }
+
+        let hunk = out.last_mut().unwrap();
+        match e.edit_type {
+            EditType::DELETE => hunk.end = e.old_index + e.tokens.len(),
+            EditType::INSERT => hunk.tokens.extend(e.tokens.iter().cloned()),
+            EditType::SAME => {}
+        }
+    }
+
+    out
+}
+
+/// Two pure inserts at the same spot don't overlap, they just both go in
+fn overlaps(a: &Hunk, b: &Hunk) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+fn apply_hunk(out: &mut Vec<Token>, pos: &mut usize, base: &[Token], hunk: &Hunk) {
+    out.extend_from_slice(&base[*pos..hunk.start]);
+    out.extend(hunk.tokens.iter().cloned());
+    *pos = hunk.end;
+}
+
+fn same_values(a: &[Token], b: &[Token]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| x.eq_value(y))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::temsync::{patch::tokens_to_string, tokenizer::parse_string_tokens};
+
+    use super::*;
+
+    fn merge_strings(base: &str, theirs: &str, ours: &str) -> (String, usize) {
+        let base = parse_string_tokens(base).expect("tokenizer parse_string_tokens failed");
+        let theirs = parse_string_tokens(theirs).expect("tokenizer parse_string_tokens failed");
+        let ours = parse_string_tokens(ours).expect("tokenizer parse_string_tokens failed");
+        let merge = merge_tokens(&base, &theirs, &ours).expect("merge_tokens failed");
+        (tokens_to_string(&merge.tokens), merge.conflicts)
+    }
+
+    #[test]
+    fn test_merge_separate_changes() {
+        let out = merge_strings(
+            "A car ate my dog.",
+            "A red car ate my dog.",
+            "A car ate my cat.",
+        );
+        assert_eq!(out, ("A red car ate my cat.".into(), 0));
+    }
+
+    #[test]
+    fn test_merge_same_change() {
+        let out = merge_strings(
+            "A car ate my dog.",
+            "A car ate my cat.",
+            "A car ate my cat.",
+        );
+        assert_eq!(out, ("A car ate my cat.".into(), 0));
+    }
+
+    #[test]
+    fn test_merge_conflict() {
+        let out = merge_strings(
+            "A car ate my dog.",
+            "A car ate my cat.",
+            "A car ate my fish.",
+        );
+        assert_eq!(
+            out,
+            (
+                "A car ate my \n<<<<<<< theirs\ncat\n=======\nfish\n>>>>>>> ours\n.".into(),
+                1
+            )
+        );
+
+        // Ours deleted `three four `, theirs kept `three` when changing `four`, and
+        // keeps it in its version of the region
+        let out = merge_strings(
+            "one two three four five",
+            "ONE two three FOUR five",
+            "one X five",
+        );
+        assert_eq!(
+            out,
+            (
+                "ONE X \n<<<<<<< theirs\nthree FOUR \n=======\n\n>>>>>>> ours\nfive".into(),
+                1
+            )
+        );
+
+        // Deleting text the other side edited is a conflict too
+        let (out, conflicts) = merge_strings(
+            "one two three four five",
+            "ONE two three FOUR five",
+            "X five",
+        );
+        assert_eq!(conflicts, 2);
+        assert!(out.contains("\ntwo three FOUR \n=======\n\n"));
+    }
+}
@@ -11,23 +11,24 @@ pub fn get_diff(prev: &Vec<Token>, curr: &Vec<Token>) -> Result<Vec<Edit>, Strin
     let n = prev.len();
     let m = curr.len();
     let max = n + m;
-    let max_size = max * 2 + 1;
+    let max_size = max * 2 + 2;
     let mut dp = vec![usize::MAX; max_size];
     let mut trace = Vec::<Vec<usize>>::new();
 
     // Set first dp value to get (0, 0) on first calculation
-    dp[max] = 0;
+    dp[max + 1] = 0;
 
     // Fill dp array, going left to right on d and top to bottom on k
-    for d in 0..max {
+    // (d goes up to max, which is needed when none of the tokens match)
+    for d in 0..=max {
         // Push previous state to array
         trace.push(dp.clone());
 
         // Go from -d to d, but offset because usize indexes
         for ki in (0..(2 * d + 1)).step_by(2) {
             // We know k = ki - d bc ki is offset by d
-            // Also idx = k + max - 1, subbing for k we get
-            let idx = ki + max - d - 1;
+            // Also idx = k + max, subbing for k we get
+            let idx = ki + max - d;
 
             // x = down (same) or right (prev + 1)
             let mut x = if ki == 0 || (ki != (2 * d) && dp[idx - 1] < dp[idx + 1]) {
@@ -38,7 +39,7 @@ pub fn get_diff(prev: &Vec<Token>, curr: &Vec<Token>) -> Result<Vec<Edit>, Strin
 
             // Because idx = k + max, y = x - (idx - max)
             // This is the same as y = x - k
-            let mut y = x + max - 1 - idx;
+            let mut y = x + max - idx;
 
             // Diagonal steps
             while x < n && y < m && prev[x].eq_value(&curr[y]) {
@@ -88,10 +89,19 @@ fn backtrack(trace: &Vec<Vec<usize>>, n: usize, m: usize) -> Vec<Move> {
 
     // Iterate through trace backwards
     for (d, t) in trace.iter().enumerate().rev() {
-        // Same as above logic, k = x - y and idx = k + max - 1
-        // So to keep usize, we do idx = x - y + max - 1
-        // We can apply the same logic backwards to get ki (ki = idx + d - (max - 1)) => (ki = x - y + d)
-        let idx = x + max - y - 1;
+        // No edits are left at d = 0 (eg. both files are the same), so go diagonally to (0, 0)
+        if d == 0 {
+            while x > 0 {
+                path.push(Move::new(x - 1, y - 1, x, y));
+                (x, y) = (x - 1, y - 1);
+            }
+            break;
+        }
+
+        // Same as above logic, k = x - y and idx = k + max
+        // So to keep usize, we do idx = x - y + max
+        // We can apply the same logic backwards to get ki (ki = idx + d - max) => (ki = x - y + d)
+        let idx = x + max - y;
         let ki = x + d - y;
 
         // Using same logic as above, determine if we take a rightwards or downwards step
@@ -103,7 +113,7 @@ fn backtrack(trace: &Vec<Vec<usize>>, n: usize, m: usize) -> Vec<Move> {
 
         // Get previous x and y
         let prev_x = t[prev_idx];
-        let prev_y = prev_x + max - 1 - prev_idx;
+        let prev_y = prev_x + max - prev_idx;
 
         // Step backwards diagonally
         while x > prev_x && y > prev_y {
@@ -242,4 +252,30 @@ mod tests {
 
         assert_eq!(diff.len(), 7, "diff should be length 7");
     }
+
+    #[test]
+    fn test_get_diff_edges() {
+        let a = vec![
+            Token::new_with_values(0, 1, "A".into()),
+            Token::new_with_values(1, 1, " ".into()),
+            Token::new_with_values(2, 3, "car".into()),
+        ];
+        let b = vec![Token::new_with_values(0, 3, "bus".into())];
+
+        // Empty files
+        let diff = get_diff(&vec![], &vec![]).expect("meyers get_diff failed");
+        assert!(diff.is_empty(), "diff of empty files should be empty");
+        let diff = get_diff(&vec![], &a).expect("meyers get_diff failed");
+        assert_eq!(diff.len(), 1, "diff from empty should be a single insert");
+        assert_eq!(diff[0].edit_type, EditType::INSERT);
+
+        // Same files
+        let diff = get_diff(&a, &a).expect("meyers get_diff failed");
+        assert_eq!(diff.len(), 1, "diff of same files should be a single same");
+        assert_eq!(diff[0].edit_type, EditType::SAME);
+
+        // Nothing in common
+        let diff = get_diff(&a, &b).expect("meyers get_diff failed");
+        assert_eq!(diff.len(), 2, "diff should be a delete and an insert");
+    }
 }
@@ -0,0 +1,75 @@
+//! Note names as URL path components and file names
+
+use std::error::Error;
+
+use sha2::{Digest, Sha256};
+
+/// Longest file name file_component gives, under the usual 255 byte limit
+const MAX_FILE_NAME: usize = 200;
+
+/// Percent-encodes everything except unreserved characters, so note names can be
+/// used both in URLs and as file names
+pub fn encode_component(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// encode_component for use as a file name. Names too long for that (eg. long
+/// encrypted names) are cut short and end in a hash of the whole name instead,
+/// so they can't be decoded and the name has to be kept elsewhere.
+pub fn file_component(s: &str) -> String {
+    let encoded = encode_component(s);
+    if encoded.len() <= MAX_FILE_NAME {
+        return encoded;
+    }
+    let hash = Sha256::digest(s.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    // Encoded names are ascii, so any index is a char boundary
+    format!("{}~{}", &encoded[..MAX_FILE_NAME - hash.len() - 1], hash)
+}
+
+/// Reverses encode_component
+pub fn decode_component(s: &str) -> Result<String, Box<dyn Error>> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::<u8>::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3).ok_or("truncated percent escape")?;
+            out.push(u8::from_str_radix(hex, 16)?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(String::from_utf8(out)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_component_encoding() {
+        let s = "notes/ümlaut & co.md";
+        assert_eq!(decode_component(&encode_component(s)).unwrap(), s);
+        assert!(!encode_component(s).contains('/'));
+
+        // Long names get a fixed length hashed file name
+        assert_eq!(file_component(s), encode_component(s));
+        let long = "ü".repeat(300);
+        assert_eq!(file_component(&long).len(), MAX_FILE_NAME);
+        assert_ne!(file_component(&long), file_component(&"ü".repeat(301)));
+    }
+}
@@ -2,6 +2,7 @@ use std::{error::Error, fs};
 
 use super::{
     edit::{Edit, EditType},
+    token::Token,
     tokenizer::parse_string_tokens,
 };
 
@@ -18,8 +19,9 @@ pub fn generate_patch(edits: &Vec<Edit>) -> String {
 
         // Add operation and numbers, adjusting for the addition offset due to nature of patch
         out.push(match e.edit_type {
-            EditType::INSERT => format!("+{},{}#", e.old_index - 1, e.new_index),
-            EditType::DELETE => format!("-{},{}#", e.old_index, e.new_index - 1),
+            // Saturate so edits at the very start of the file don't underflow
+            EditType::INSERT => format!("+{},{}#", e.old_index.saturating_sub(1), e.new_index),
+            EditType::DELETE => format!("-{},{}#", e.old_index, e.new_index.saturating_sub(1)),
             _ => panic!("Illegal edit type detected: {:?}", e.edit_type),
         });
 
@@ -44,6 +46,12 @@ pub fn read_patch(raw_content: &str) -> Result<Vec<Edit>, Box<dyn Error>> {
     // Create output vec
     let mut output = Vec::<Edit>::new();
 
+    // An empty patch has no edits, and generate_patch always ends with a newline
+    let raw_content = raw_content.strip_suffix('\n').unwrap_or(raw_content);
+    if raw_content.is_empty() {
+        return Ok(output);
+    }
+
     // Loop through each line
     let lines = raw_content.split('\n');
     for line in lines {
@@ -98,6 +106,57 @@ pub fn read_patch(raw_content: &str) -> Result<Vec<Edit>, Box<dyn Error>> {
     Ok(output)
 }
 
+/// Applies a list of edits to the tokens they were generated from, returning the new tokens.
+/// Deletions are checked against the base so a patch for a different file state is rejected.
+pub fn apply_patch(base: &[Token], edits: &[Edit]) -> Result<Vec<Token>, String> {
+    let mut out = Vec::<Token>::with_capacity(base.len());
+    let mut cursor = 0;
+
+    for e in edits.iter() {
+        match e.edit_type {
+            EditType::SAME => continue,
+            EditType::DELETE => {
+                // Deletions are positioned by their index in the old tokens
+                if e.old_index < cursor || e.old_index + e.tokens.len() > base.len() {
+                    return Err(format!("deletion at {} is out of range", e.old_index));
+                }
+                out.extend_from_slice(&base[cursor..e.old_index]);
+                cursor = e.old_index;
+
+                // Every deleted token has to match what is actually there
+                for t in e.tokens.iter() {
+                    if !base[cursor].eq_value(t) {
+                        return Err(format!(
+                            "deletion at {} expected [{}], found [{}]",
+                            cursor, t.value, base[cursor].value
+                        ));
+                    }
+                    cursor += 1;
+                }
+            }
+            EditType::INSERT => {
+                // Insertions are positioned by their index in the new tokens
+                if e.new_index < out.len() || e.new_index - out.len() > base.len() - cursor {
+                    return Err(format!("insertion at {} is out of range", e.new_index));
+                }
+                let copy_to = cursor + e.new_index - out.len();
+                out.extend_from_slice(&base[cursor..copy_to]);
+                cursor = copy_to;
+                out.extend(e.tokens.iter().cloned());
+            }
+        }
+    }
+
+    // Copy the unchanged tail
+    out.extend_from_slice(&base[cursor..]);
+    Ok(out)
+}
+
+/// Joins tokens back into the text they were parsed from
+pub fn tokens_to_string(tokens: &[Token]) -> String {
+    tokens.iter().map(|t| t.value.as_str()).collect()
+}
+
 /// Escape special characters and clone string reference
 fn escape_chars_and_clone(s: &str) -> String {
     match s {
@@ -130,10 +189,13 @@ pub fn generate_and_write_patch(edits: &Vec<Edit>, filename: &str) -> Result<(),
 #[cfg(test)]
 mod tests {
     use crate::temsync::{
-        meyers::get_diff, patch::generate_patch, token::Token, tokenizer::parse_file_tokens,
+        meyers::get_diff,
+        patch::generate_patch,
+        token::Token,
+        tokenizer::{parse_file_tokens, parse_string_tokens},
     };
 
-    use super::read_patch;
+    use super::{apply_patch, read_patch, tokens_to_string};
 
     #[test]
     pub fn test_generate_patch() {
@@ -199,4 +261,42 @@ mod tests {
         let out = read_patch(patch).expect("read_patch failed");
         println!("{:?}", out);
     }
+
+    #[test]
+    pub fn test_apply_patch_roundtrip() {
+        let a = parse_file_tokens("./src/temsync/test-files/poem-a")
+            .expect("tokenizer parse_file_tokens failed");
+        let b = parse_file_tokens("./src/temsync/test-files/poem-b")
+            .expect("tokenizer parse_file_tokens failed");
+
+        // Go through the text format to make sure nothing is lost on the way
+        let patch = generate_patch(&get_diff(&a, &b).expect("meyers get_diff failed"));
+        let edits = read_patch(&patch).expect("read_patch failed");
+        let out = apply_patch(&a, &edits).expect("apply_patch failed");
+
+        assert_eq!(tokens_to_string(&out), tokens_to_string(&b));
+    }
+
+    #[test]
+    pub fn test_apply_patch_edges() {
+        // Edits touching the first token used to underflow when generating the patch
+        let a = parse_string_tokens("A car").expect("tokenizer parse_string_tokens failed");
+        let b = parse_string_tokens("My car").expect("tokenizer parse_string_tokens failed");
+        let patch = generate_patch(&get_diff(&a, &b).expect("meyers get_diff failed"));
+        let edits = read_patch(&patch).expect("read_patch failed");
+        let out = apply_patch(&a, &edits).expect("apply_patch failed");
+        assert_eq!(tokens_to_string(&out), "My car");
+
+        // An empty patch is a no-op
+        let edits = read_patch("").expect("read_patch failed");
+        assert_eq!(apply_patch(&a, &edits).expect("apply_patch failed"), a);
+    }
+
+    #[test]
+    pub fn test_apply_patch_mismatch() {
+        let a = parse_string_tokens("A car").expect("tokenizer parse_string_tokens failed");
+        let edits = read_patch("-2,1#bus").expect("read_patch failed");
+
+        assert!(apply_patch(&a, &edits).is_err());
+    }
 }
@@ -0,0 +1,203 @@
+//! The temsync sync protocol.
+//!
+//! Every request and reply is a JSON frame: the protocol version it was written
+//! for plus a message tagged by `type`. Over HTTP, a client POSTs a
+//! `Frame<ClientMessage>` to `/temsync` and gets back a `Frame<ServerMessage>`.
+//!
+//! ```text
+//! {"version":1,"type":"push-patch","note":"todo.md","base_revision":3,"patch":"+4,5#done\n"}
+//! {"version":1,"type":"ack","note":"todo.md","revision":4}
+//! ```
+//!
+//! A session starts with `hello`, where the client lists every version it can
+//! speak and the server replies with a `hello` frame whose version is the
+//! highest one they share (or an error if there is none). Later frames have
+//! to use the negotiated version.
+//!
+//! Each note has a revision counter on the server. Revision 0 is the empty note,
+//! and every accepted patch bumps it by one. Patches are in the temsync patch
+//! format (see `patch::generate_patch`) and are only accepted against the
+//! latest revision. Anything older gets a `conflict` reply, and the client
+//! has to `pull-since` its last revision, rebase and push again.
+//!
+//! Clients can encrypt patches and note names end to end (see `crypto`).
+//! Encrypted values start with `ENCRYPTED_PREFIX`, and the server stores them
+//! as-is without trying to apply them. Servers can also require a per-device
+//! token, sent over HTTP as `Authorization: Bearer <token>`.
+
+use serde::{Deserialize, Serialize};
+
+/// Version this build writes by default
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Every version this build can speak, oldest first
+pub const SUPPORTED_VERSIONS: &[u32] = &[1];
+
+/// Capability for servers that can list all of their notes
+pub const CAP_LIST_NOTES: &str = "list-notes";
+
+/// Capability for servers that store encrypted patches without reading them
+pub const CAP_ENCRYPTED: &str = "encrypted-payloads";
+
+/// Marks a patch or note name that was encrypted by the client
+pub const ENCRYPTED_PREFIX: &str = "enc1:";
+
+/// A message together with the protocol version it uses
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Frame<T> {
+    pub version: u32,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+impl<T> Frame<T> {
+    pub fn new(version: u32, message: T) -> Self {
+        Frame { version, message }
+    }
+}
+
+/// Latest revision of a single note
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NoteRevision {
+    pub note: String,
+    pub revision: usize,
+}
+
+/// A patch together with the revision it produces
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RevisionPatch {
+    pub revision: usize,
+    pub patch: String,
+}
+
+/// Messages sent from a client to the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ClientMessage {
+    /// Opens a session, listing the versions and capabilities the client supports
+    Hello {
+        versions: Vec<u32>,
+        capabilities: Vec<String>,
+    },
+    /// Asks for every note and its latest revision
+    ListNotes,
+    /// Pushes a patch made against base_revision
+    PushPatch {
+        note: String,
+        base_revision: usize,
+        patch: String,
+    },
+    /// Asks for all patches after the given revision
+    PullSince { note: String, since: usize },
+}
+
+/// Messages sent from the server back to a client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ServerMessage {
+    /// Reply to hello, the frame carries the negotiated version
+    Hello { capabilities: Vec<String> },
+    /// Reply to list-notes, sorted by note
+    Notes { notes: Vec<NoteRevision> },
+    /// Reply to pull-since, patches are in revision order
+    Patches {
+        note: String,
+        revision: usize,
+        patches: Vec<RevisionPatch>,
+    },
+    /// The pushed patch was accepted as the given revision
+    Ack { note: String, revision: usize },
+    /// The push was made against an older revision than the server's
+    Conflict {
+        note: String,
+        base_revision: usize,
+        revision: usize,
+    },
+    /// The request couldn't be handled (bad frame, unsupported version, invalid patch)
+    Error { message: String },
+}
+
+/// Picks the highest version both sides support
+pub fn negotiate(offered: &[u32]) -> Option<u32> {
+    offered
+        .iter()
+        .filter(|v| SUPPORTED_VERSIONS.contains(v))
+        .max()
+        .copied()
+}
+
+/// Whether a patch or note name is client-side encrypted
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(ENCRYPTED_PREFIX)
+}
+
+/// Serializes a frame to its JSON wire format
+pub fn encode<T: Serialize>(frame: &Frame<T>) -> String {
+    serde_json::to_string(frame).expect("protocol frames should always serialize")
+}
+
+/// Parses a frame from its JSON wire format
+pub fn decode<T: for<'de> Deserialize<'de>>(raw: &str) -> Result<Frame<T>, String> {
+    serde_json::from_str(raw).map_err(|e| format!("invalid frame: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wire_format() {
+        // Alternate servers depend on this exact shape, so check it literally
+        let frame = Frame::new(
+            1,
+            ClientMessage::PushPatch {
+                note: "todo.md".into(),
+                base_revision: 3,
+                patch: "+4,5#done\n".into(),
+            },
+        );
+        assert_eq!(
+            encode(&frame),
+            r#"{"version":1,"type":"push-patch","note":"todo.md","base_revision":3,"patch":"+4,5#done\n"}"#
+        );
+
+        let raw =
+            r#"{"version":1,"type":"conflict","note":"todo.md","base_revision":3,"revision":5}"#;
+        let frame = decode::<ServerMessage>(raw).expect("decode failed");
+        assert_eq!(
+            frame.message,
+            ServerMessage::Conflict {
+                note: "todo.md".into(),
+                base_revision: 3,
+                revision: 5
+            }
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let messages = vec![
+            ClientMessage::Hello {
+                versions: vec![1, 2],
+                capabilities: vec![CAP_LIST_NOTES.into()],
+            },
+            ClientMessage::ListNotes,
+            ClientMessage::PullSince {
+                note: "a.md".into(),
+                since: 2,
+            },
+        ];
+        for m in messages.into_iter() {
+            let frame = Frame::new(PROTOCOL_VERSION, m);
+            assert_eq!(decode::<ClientMessage>(&encode(&frame)), Ok(frame));
+        }
+        assert!(decode::<ClientMessage>(r#"{"version":1,"type":"shout"}"#).is_err());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(&[1, 2, 3]), Some(1));
+        assert_eq!(negotiate(&[7]), None);
+        assert_eq!(negotiate(&[]), None);
+    }
+}
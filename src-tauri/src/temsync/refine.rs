@@ -0,0 +1,154 @@
+use serde::Serialize;
+
+use super::{
+    edit::{Edit, EditType},
+    meyers::get_diff,
+    token::Token,
+    tokenizer::parse_string_tokens,
+    worddiff::fill_same,
+};
+
+/// Character level diff of a deleted run and the run inserted in its place,
+/// eg. `a` -> `an` or `Generator` -> `Creator`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Refinement {
+    /// Token index of the deleted run in the old text
+    pub old_index: usize,
+    /// Token index of the inserted run in the new text
+    pub new_index: usize,
+    /// Edits of single character tokens, indexed by character within the runs.
+    /// Token starts and lengths are in UTF-16 units from the start of the run,
+    /// like the tokenizer's starts.
+    pub edits: Vec<Edit>,
+}
+
+/// Diffs the characters of every deleted run that has an inserted run next to
+/// it, for highlighting just the characters that changed. Runs with too little
+/// in common (less than half of the longer one unchanged, eg. `dog` -> `cat`)
+/// read better as whole words, so they're left out. Like render_word_diff, the
+/// edits only need their inserts and deletes (eg. from read_patch).
+pub fn refine(edits: &[Edit], prev: &str, curr: &str) -> Result<Vec<Refinement>, String> {
+    let prev = parse_string_tokens(prev).map_err(|e| e.to_string())?;
+    let curr = parse_string_tokens(curr).map_err(|e| e.to_string())?;
+    // Runs are what's between unchanged text, so that has to be there
+    let edits = fill_same(edits, &prev, &curr)?;
+
+    let mut out = Vec::<Refinement>::new();
+    for run in edits.split(|e| e.edit_type == EditType::SAME) {
+        let deleted = run.iter().find(|e| e.edit_type == EditType::DELETE);
+        let inserted = run.iter().find(|e| e.edit_type == EditType::INSERT);
+        let (Some(deleted), Some(inserted)) = (deleted, inserted) else {
+            continue;
+        };
+
+        let prev = char_tokens(run, EditType::DELETE);
+        let curr = char_tokens(run, EditType::INSERT);
+        let char_edits = get_diff(&prev, &curr)?;
+        let same = char_edits
+            .iter()
+            .filter(|e| e.edit_type == EditType::SAME)
+            .map(|e| e.tokens.len())
+            .sum::<usize>();
+        if same * 2 < prev.len().max(curr.len()) {
+            continue;
+        }
+        out.push(Refinement {
+            old_index: deleted.old_index,
+            new_index: inserted.new_index,
+            edits: char_edits,
+        });
+    }
+    Ok(out)
+}
+
+/// Splits the tokens of one side of a run into characters
+fn char_tokens(run: &[Edit], edit_type: EditType) -> Vec<Token> {
+    let mut start = 0;
+    let mut out = Vec::<Token>::new();
+    for c in run
+        .iter()
+        .filter(|e| e.edit_type == edit_type)
+        .flat_map(|e| e.tokens.iter())
+        .flat_map(|t| t.value.chars())
+    {
+        out.push(Token {
+            start,
+            len: c.len_utf16(),
+            value: c.to_string(),
+        });
+        start += c.len_utf16();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::temsync::patch::{generate_patch, read_patch};
+
+    use super::*;
+
+    fn refined(prev: &str, curr: &str) -> Vec<Refinement> {
+        let a = parse_string_tokens(prev).expect("tokenizer parse_string_tokens failed");
+        let b = parse_string_tokens(curr).expect("tokenizer parse_string_tokens failed");
+        let edits = get_diff(&a, &b).expect("meyers get_diff failed");
+        refine(&edits, prev, curr).expect("refine failed")
+    }
+
+    /// The changes as `-x`/`+x` strings, to compare easily
+    fn changes(refinement: &Refinement) -> Vec<String> {
+        refinement
+            .edits
+            .iter()
+            .map(|e| {
+                let text = e
+                    .tokens
+                    .iter()
+                    .map(|t| t.value.as_str())
+                    .collect::<String>();
+                match e.edit_type {
+                    EditType::INSERT => format!("+{}", text),
+                    EditType::DELETE => format!("-{}", text),
+                    EditType::SAME => text,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_refine() {
+        let out = refined("a Generator", "an Creator");
+        assert_eq!(out.len(), 2);
+        assert_eq!((out[0].old_index, out[0].new_index), (0, 0));
+        assert_eq!(changes(&out[0]), vec!["a", "+n"]);
+        assert_eq!(changes(&out[1]), vec!["-Gene", "+C", "r", "+e", "ator"]);
+        assert_eq!(out[1].edits[2].old_index, 4);
+
+        // Typo fixes are a single character
+        let out = refined("the quikc fox", "the quick fox");
+        assert_eq!(changes(&out[0]), vec!["qui", "-k", "c", "+k"]);
+
+        // Patches only have the changes, which are still refined one run at a time
+        let (prev, curr) = ("a Generator x", "an Creator x");
+        let a = parse_string_tokens(prev).unwrap();
+        let b = parse_string_tokens(curr).unwrap();
+        let patch = generate_patch(&get_diff(&a, &b).unwrap());
+        let edits = read_patch(&patch).unwrap();
+        assert_eq!(refine(&edits, prev, curr), Ok(refined(prev, curr)));
+        assert!(refine(&edits, "a Generator", curr).is_err());
+
+        // Positions count UTF-16 units, like the tokenizer
+        let out = refined("café au lait", "cafés au lait");
+        assert_eq!(changes(&out[0]), vec!["café", "+s"]);
+        assert_eq!(
+            (
+                out[0].edits[1].tokens[0].start,
+                out[0].edits[1].tokens[0].len
+            ),
+            (4, 1)
+        );
+
+        // Nothing to refine when words are only added or swapped for different ones
+        assert_eq!(refined("my dog", "my cat"), vec![]);
+        assert_eq!(refined("a dog", "a red dog"), vec![]);
+    }
+}
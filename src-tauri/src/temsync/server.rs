@@ -0,0 +1,596 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use tiny_http::{Header, Method, Request, Response};
+
+use super::{
+    auth::DeviceTokens,
+    names::{decode_component, encode_component, file_component},
+    patch::{apply_patch, read_patch, tokens_to_string},
+    protocol::{
+        self, ClientMessage, Frame, NoteRevision, RevisionPatch, ServerMessage, CAP_ENCRYPTED,
+        CAP_LIST_NOTES, SUPPORTED_VERSIONS,
+    },
+    tokenizer::parse_string_tokens,
+};
+
+/// Extension used for the per-note history directories in the store
+const STORE_EXT: &str = "temsync";
+
+/// File in a note's store folder holding the note name, for folders named by
+/// file_component's hash that can't be decoded back
+const NAME_FILE: &str = "name";
+
+/// Reasons the store can refuse a patch
+#[derive(Debug, Clone, PartialEq)]
+pub enum PushError {
+    /// The patch was made against an older revision, the client needs to pull first
+    Stale { current: usize },
+    /// The patch could not be parsed or does not apply to the base revision
+    Invalid(String),
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PushError::Stale { current } => {
+                write!(f, "base revision is stale, current revision is {}", current)
+            }
+            PushError::Invalid(e) => write!(f, "invalid patch: {}", e),
+        }
+    }
+}
+
+impl Error for PushError {}
+
+/// History of one note: every accepted patch and the content they build up to
+#[derive(Debug, Default)]
+struct NoteHistory {
+    patches: Vec<String>,
+    content: String,
+}
+
+impl NoteHistory {
+    /// Applies the patch to the current content, returning the new content.
+    /// Encrypted patches can't be read, so they are stored without being applied.
+    fn apply(&self, patch: &str) -> Result<String, String> {
+        let encrypted = protocol::is_encrypted(patch);
+        if !self.patches.is_empty() && encrypted != self.is_encrypted() {
+            return Err("cannot mix encrypted and plaintext patches in one note".into());
+        }
+        if encrypted {
+            return Ok(String::new());
+        }
+
+        let base = parse_string_tokens(&self.content).map_err(|e| e.to_string())?;
+        let edits = read_patch(patch).map_err(|e| e.to_string())?;
+        Ok(tokens_to_string(&apply_patch(&base, &edits)?))
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.patches
+            .first()
+            .map(|p| protocol::is_encrypted(p))
+            .unwrap_or(false)
+    }
+}
+
+/// Canonical revision store. Revision 0 is the empty note and each accepted patch
+/// bumps the revision by one. If a root is set, patches are also written to
+/// `<root>/<note>.temsync/<revision>.patch` so the history survives restarts.
+#[derive(Debug, Default)]
+pub struct RevisionStore {
+    root: Option<PathBuf>,
+    notes: HashMap<String, NoteHistory>,
+}
+
+impl RevisionStore {
+    /// Creates an empty store that is only kept in memory
+    pub fn new() -> Self {
+        RevisionStore::default()
+    }
+
+    /// Opens (or creates) a store backed by the given directory, replaying all saved patches
+    pub fn open(root: &Path) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(root)?;
+        let mut store = RevisionStore {
+            root: Some(root.to_path_buf()),
+            notes: HashMap::new(),
+        };
+
+        for entry in fs::read_dir(root)? {
+            let path = entry?.path();
+            if !path.is_dir() || path.extension().and_then(|e| e.to_str()) != Some(STORE_EXT) {
+                continue;
+            }
+            let note = match fs::read_to_string(path.join(NAME_FILE)) {
+                Ok(name) => name,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    decode_component(path.file_stem().and_then(|s| s.to_str()).unwrap_or(""))?
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            // Patch files are zero-padded so sorting by name gives revision order
+            let mut files = fs::read_dir(&path)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<Result<Vec<PathBuf>, _>>()?;
+            files.retain(|p| p.extension().and_then(|e| e.to_str()) == Some("patch"));
+            files.sort();
+
+            let mut history = NoteHistory::default();
+            for file in files.iter() {
+                let patch = fs::read_to_string(file)?;
+                history.content = history
+                    .apply(&patch)
+                    .map_err(|e| format!("corrupt history for {}: {}", note, e))?;
+                history.patches.push(patch);
+            }
+            store.notes.insert(note, history);
+        }
+
+        Ok(store)
+    }
+
+    /// Current revision of a note, 0 if the store has never seen it
+    pub fn revision(&self, note: &str) -> usize {
+        self.notes.get(note).map(|h| h.patches.len()).unwrap_or(0)
+    }
+
+    /// Current content of a note, None for encrypted notes since the server can't read them
+    pub fn content(&self, note: &str) -> Option<&str> {
+        self.notes
+            .get(note)
+            .filter(|h| !h.is_encrypted())
+            .map(|h| h.content.as_str())
+    }
+
+    /// Lists every note with its latest revision, sorted by name
+    pub fn list(&self) -> Vec<NoteRevision> {
+        let mut out = self
+            .notes
+            .iter()
+            .map(|(note, h)| NoteRevision {
+                note: note.clone(),
+                revision: h.patches.len(),
+            })
+            .collect::<Vec<NoteRevision>>();
+        out.sort_by(|a, b| a.note.cmp(&b.note));
+        out
+    }
+
+    /// Returns all patches made after the given revision
+    pub fn since(&self, note: &str, revision: usize) -> Vec<RevisionPatch> {
+        match self.notes.get(note) {
+            Some(h) => h
+                .patches
+                .iter()
+                .enumerate()
+                .skip(revision)
+                .map(|(i, p)| RevisionPatch {
+                    revision: i + 1,
+                    patch: p.clone(),
+                })
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Accepts a patch made against base_revision, returning the new revision
+    pub fn push(
+        &mut self,
+        note: &str,
+        base_revision: usize,
+        patch: &str,
+    ) -> Result<usize, PushError> {
+        if note.is_empty() {
+            return Err(PushError::Invalid("note name cannot be empty".into()));
+        }
+
+        // Only patches against the latest revision are accepted
+        let current = self.revision(note);
+        if base_revision != current {
+            return Err(PushError::Stale { current });
+        }
+
+        // Apply first so a bad patch leaves the store untouched
+        let content = match self.notes.get(note) {
+            Some(h) => h.apply(patch),
+            None => NoteHistory::default().apply(patch),
+        }
+        .map_err(PushError::Invalid)?;
+
+        // Write to disk before committing in memory so a failed write doesn't diverge
+        if let Some(root) = &self.root {
+            let name = file_component(note);
+            let dir = root.join(format!("{}.{}", name, STORE_EXT));
+            fs::create_dir_all(&dir)
+                .and_then(|_| {
+                    if name == encode_component(note) {
+                        Ok(())
+                    } else {
+                        fs::write(dir.join(NAME_FILE), note)
+                    }
+                })
+                .and_then(|_| fs::write(dir.join(format!("{:08}.patch", current + 1)), patch))
+                .map_err(|e| PushError::Invalid(format!("unable to save patch: {}", e)))?;
+        }
+
+        let history = self.notes.entry(note.to_string()).or_default();
+        history.patches.push(patch.to_string());
+        history.content = content;
+        Ok(current + 1)
+    }
+
+    /// Answers a single protocol message
+    pub fn handle(&mut self, message: ClientMessage) -> ServerMessage {
+        match message {
+            ClientMessage::Hello { .. } => ServerMessage::Hello {
+                capabilities: vec![CAP_LIST_NOTES.into(), CAP_ENCRYPTED.into()],
+            },
+            ClientMessage::ListNotes => ServerMessage::Notes { notes: self.list() },
+            ClientMessage::PullSince { note, since } => ServerMessage::Patches {
+                revision: self.revision(&note),
+                patches: self.since(&note, since),
+                note,
+            },
+            ClientMessage::PushPatch {
+                note,
+                base_revision,
+                patch,
+            } => match self.push(&note, base_revision, &patch) {
+                Ok(revision) => ServerMessage::Ack { note, revision },
+                Err(PushError::Stale { current }) => ServerMessage::Conflict {
+                    note,
+                    base_revision,
+                    revision: current,
+                },
+                Err(e) => ServerMessage::Error {
+                    message: e.to_string(),
+                },
+            },
+        }
+    }
+}
+
+/// Answers a frame, checking its version first. Hello is the only message
+/// allowed before a version has been agreed on.
+pub fn handle_frame(
+    store: &mut RevisionStore,
+    frame: Frame<ClientMessage>,
+) -> Frame<ServerMessage> {
+    let version = match &frame.message {
+        ClientMessage::Hello { versions, .. } => match protocol::negotiate(versions) {
+            Some(v) => v,
+            None => {
+                return error_frame(format!(
+                    "no common protocol version, server supports {:?}",
+                    SUPPORTED_VERSIONS
+                ))
+            }
+        },
+        _ if SUPPORTED_VERSIONS.contains(&frame.version) => frame.version,
+        _ => return error_frame(format!("unsupported protocol version {}", frame.version)),
+    };
+
+    Frame::new(version, store.handle(frame.message))
+}
+
+/// HTTP front end for a RevisionStore. Clients POST protocol frames to
+/// `/temsync` and get a frame back (see the protocol module).
+pub struct SyncServer {
+    server: Arc<tiny_http::Server>,
+    store: Arc<Mutex<RevisionStore>>,
+    auth: Option<DeviceTokens>,
+}
+
+impl SyncServer {
+    /// Binds the server to the address (use port 0 to pick a free one)
+    pub fn bind(addr: &str, store: RevisionStore) -> Result<Self, Box<dyn Error>> {
+        let server = tiny_http::Server::http(addr).map_err(|e| e.to_string())?;
+        Ok(SyncServer {
+            server: Arc::new(server),
+            store: Arc::new(Mutex::new(store)),
+            auth: None,
+        })
+    }
+
+    /// Requires every request to carry one of the device tokens
+    pub fn with_auth(mut self, tokens: DeviceTokens) -> Self {
+        self.auth = Some(tokens);
+        self
+    }
+
+    /// Address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server
+            .server_addr()
+            .to_ip()
+            .expect("sync server should be bound to an ip address")
+    }
+
+    /// Serves requests until the server is shut down
+    pub fn run(&self) {
+        for request in self.server.incoming_requests() {
+            handle_request(&self.store, self.auth.as_ref(), request);
+        }
+    }
+
+    /// Serves requests on a background thread
+    pub fn spawn(self) -> ServerHandle {
+        let addr = self.local_addr();
+        let server = self.server.clone();
+        let thread = thread::spawn(move || self.run());
+        ServerHandle {
+            addr,
+            server,
+            thread,
+        }
+    }
+}
+
+/// Handle to a server running on a background thread
+pub struct ServerHandle {
+    addr: SocketAddr,
+    server: Arc<tiny_http::Server>,
+    thread: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops accepting requests and waits for the server thread to exit
+    pub fn shutdown(self) {
+        self.server.unblock();
+        let _ = self.thread.join();
+    }
+}
+
+fn handle_request(store: &Mutex<RevisionStore>, auth: Option<&DeviceTokens>, mut request: Request) {
+    let (status, reply) = match (request.method(), request.url()) {
+        (Method::Post, "/temsync") if !is_authorized(auth, &request) => {
+            (401, error_frame("missing or unknown device token".into()))
+        }
+        (Method::Post, "/temsync") => {
+            let mut raw = String::new();
+            let frame = request
+                .as_reader()
+                .read_to_string(&mut raw)
+                .map_err(|e| e.to_string())
+                .and_then(|_| protocol::decode::<ClientMessage>(&raw));
+            match frame {
+                Ok(frame) => {
+                    // A request that panicked mid-push still left the store whole, since
+                    // push only changes it once nothing else can fail, so keep serving
+                    let mut store = store.lock().unwrap_or_else(|e| e.into_inner());
+                    let reply = handle_frame(&mut store, frame);
+                    match reply.message {
+                        ServerMessage::Error { .. } => (400, reply),
+                        _ => (200, reply),
+                    }
+                }
+                Err(e) => (400, error_frame(e)),
+            }
+        }
+        (_, url) => (404, error_frame(format!("no route for {}", url))),
+    };
+
+    let header = Header::from_bytes("Content-Type", "application/json")
+        .expect("content type header should be valid");
+    let response = Response::from_string(protocol::encode(&reply))
+        .with_status_code(status)
+        .with_header(header);
+
+    // The client may have gone away already, nothing to do about it here
+    let _ = request.respond(response);
+}
+
+/// Checks the `Authorization: Bearer <token>` header if the server requires tokens
+fn is_authorized(auth: Option<&DeviceTokens>, request: &Request) -> bool {
+    let tokens = match auth {
+        Some(t) => t,
+        None => return true,
+    };
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+        .and_then(|token| tokens.verify(token.trim()))
+        .is_some()
+}
+
+fn error_frame(message: String) -> Frame<ServerMessage> {
+    Frame::new(protocol::PROTOCOL_VERSION, ServerMessage::Error { message })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::temsync::{meyers::get_diff, patch::generate_patch, tokenizer::parse_string_tokens};
+
+    use super::*;
+
+    /// Makes the patch that takes a note from one text to another
+    fn make_patch(prev: &str, curr: &str) -> String {
+        let a = parse_string_tokens(prev).expect("tokenizer parse_string_tokens failed");
+        let b = parse_string_tokens(curr).expect("tokenizer parse_string_tokens failed");
+        generate_patch(&get_diff(&a, &b).expect("meyers get_diff failed"))
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tem-{}-{}.temsync", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_store_push_and_since() {
+        let mut store = RevisionStore::new();
+
+        let p1 = make_patch("", "A car ate my dog.");
+        let p2 = make_patch("A car ate my dog.", "A red car ate my dog.");
+        assert_eq!(store.push("poem", 0, &p1), Ok(1));
+        assert_eq!(store.push("poem", 1, &p2), Ok(2));
+        assert_eq!(store.content("poem"), Some("A red car ate my dog."));
+
+        // A push against an old revision is rejected with the current revision
+        let p3 = make_patch("A car ate my dog.", "A car ate my cat.");
+        assert_eq!(
+            store.push("poem", 1, &p3),
+            Err(PushError::Stale { current: 2 })
+        );
+
+        let since = store.since("poem", 1);
+        assert_eq!(since.len(), 1);
+        assert_eq!(since[0].revision, 2);
+    }
+
+    #[test]
+    fn test_store_rejects_bad_patch() {
+        let mut store = RevisionStore::new();
+        assert!(matches!(
+            store.push("poem", 0, "-0,0#nothing"),
+            Err(PushError::Invalid(_))
+        ));
+        assert_eq!(store.revision("poem"), 0);
+        assert!(store.list().is_empty());
+    }
+
+    #[test]
+    fn test_store_encrypted_notes() {
+        let mut store = RevisionStore::new();
+
+        // Ciphertext is stored as-is, the server never sees the content
+        assert_eq!(store.push("enc1:abc", 0, "enc1:one"), Ok(1));
+        assert_eq!(store.push("enc1:abc", 1, "enc1:two"), Ok(2));
+        assert_eq!(store.content("enc1:abc"), None);
+        assert_eq!(store.since("enc1:abc", 1)[0].patch, "enc1:two");
+
+        // A note is either all ciphertext or all plaintext
+        let plain = make_patch("", "hello");
+        assert!(matches!(
+            store.push("enc1:abc", 2, &plain),
+            Err(PushError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_store_reopen() {
+        let dir = test_dir("store-reopen");
+        {
+            let mut store = RevisionStore::open(&dir).expect("open failed");
+            store
+                .push("notes/today.md", 0, &make_patch("", "hello world"))
+                .expect("push failed");
+            store
+                .push(&"long ".repeat(100), 0, &make_patch("", "long"))
+                .expect("push of a long name failed");
+        }
+
+        let store = RevisionStore::open(&dir).expect("reopen failed");
+        assert_eq!(store.revision("notes/today.md"), 1);
+        assert_eq!(store.content("notes/today.md"), Some("hello world"));
+        assert_eq!(store.content(&"long ".repeat(100)), Some("long"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_handle_frame_versions() {
+        let mut store = RevisionStore::new();
+
+        let hello = ClientMessage::Hello {
+            versions: vec![1, 99],
+            capabilities: vec![],
+        };
+        let reply = handle_frame(&mut store, Frame::new(99, hello));
+        assert_eq!(reply.version, 1);
+        assert!(matches!(reply.message, ServerMessage::Hello { .. }));
+
+        // Anything but hello has to use a supported version
+        let reply = handle_frame(&mut store, Frame::new(99, ClientMessage::ListNotes));
+        assert!(matches!(reply.message, ServerMessage::Error { .. }));
+
+        let hello = ClientMessage::Hello {
+            versions: vec![99],
+            capabilities: vec![],
+        };
+        let reply = handle_frame(&mut store, Frame::new(99, hello));
+        assert!(matches!(reply.message, ServerMessage::Error { .. }));
+    }
+
+    #[test]
+    fn test_server_roundtrip() {
+        let server = SyncServer::bind("127.0.0.1:0", RevisionStore::new()).expect("bind failed");
+        let handle = server.spawn();
+        let url = format!("http://{}/temsync", handle.addr());
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .build()
+            .into();
+        let send = |message: ClientMessage| -> ServerMessage {
+            let raw = agent
+                .post(&url)
+                .send(protocol::encode(&Frame::new(1, message)))
+                .expect("request failed")
+                .body_mut()
+                .read_to_string()
+                .expect("bad response body");
+            protocol::decode::<ServerMessage>(&raw)
+                .expect("bad response frame")
+                .message
+        };
+
+        // First client creates the note
+        let push = ClientMessage::PushPatch {
+            note: "a b/c.md".into(),
+            base_revision: 0,
+            patch: make_patch("", "one two"),
+        };
+        let reply = send(push.clone());
+        assert!(matches!(reply, ServerMessage::Ack { revision: 1, .. }));
+
+        // A second push against revision 0 is stale
+        let reply = send(push);
+        assert!(matches!(reply, ServerMessage::Conflict { revision: 1, .. }));
+
+        // A client that is behind gets the missing patch
+        let reply = send(ClientMessage::PullSince {
+            note: "a b/c.md".into(),
+            since: 0,
+        });
+        match reply {
+            ServerMessage::Patches {
+                revision, patches, ..
+            } => {
+                assert_eq!(revision, 1);
+                assert_eq!(patches[0].patch, make_patch("", "one two"));
+            }
+            m => panic!("expected patches, got {:?}", m),
+        }
+
+        let reply = send(ClientMessage::ListNotes);
+        assert_eq!(
+            reply,
+            ServerMessage::Notes {
+                notes: vec![NoteRevision {
+                    note: "a b/c.md".into(),
+                    revision: 1
+                }]
+            }
+        );
+
+        handle.shutdown();
+    }
+}
@@ -1,7 +1,9 @@
 use std::error::Error;
 use std::str;
 
-#[derive(Debug, Clone, PartialEq)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Token {
     pub start: usize,
     pub len: usize,
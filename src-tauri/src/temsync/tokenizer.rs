@@ -35,7 +35,15 @@ pub fn parse_tokens(buffer: &str) -> Result<Vec<Token>, Box<dyn Error>> {
         return Ok(tokens);
     }
 
-    for (i, char) in buffer.encode_utf16().enumerate() {
+    // i is the UTF-16 index of the character. Characters outside the BMP (eg. emoji)
+    // are two UTF-16 units, so the character is walked rather than single units.
+    let mut i: usize = 0;
+    let mut units = [0u16; 2];
+    for c in buffer.chars() {
+        let encoded = c.encode_utf16(&mut units);
+        let char = encoded[0];
+        let len = encoded.len();
+
         // If alphanumeric, keep going (appending to current token)
         // Note this accounts for the entire Unicode range
         // See https://www.fileformat.info/info/charset/UTF-16/list.htm
@@ -48,6 +56,7 @@ pub fn parse_tokens(buffer: &str) -> Result<Vec<Token>, Box<dyn Error>> {
         // ø-ʯ
         {
             sb.push(char);
+            i += len;
             continue;
         }
 
@@ -58,15 +67,17 @@ pub fn parse_tokens(buffer: &str) -> Result<Vec<Token>, Box<dyn Error>> {
         }
 
         // Then push current character onto stack
-        tokens.push(Token::new(i, String::from_utf16(&vec![char])?.as_str()).unwrap());
+        tokens.push(Token::new(i, c.encode_utf8(&mut [0; 4])).unwrap());
 
         // Set start to next character
-        start = i + 1;
+        i += len;
+        start = i;
     }
 
     // Push last alpha token to output if last char is alphabetical
-    if start != buffer.len() {
-        tokens.push(Token::new(start, &buffer[start..]).unwrap());
+    // (start is a UTF-16 index, so the buffered chars are used instead of slicing the buffer)
+    if !sb.is_empty() {
+        tokens.push(Token::new(start, String::from_utf16(&sb)?.as_str()).unwrap());
     }
 
     Ok(tokens)
@@ -115,6 +126,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_tokenizer_unicode_tail() {
+        // The last word comes after a multi-byte character, so byte and UTF-16 indices differ
+        let tokens = parse_string_tokens("né café").expect("tokenizer parse_string failed");
+
+        let values = tokens
+            .iter()
+            .map(|t| t.value.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(values, vec!["né", " ", "café"]);
+    }
+
+    #[test]
+    fn test_tokenizer_surrogate_pair() {
+        // Characters outside the BMP are one token, two UTF-16 units long
+        let tokens = parse_string_tokens("hi 🚗 car").expect("tokenizer parse_string failed");
+
+        let values = tokens
+            .iter()
+            .map(|t| (t.start, t.value.as_str()))
+            .collect::<Vec<(usize, &str)>>();
+        assert_eq!(
+            values,
+            vec![(0, "hi"), (2, " "), (3, "🚗"), (5, " "), (6, "car")]
+        );
+    }
+
     /// To see this result, use the command cargo test -- --nocapture
     #[test]
     fn test_tokenizer_complex() {
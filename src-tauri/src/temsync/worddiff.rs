@@ -0,0 +1,343 @@
+use super::{
+    edit::{Edit, EditType},
+    token::Token,
+    tokenizer::parse_string_tokens,
+};
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+/// Marks text left out between changes in context mode
+const GAP: &str = "...";
+
+/// How changed words are marked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WordDiffStyle {
+    /// Deleted words in red and inserted ones in green
+    Color,
+    /// `[-deleted-]{+inserted+}`, like `git diff --word-diff=plain`
+    Plain,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WordDiffOptions {
+    pub style: WordDiffStyle,
+    /// Wraps lines at this many columns, breaking between tokens
+    pub width: Option<usize>,
+    /// Only shows this many unchanged tokens on each side of a change
+    pub context: Option<usize>,
+}
+
+impl Default for WordDiffOptions {
+    fn default() -> Self {
+        WordDiffOptions {
+            style: WordDiffStyle::Color,
+            width: None,
+            context: None,
+        }
+    }
+}
+
+/// Renders a diff of two texts as a word diff for the terminal. The edits only
+/// need their inserts and deletes (eg. from read_patch), unchanged text is
+/// taken from the texts themselves.
+pub fn render_word_diff(
+    edits: &[Edit],
+    prev: &str,
+    curr: &str,
+    options: &WordDiffOptions,
+) -> Result<String, String> {
+    let prev = parse_string_tokens(prev).map_err(|e| e.to_string())?;
+    let curr = parse_string_tokens(curr).map_err(|e| e.to_string())?;
+    let edits = fill_same(edits, &prev, &curr)?;
+
+    let mut out = Writer::new(options);
+    let context = match options.context {
+        Some(n) => n,
+        None => {
+            for e in edits.iter() {
+                out.tokens(&e.edit_type, &e.tokens);
+            }
+            return Ok(out.finish());
+        }
+    };
+
+    // Nothing changed, so there is nothing to give context for
+    if edits.iter().all(|e| e.edit_type == EditType::SAME) {
+        return Ok(String::new());
+    }
+    for (i, e) in edits.iter().enumerate() {
+        if e.edit_type != EditType::SAME {
+            out.tokens(&e.edit_type, &e.tokens);
+            continue;
+        }
+        let head = if i == 0 { 0 } else { context };
+        let tail = if i + 1 == edits.len() { 0 } else { context };
+        if e.tokens.len() <= head + tail {
+            out.tokens(&e.edit_type, &e.tokens);
+        } else {
+            out.tokens(&e.edit_type, &e.tokens[..head]);
+            out.gap();
+            out.tokens(&e.edit_type, &e.tokens[e.tokens.len() - tail..]);
+        }
+    }
+    Ok(out.finish())
+}
+
+/// Fills in the unchanged runs between the inserts and deletes of a diff, so
+/// the edits cover both token lists from start to end. Any SAME edits given
+/// are replaced.
+pub fn fill_same(edits: &[Edit], prev: &[Token], curr: &[Token]) -> Result<Vec<Edit>, String> {
+    let mut out = Vec::<Edit>::with_capacity(edits.len() * 2 + 1);
+    let (mut i, mut j) = (0, 0);
+    for e in edits.iter() {
+        match e.edit_type {
+            EditType::SAME => continue,
+            // Deletions are positioned by their index in the old tokens
+            EditType::DELETE => {
+                if e.old_index < i || e.old_index + e.tokens.len() > prev.len() {
+                    return Err(format!("deletion at {} is out of range", e.old_index));
+                }
+                (i, j) = push_same(&mut out, prev, (i, j), e.old_index - i);
+                out.push(e.clone());
+                i += e.tokens.len();
+            }
+            // Insertions by their index in the new tokens
+            EditType::INSERT => {
+                if e.new_index < j
+                    || e.new_index + e.tokens.len() > curr.len()
+                    || e.new_index - j > prev.len() - i
+                {
+                    return Err(format!("insertion at {} is out of range", e.new_index));
+                }
+                (i, j) = push_same(&mut out, prev, (i, j), e.new_index - j);
+                out.push(e.clone());
+                j += e.tokens.len();
+            }
+        }
+    }
+
+    if prev.len() - i != curr.len() - j {
+        return Err("edits don't take the old text to the new one".into());
+    }
+    push_same(&mut out, prev, (i, j), prev.len() - i);
+    Ok(out)
+}
+
+/// Adds a SAME edit for the next n tokens, returning the positions after it
+fn push_same(
+    out: &mut Vec<Edit>,
+    prev: &[Token],
+    (i, j): (usize, usize),
+    n: usize,
+) -> (usize, usize) {
+    if n > 0 {
+        let tokens = prev[i..i + n].to_vec();
+        out.push(Edit::new_with_tokens(i, j, EditType::SAME, tokens));
+    }
+    (i + n, j + n)
+}
+
+/// Builds the output a token at a time, keeping track of the column for
+/// wrapping and of the open span so it can be closed and reopened around breaks
+struct Writer<'a> {
+    options: &'a WordDiffOptions,
+    out: String,
+    col: usize,
+    /// Change the last span opened for, None when it's closed
+    open: Option<EditType>,
+    /// Unchanged whitespace, held back until it's known whether the line wraps
+    pending: String,
+}
+
+impl<'a> Writer<'a> {
+    fn new(options: &'a WordDiffOptions) -> Self {
+        Writer {
+            options,
+            out: String::new(),
+            col: 0,
+            open: None,
+            pending: String::new(),
+        }
+    }
+
+    fn tokens(&mut self, kind: &EditType, tokens: &[Token]) {
+        for t in tokens.iter() {
+            self.token(kind, &t.value);
+        }
+    }
+
+    fn token(&mut self, kind: &EditType, value: &str) {
+        if self.open.is_some() && self.open.as_ref() != Some(kind) {
+            self.close();
+        }
+
+        if *kind == EditType::SAME && value != "\n" && value.trim().is_empty() {
+            self.pending.push_str(value);
+            return;
+        }
+        if value == "\n" {
+            self.flush();
+            // Colors are reset at the end of each line so a pager doesn't carry them over
+            if self.options.style == WordDiffStyle::Color {
+                self.close();
+            }
+            self.newline();
+            return;
+        }
+
+        let width = value.chars().count();
+        let marker = match (self.options.style, &self.open) {
+            (WordDiffStyle::Plain, None) if *kind != EditType::SAME => 2,
+            _ => 0,
+        };
+        let pending = self.pending.chars().count();
+        if let Some(max) = self.options.width {
+            if self.col > 0 && self.col + pending + marker + width > max {
+                if self.options.style == WordDiffStyle::Color {
+                    self.close();
+                }
+                // Whitespace at the break isn't needed on either line
+                self.pending.clear();
+                self.newline();
+            }
+        }
+        self.flush();
+
+        if self.open.is_none() && *kind != EditType::SAME {
+            let start = match (self.options.style, kind) {
+                (WordDiffStyle::Color, EditType::DELETE) => RED,
+                (WordDiffStyle::Color, _) => GREEN,
+                (WordDiffStyle::Plain, EditType::DELETE) => "[-",
+                (WordDiffStyle::Plain, _) => "{+",
+            };
+            self.out.push_str(start);
+            self.col += marker;
+            self.open = Some(kind.clone());
+        }
+        self.out.push_str(value);
+        self.col += width;
+    }
+
+    fn close(&mut self) {
+        let kind = match self.open.take() {
+            Some(kind) => kind,
+            None => return,
+        };
+        let end = match (self.options.style, kind) {
+            (WordDiffStyle::Color, _) => RESET,
+            (WordDiffStyle::Plain, EditType::DELETE) => "-]",
+            (WordDiffStyle::Plain, _) => "+}",
+        };
+        self.out.push_str(end);
+        self.col += end.len() * (self.options.style == WordDiffStyle::Plain) as usize;
+    }
+
+    fn flush(&mut self) {
+        self.col += self.pending.chars().count();
+        self.out.push_str(&self.pending);
+        self.pending.clear();
+    }
+
+    fn newline(&mut self) {
+        self.out.push('\n');
+        self.col = 0;
+    }
+
+    /// Marks unchanged text that was left out, on a line of its own
+    fn gap(&mut self) {
+        self.close();
+        self.pending.clear();
+        if self.col > 0 {
+            self.newline();
+        }
+        match self.options.style {
+            WordDiffStyle::Color => self.out.push_str(&format!("{}{}{}", CYAN, GAP, RESET)),
+            WordDiffStyle::Plain => self.out.push_str(GAP),
+        }
+        self.newline();
+    }
+
+    fn finish(mut self) -> String {
+        self.close();
+        self.flush();
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::temsync::{meyers::get_diff, patch::read_patch};
+
+    use super::*;
+
+    fn diff(prev: &str, curr: &str, options: &WordDiffOptions) -> String {
+        let a = parse_string_tokens(prev).expect("tokenizer parse_string_tokens failed");
+        let b = parse_string_tokens(curr).expect("tokenizer parse_string_tokens failed");
+        let edits = get_diff(&a, &b).expect("meyers get_diff failed");
+        render_word_diff(&edits, prev, curr, options).expect("render_word_diff failed")
+    }
+
+    #[test]
+    fn test_render_word_diff() {
+        let (prev, curr) = ("A car ate my dog.", "A red car ate my cat.");
+        let plain = WordDiffOptions {
+            style: WordDiffStyle::Plain,
+            ..Default::default()
+        };
+        assert_eq!(
+            diff(prev, curr, &plain),
+            "A {+red +}car ate my [-dog-]{+cat+}."
+        );
+        assert_eq!(
+            diff(prev, curr, &WordDiffOptions::default()),
+            "A \x1b[32mred \x1b[0mcar ate my \x1b[31mdog\x1b[0m\x1b[32mcat\x1b[0m."
+        );
+
+        // Patches only have the changes, the rest comes from the texts
+        let edits = read_patch("+1,2#red \n-8,9#dog\n+8,10#cat\n").unwrap();
+        assert_eq!(
+            render_word_diff(&edits, prev, curr, &plain),
+            Ok("A {+red +}car ate my [-dog-]{+cat+}.".into())
+        );
+        assert!(render_word_diff(&edits, "A car", curr, &plain).is_err());
+    }
+
+    #[test]
+    fn test_render_word_diff_layout() {
+        let prev = "one two three four five six seven eight nine ten";
+        let curr = "one two three four FIVE six seven eight nine ten";
+
+        // Colors don't count towards the width and are closed at each break
+        let wrapped = WordDiffOptions {
+            width: Some(12),
+            ..Default::default()
+        };
+        assert_eq!(
+            diff("one two three\nfour", "one 2 three\nfour", &wrapped),
+            "one \x1b[31mtwo\x1b[0m\x1b[32m2\x1b[0m\nthree\nfour"
+        );
+        let plain = WordDiffOptions {
+            style: WordDiffStyle::Plain,
+            width: Some(24),
+            context: None,
+        };
+        assert_eq!(
+            diff(prev, curr, &plain),
+            "one two three four\n[-five-]{+FIVE+} six\nseven eight nine ten"
+        );
+
+        let context = WordDiffOptions {
+            style: WordDiffStyle::Plain,
+            width: None,
+            context: Some(2),
+        };
+        assert_eq!(
+            diff(prev, curr, &context),
+            "...\nfour [-five-]{+FIVE+} six\n...\n"
+        );
+        assert_eq!(diff(prev, prev, &context), "");
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fmt,
+};
+
+use serde::Serialize;
+
+use super::{
+    links::{find_attachment, is_attachment, note_name, LinkIndex},
+    markdown::{slug, Heading},
+    note_id, walk_files,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiagnosticKind {
+    /// A link to a note that doesn't exist
+    DeadLink,
+    /// A link to a heading the note doesn't have
+    MissingHeading,
+    /// An image or other attachment that isn't in the vault
+    MissingAttachment,
+    /// No other note links here
+    Orphan,
+    /// Another note has the same name, so wikilinks by name are ambiguous
+    DuplicateTitle,
+}
+
+impl fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DiagnosticKind::DeadLink => "dead-link",
+            DiagnosticKind::MissingHeading => "missing-heading",
+            DiagnosticKind::MissingAttachment => "missing-attachment",
+            DiagnosticKind::Orphan => "orphan",
+            DiagnosticKind::DuplicateTitle => "duplicate-title",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A problem found in a note. Problems with the note as a whole point at its
+/// first line.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub note: String,
+    /// 1-based, the column counts characters
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    /// `note:line:column: kind: message`, like compiler output
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}: {}",
+            self.note, self.line, self.column, self.kind, self.message
+        )
+    }
+}
+
+/// Checks every note in the index for broken links, orphans and duplicate
+/// names, sorted by note and position
+pub fn check_vault(index: &LinkIndex) -> Result<Vec<Diagnostic>, Box<dyn Error>> {
+    let root = index.root();
+    let files = walk_files(root)?
+        .iter()
+        .filter_map(|p| note_id(root, p))
+        .collect::<BTreeSet<String>>();
+
+    let mut out = Vec::<Diagnostic>::new();
+    let mut linked = BTreeSet::<String>::new();
+    for note in index.notes() {
+        for outgoing in index.outgoing(note) {
+            let link = &outgoing.link;
+            let at = |kind: DiagnosticKind, message: String| Diagnostic {
+                kind,
+                note: note.to_string(),
+                line: link.line,
+                column: link.column,
+                message,
+            };
+
+            if is_attachment(link) {
+                if find_attachment(&files, note, link).is_none() {
+                    out.push(at(
+                        DiagnosticKind::MissingAttachment,
+                        format!("no attachment at '{}'", link.target),
+                    ));
+                }
+                continue;
+            }
+
+            let target = match outgoing.resolved {
+                Some(t) => t,
+                None => {
+                    out.push(at(
+                        DiagnosticKind::DeadLink,
+                        format!("no note for '{}'", link.target),
+                    ));
+                    continue;
+                }
+            };
+            if let Some(heading) = &link.heading {
+                if !has_heading(index.headings(&target), heading) {
+                    out.push(at(
+                        DiagnosticKind::MissingHeading,
+                        format!("{} has no heading '{}'", target, heading),
+                    ));
+                }
+            }
+            if target != note {
+                linked.insert(target);
+            }
+        }
+    }
+
+    let whole_note = |kind: DiagnosticKind, note: &str, message: String| Diagnostic {
+        kind,
+        note: note.to_string(),
+        line: 1,
+        column: 1,
+        message,
+    };
+    let mut names = BTreeMap::<String, Vec<&str>>::new();
+    for note in index.notes() {
+        if !linked.contains(note) {
+            out.push(whole_note(
+                DiagnosticKind::Orphan,
+                note,
+                "no other note links here".into(),
+            ));
+        }
+        names
+            .entry(note_name(note).to_lowercase())
+            .or_default()
+            .push(note);
+    }
+    for notes in names.values().filter(|n| n.len() > 1) {
+        for note in notes.iter() {
+            let others = notes
+                .iter()
+                .filter(|n| *n != note)
+                .copied()
+                .collect::<Vec<&str>>();
+            out.push(whole_note(
+                DiagnosticKind::DuplicateTitle,
+                note,
+                format!(
+                    "'{}' is also the name of {}",
+                    note_name(note),
+                    others.join(", ")
+                ),
+            ));
+        }
+    }
+
+    out.sort_by(|a, b| {
+        (&a.note, a.line, a.column, a.kind).cmp(&(&b.note, b.line, b.column, b.kind))
+    });
+    Ok(out)
+}
+
+/// Whether a heading link matches one of the headings. Wikilinks write the heading
+/// text, markdown links usually its slug (`#part-two`), so both are compared as
+/// slugs. Nested wikilink headings (`#Part#Sub`) only need the last one, and block
+/// references (`#^id`) aren't checked.
+fn has_heading(headings: &[Heading], wanted: &str) -> bool {
+    let wanted = wanted.rsplit('#').next().unwrap_or(wanted);
+    if wanted.starts_with('^') {
+        return true;
+    }
+    let wanted = slug(wanted);
+    headings.iter().any(|h| slug(&h.text) == wanted)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+
+    fn vault(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("tem-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_check_vault() {
+        let root = vault("check");
+        fs::create_dir_all(root.join("img")).unwrap();
+        fs::create_dir_all(root.join("old")).unwrap();
+        let write = |note: &str, content: &str| fs::write(root.join(note), content).unwrap();
+        write("img/chart.png", "");
+        write(
+            "index.md",
+            "# Index\n[[Plan#Goals]] [[Plan#Budget]] [[Gone]]\n\
+             ![[chart.png]] ![missing](img/nope.png) [plan](plan.md#goals) [[#Index]]",
+        );
+        write("plan.md", "## Goals\nBack to [[index]]");
+        write("old/plan.md", "Lonely");
+
+        let index = LinkIndex::scan(&root).unwrap();
+        let found = check_vault(&index)
+            .unwrap()
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            found,
+            vec![
+                "index.md:2:16: missing-heading: plan.md has no heading 'Budget'",
+                "index.md:2:32: dead-link: no note for 'Gone'",
+                "index.md:3:16: missing-attachment: no attachment at 'img/nope.png'",
+                "old/plan.md:1:1: orphan: no other note links here",
+                "old/plan.md:1:1: duplicate-title: 'plan' is also the name of plan.md",
+                "plan.md:1:1: duplicate-title: 'plan' is also the name of old/plan.md",
+            ]
+        );
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
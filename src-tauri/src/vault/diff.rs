@@ -0,0 +1,590 @@
+//! Views of a temsync diff between two versions of a note: HTML for history
+//! views and review pages, and stats with a one line summary for revision
+//! lists. The markdown source is shown as it was written, block by block, with
+//! the blocks of both versions lined up so an edited paragraph sits next to
+//! what it was.
+
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    markdown::{self, headings, Heading, Node, NodeKind},
+    render::escape,
+};
+use crate::temsync::{
+    edit::{Edit, EditType},
+    tokenizer::parse_string_tokens,
+    worddiff::fill_same,
+};
+
+/// Stylesheet for rendered diffs
+pub const DIFF_CSS: &str = ".diff { width: 100%; }
+.diff .block { white-space: pre-wrap; margin: 0.25rem 0; }
+.diff .heading { font-weight: 700; }
+.diff .code-block, .diff .table, .diff .frontmatter { font-family: monospace; }
+.diff .deleted { opacity: 0.7; }
+.diff del { background-color: rgba(204, 102, 102, 0.3); }
+.diff ins { background-color: rgba(181, 189, 104, 0.3); text-decoration: none; }
+table.diff { border-collapse: collapse; table-layout: fixed; }
+table.diff td { width: 50%; vertical-align: top; padding: 0 0.5rem; }
+";
+
+/// How the two versions are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiffLayout {
+    /// One column, with deleted text in `<del>` and inserted text in `<ins>`
+    Inline,
+    /// The old version on the left and the new one on the right, a table row per aligned block
+    SideBySide,
+}
+
+/// Size of a diff and where in the note it is
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct DiffStats {
+    /// Words only the new version has
+    pub words_added: usize,
+    /// Words only the old version has
+    pub words_removed: usize,
+    /// Words replaced by others in the same spot
+    pub words_changed: usize,
+    pub chars_added: usize,
+    pub chars_removed: usize,
+    /// Blocks (paragraphs, headings, list items, ...) that were edited, added or removed
+    pub paragraphs: usize,
+    /// Headings of the sections with changes, eg. `## Setup`, in order
+    pub sections: Vec<String>,
+    /// What changed in a few words, eg. "edited 3 paragraphs under ## Setup"
+    pub summary: String,
+}
+
+/// Blocks of the old and new text shown next to each other
+type Row = (Range<usize>, Range<usize>);
+
+/// A token of the diff and the blocks it is in on either side
+struct Piece<'a> {
+    kind: &'a EditType,
+    text: &'a str,
+    old: Option<usize>,
+    new: Option<usize>,
+    row: usize,
+    /// Changed whitespace between blocks, shown with a neighbouring block
+    between: bool,
+}
+
+/// Shown for line breaks that were added or removed between blocks
+const BREAK: &str = "\u{21b5}";
+
+/// Renders the diff that takes prev to curr as HTML. Like render_word_diff,
+/// the edits only need their inserts and deletes.
+pub fn render_diff_html(
+    edits: &[Edit],
+    prev: &str,
+    curr: &str,
+    layout: DiffLayout,
+) -> Result<String, String> {
+    let prev_tokens = parse_string_tokens(prev).map_err(|e| e.to_string())?;
+    let curr_tokens = parse_string_tokens(curr).map_err(|e| e.to_string())?;
+    let edits = fill_same(edits, &prev_tokens, &curr_tokens)?;
+    let old_blocks = blocks(prev, &markdown::parse(prev));
+    let new_blocks = blocks(curr, &markdown::parse(curr));
+    let (pieces, rows) = place(&edits, &old_blocks, &new_blocks);
+
+    let mut out = String::new();
+    match layout {
+        DiffLayout::Inline => out.push_str("<div class=\"diff inline\">\n"),
+        DiffLayout::SideBySide => out.push_str("<table class=\"diff side-by-side\">\n"),
+    }
+    for (i, (old, new)) in rows.iter().enumerate() {
+        let row = pieces
+            .iter()
+            .filter(|p| p.row == i)
+            .collect::<Vec<&Piece>>();
+        match layout {
+            DiffLayout::Inline => {
+                let class = match (new.is_empty(), old.is_empty()) {
+                    (false, false) => block_class(&new_blocks[new.start].1),
+                    (false, true) => format!("{} inserted", block_class(&new_blocks[new.start].1)),
+                    _ => format!("{} deleted", block_class(&old_blocks[old.start].1)),
+                };
+                out.push_str(&format!("<div class=\"block {}\">", class));
+                out.push_str(&spans(row.iter().copied()));
+                out.push_str("</div>\n");
+            }
+            DiffLayout::SideBySide => {
+                out.push_str("<tr>");
+                let old_side = row.iter().copied().filter(|p| *p.kind != EditType::INSERT);
+                out.push_str(&cell("old", old_side, |p| p.old, &old_blocks));
+                let new_side = row.iter().copied().filter(|p| *p.kind != EditType::DELETE);
+                out.push_str(&cell("new", new_side, |p| p.new, &new_blocks));
+                out.push_str("</tr>\n");
+            }
+        }
+    }
+    match layout {
+        DiffLayout::Inline => out.push_str("</div>\n"),
+        DiffLayout::SideBySide => out.push_str("</table>\n"),
+    }
+    Ok(out)
+}
+
+/// Counts what the diff that takes prev to curr changed, and sums it up in a
+/// line. Like render_diff_html, the edits only need their inserts and deletes.
+pub fn diff_stats(edits: &[Edit], prev: &str, curr: &str) -> Result<DiffStats, String> {
+    let prev_tokens = parse_string_tokens(prev).map_err(|e| e.to_string())?;
+    let curr_tokens = parse_string_tokens(curr).map_err(|e| e.to_string())?;
+    let edits = fill_same(edits, &prev_tokens, &curr_tokens)?;
+    let mut stats = DiffStats::default();
+
+    // Deletes and inserts between the same unchanged runs replace each other,
+    // as far as they go word for word
+    for run in edits.split(|e| e.edit_type == EditType::SAME) {
+        let (mut removed, mut added) = (0, 0);
+        for e in run.iter() {
+            let words = e.tokens.iter().filter(|t| is_word(&t.value)).count();
+            let chars = e
+                .tokens
+                .iter()
+                .map(|t| t.value.chars().count())
+                .sum::<usize>();
+            match e.edit_type {
+                EditType::DELETE => {
+                    (removed, stats.chars_removed) = (removed + words, stats.chars_removed + chars)
+                }
+                _ => (added, stats.chars_added) = (added + words, stats.chars_added + chars),
+            }
+        }
+        let changed = removed.min(added);
+        stats.words_changed += changed;
+        stats.words_removed += removed - changed;
+        stats.words_added += added - changed;
+    }
+
+    let old_blocks = blocks(prev, &markdown::parse(prev));
+    let new_blocks = blocks(curr, &markdown::parse(curr));
+    let (pieces, rows) = place(&edits, &old_blocks, &new_blocks);
+    let (old_headings, new_headings) = (headings(prev), headings(curr));
+    let (mut edited, mut added, mut removed) = (0, 0, 0);
+    for (i, (old, new)) in rows.iter().enumerate() {
+        if !pieces
+            .iter()
+            .any(|p| p.row == i && *p.kind != EditType::SAME && !p.between)
+        {
+            continue;
+        }
+        let section = match (old.is_empty(), new.is_empty()) {
+            (false, false) => {
+                edited += 1;
+                section(&new_headings, new_blocks[new.start].0.start)
+            }
+            (true, _) => {
+                added += 1;
+                section(&new_headings, new_blocks[new.start].0.start)
+            }
+            (_, true) => {
+                removed += 1;
+                section(&old_headings, old_blocks[old.start].0.start)
+            }
+        };
+        if let Some(section) = section.filter(|s| !stats.sections.contains(s)) {
+            stats.sections.push(section);
+        }
+    }
+    stats.paragraphs = edited + added + removed;
+    stats.summary = summarize(&stats, edited, added, removed);
+    Ok(stats)
+}
+
+/// Whether a token counts as a word, rather than whitespace or punctuation
+fn is_word(token: &str) -> bool {
+    token.chars().any(char::is_alphanumeric)
+}
+
+/// Heading of the section an offset is in, as written in markdown (`## Setup`)
+fn section(headings: &[Heading], offset: usize) -> Option<String> {
+    headings
+        .iter()
+        .rev()
+        .find(|h| h.range.start <= offset)
+        .map(|h| format!("{} {}", "#".repeat(h.level), h.text))
+}
+
+/// One line about a diff, eg. "edited 2 paragraphs and added 1 paragraph under ## Setup"
+fn summarize(stats: &DiffStats, edited: usize, added: usize, removed: usize) -> String {
+    let paragraphs = |n: usize| match n {
+        1 => "1 paragraph".to_string(),
+        n => format!("{} paragraphs", n),
+    };
+    let mut parts = Vec::<String>::new();
+    for (verb, n) in [("edited", edited), ("added", added), ("removed", removed)] {
+        if n > 0 {
+            parts.push(format!("{} {}", verb, paragraphs(n)));
+        }
+    }
+
+    let mut out = match parts.split_last() {
+        None if stats.chars_added + stats.chars_removed > 0 => return "changed whitespace".into(),
+        None => return "no changes".into(),
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+    };
+    match stats.sections.as_slice() {
+        [] => (),
+        [one] => out.push_str(&format!(" under {}", one)),
+        [a, b] => out.push_str(&format!(" under {} and {}", a, b)),
+        more => out.push_str(&format!(" in {} sections", more.len())),
+    }
+    out
+}
+
+/// Puts the tokens of the diff in the blocks they are in, and lines the
+/// blocks up in rows. Every piece gets the row of its blocks.
+fn place<'a>(
+    edits: &'a [Edit],
+    old_blocks: &[(Range<usize>, NodeKind)],
+    new_blocks: &[(Range<usize>, NodeKind)],
+) -> (Vec<Piece<'a>>, Vec<Row>) {
+    // Find the blocks of every token, tokens are contiguous so offsets add up
+    let mut pieces = Vec::<Piece>::new();
+    let (mut old_at, mut new_at) = (0, 0);
+    for e in edits.iter() {
+        for t in e.tokens.iter() {
+            let old = (e.edit_type != EditType::INSERT).then(|| block_at(old_blocks, old_at));
+            let new = (e.edit_type != EditType::DELETE).then(|| block_at(new_blocks, new_at));
+            pieces.push(Piece {
+                kind: &e.edit_type,
+                text: &t.value,
+                old: old.flatten(),
+                new: new.flatten(),
+                row: 0,
+                between: match e.edit_type {
+                    EditType::DELETE => old.flatten().is_none(),
+                    EditType::INSERT => new.flatten().is_none(),
+                    EditType::SAME => false,
+                },
+            });
+            old_at += if old.is_some() { t.value.len() } else { 0 };
+            new_at += if new.is_some() { t.value.len() } else { 0 };
+        }
+    }
+
+    let rows = align(&pieces, old_blocks, new_blocks);
+    let (mut row_of_old, mut row_of_new) = (vec![0; old_blocks.len()], vec![0; new_blocks.len()]);
+    for (i, (old, new)) in rows.iter().enumerate() {
+        old.clone().for_each(|b| row_of_old[b] = i);
+        new.clone().for_each(|b| row_of_new[b] = i);
+    }
+
+    // Changed whitespace between blocks goes with the block before it, so a
+    // joined or split paragraph shows the break that went or came. Next to a
+    // block that was added or removed as a whole it's part of that change, and
+    // unchanged whitespace between blocks is left out.
+    let (mut old_kept, mut new_kept) =
+        (vec![false; old_blocks.len()], vec![false; new_blocks.len()]);
+    for p in pieces.iter() {
+        if let (EditType::SAME, Some(old), Some(new)) = (p.kind, p.old, p.new) {
+            (old_kept[old], new_kept[new]) = (true, true);
+        }
+    }
+    let (mut old_at, mut new_at) = (0, 0);
+    for p in pieces.iter_mut() {
+        let (at, blocks, kept) = match p.kind {
+            EditType::DELETE => (old_at, old_blocks, &old_kept),
+            _ => (new_at, new_blocks, &new_kept),
+        };
+        if *p.kind != EditType::INSERT {
+            old_at += p.text.len();
+        }
+        if *p.kind != EditType::DELETE {
+            new_at += p.text.len();
+        }
+        if !p.between {
+            continue;
+        }
+
+        let next = blocks.partition_point(|(r, _)| r.start <= at);
+        let around = [
+            next.checked_sub(1),
+            Some(next).filter(|b| *b < blocks.len()),
+        ];
+        let block = match around {
+            _ if around.iter().flatten().any(|b| !kept[*b]) => None,
+            [Some(b), _] | [None, Some(b)] => Some(b),
+            [None, None] => None,
+        };
+        match p.kind {
+            EditType::DELETE => p.old = block,
+            _ => p.new = block,
+        }
+    }
+    pieces.retain(|p| p.old.is_some() || p.new.is_some());
+    for p in pieces.iter_mut() {
+        p.row = match (p.old, p.new) {
+            (Some(b), _) => row_of_old[b],
+            (_, Some(b)) => row_of_new[b],
+            _ => unreachable!("pieces outside any block were removed"),
+        };
+    }
+    (pieces, rows)
+}
+
+/// Blocks of a note that are lined up, the top level ones except that lists
+/// are split into their items. Trailing whitespace isn't part of a block.
+fn blocks(content: &str, nodes: &[Node]) -> Vec<(Range<usize>, NodeKind)> {
+    let mut out = Vec::new();
+    for node in nodes.iter() {
+        match node.kind {
+            NodeKind::List { .. } => out.extend(blocks(content, &node.children)),
+            _ => {
+                let start = node.range.start;
+                let end = start + content[node.range.clone()].trim_end().len();
+                out.push((start..end, node.kind.clone()));
+            }
+        }
+    }
+    out
+}
+
+/// Index of the block a byte offset is in
+fn block_at(blocks: &[(Range<usize>, NodeKind)], offset: usize) -> Option<usize> {
+    let i = blocks.partition_point(|(r, _)| r.end <= offset);
+    blocks.get(i).filter(|(r, _)| r.start <= offset).map(|_| i)
+}
+
+/// Groups the blocks of both sides into rows. Blocks sharing unchanged text
+/// go in one row, so a paragraph that was split or joined stays together, and
+/// a block only one side has gets paired with a block of the same kind that
+/// only the other side has.
+fn align(
+    pieces: &[Piece],
+    old_blocks: &[(Range<usize>, NodeKind)],
+    new_blocks: &[(Range<usize>, NodeKind)],
+) -> Vec<Row> {
+    let (old_count, new_count) = (old_blocks.len(), new_blocks.len());
+    // The lowest and highest block each block shares words with
+    let mut old_links = vec![None::<(usize, usize)>; old_count];
+    let mut new_links = vec![None::<(usize, usize)>; new_count];
+    let link = |links: &mut Vec<Option<(usize, usize)>>, from: usize, to: usize| {
+        links[from] = Some(links[from].map_or((to, to), |(lo, hi)| (lo.min(to), hi.max(to))));
+    };
+    for p in pieces.iter() {
+        if let (EditType::SAME, Some(old), Some(new)) = (p.kind, p.old, p.new) {
+            link(&mut old_links, old, new);
+            link(&mut new_links, new, old);
+        }
+    }
+
+    let mut rows = Vec::new();
+    let (mut o, mut n) = (0, 0);
+    while o < old_count || n < new_count {
+        let old_alone = o < old_count && old_links[o].is_none();
+        let new_alone = n < new_count && new_links[n].is_none();
+        let pair = old_alone
+            && new_alone
+            && block_class(&old_blocks[o].1) == block_class(&new_blocks[n].1);
+        if old_alone || new_alone || o == old_count || n == new_count {
+            let (oe, ne) = if pair {
+                (o + 1, n + 1)
+            } else if old_alone || n == new_count {
+                (o + 1, n)
+            } else {
+                (o, n + 1)
+            };
+            rows.push((o..oe, n..ne));
+            (o, n) = (oe, ne);
+            continue;
+        }
+
+        // Grow the row until no block in it shares words with one outside it
+        let (mut oe, mut ne) = (o + 1, n + 1);
+        loop {
+            let (o_before, n_before) = (oe, ne);
+            for (_, hi) in old_links[o..oe].iter().flatten() {
+                ne = ne.max(hi + 1);
+            }
+            for (_, hi) in new_links[n..ne].iter().flatten() {
+                oe = oe.max(hi + 1);
+            }
+            if (oe, ne) == (o_before, n_before) {
+                break;
+            }
+        }
+        rows.push((o..oe, n..ne));
+        (o, n) = (oe, ne);
+    }
+    rows
+}
+
+/// One side of a side-by-side row, a div per block
+fn cell<'a>(
+    side: &str,
+    pieces: impl Iterator<Item = &'a Piece<'a>>,
+    block: impl Fn(&Piece) -> Option<usize>,
+    blocks: &[(Range<usize>, NodeKind)],
+) -> String {
+    let mut groups = Vec::<(usize, Vec<&Piece>)>::new();
+    for p in pieces {
+        match (block(p), groups.last_mut()) {
+            (Some(b), Some((last, group))) if *last == b => group.push(p),
+            (None, Some((_, group))) => group.push(p),
+            (Some(b), _) => groups.push((b, vec![p])),
+            (None, None) => continue,
+        }
+    }
+    if groups.is_empty() {
+        return format!("<td class=\"{} empty\"></td>", side);
+    }
+
+    let mut out = format!("<td class=\"{}\">", side);
+    for (b, group) in groups.iter() {
+        out.push_str(&format!(
+            "<div class=\"block {}\">{}</div>",
+            block_class(&blocks[*b].1),
+            spans(group.iter().copied())
+        ));
+    }
+    out.push_str("</td>");
+    out
+}
+
+/// Escaped text of the pieces, with changed runs in `<del>` and `<ins>`
+fn spans<'a>(pieces: impl Iterator<Item = &'a Piece<'a>>) -> String {
+    let mut out = String::new();
+    let mut open = &EditType::SAME;
+    for p in pieces {
+        if p.kind != open {
+            out.push_str(tag(open, true));
+            out.push_str(tag(p.kind, false));
+            open = p.kind;
+        }
+        match p.between {
+            true => out.push_str(&escape(&p.text.replace('\n', BREAK))),
+            false => out.push_str(&escape(p.text)),
+        }
+    }
+    out.push_str(tag(open, true));
+    out
+}
+
+fn tag(kind: &EditType, close: bool) -> &'static str {
+    match (kind, close) {
+        (EditType::SAME, _) => "",
+        (EditType::DELETE, false) => "<del>",
+        (EditType::DELETE, true) => "</del>",
+        (EditType::INSERT, false) => "<ins>",
+        (EditType::INSERT, true) => "</ins>",
+    }
+}
+
+/// Classes of a block, eg. `heading h2`
+fn block_class(kind: &NodeKind) -> String {
+    match kind {
+        NodeKind::Heading { level } => format!("heading h{}", level),
+        NodeKind::Frontmatter => "frontmatter".into(),
+        NodeKind::BlockQuote => "block-quote".into(),
+        NodeKind::CodeBlock { .. } => "code-block".into(),
+        NodeKind::HtmlBlock => "html-block".into(),
+        NodeKind::Item { .. } => "item".into(),
+        NodeKind::Table => "table".into(),
+        NodeKind::FootnoteDefinition { .. } => "footnote-definition".into(),
+        NodeKind::Rule => "rule".into(),
+        _ => "paragraph".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::temsync::meyers::get_diff;
+
+    use super::*;
+
+    fn diff(prev: &str, curr: &str, layout: DiffLayout) -> String {
+        let a = parse_string_tokens(prev).unwrap();
+        let b = parse_string_tokens(curr).unwrap();
+        render_diff_html(&get_diff(&a, &b).unwrap(), prev, curr, layout).unwrap()
+    }
+
+    #[test]
+    fn test_diff_stats() {
+        let stats = |prev: &str, curr: &str| {
+            let a = parse_string_tokens(prev).unwrap();
+            let b = parse_string_tokens(curr).unwrap();
+            diff_stats(&get_diff(&a, &b).unwrap(), prev, curr).unwrap()
+        };
+
+        let prev = "# Notes\n\nIntro.\n\n## Setup\n\nInstall the app.\n\nRun it once.\n\n## Usage\n\nOpen a note.\n";
+        let curr = "# Notes\n\nIntro.\n\n## Setup\n\nInstall the new app quickly.\n\nRun it twice.\n\nThen restart.\n\n## Usage\n\nOpen a note.\n";
+        let s = stats(prev, curr);
+        assert_eq!((s.words_added, s.words_removed, s.words_changed), (4, 0, 1));
+        assert_eq!((s.chars_added, s.chars_removed), (32, 4));
+        assert_eq!(s.paragraphs, 3);
+        assert_eq!(s.sections, vec!["## Setup"]);
+        assert_eq!(
+            s.summary,
+            "edited 2 paragraphs and added 1 paragraph under ## Setup"
+        );
+
+        let s = stats(
+            prev,
+            "# Notes\n\nIntro.\n\n## Setup\n\nInstall the app.\n\nRun it once.\n",
+        );
+        assert_eq!(s.summary, "removed 2 paragraphs under ## Usage");
+        assert_eq!(stats(prev, prev).summary, "no changes");
+    }
+
+    #[test]
+    fn test_render_diff_html() {
+        let prev = "## Setup\n\nA car ate my dog.\n\n- one\n- two\n\nOld closing line\n";
+        let curr = "## Setup\n\nNew words here.\n\nA car ate my <cat>.\n\n- one\n- two\n- three\n\nTotally different\n";
+
+        assert_eq!(
+            diff(prev, curr, DiffLayout::Inline),
+            "<div class=\"diff inline\">\n\
+             <div class=\"block heading h2\">## Setup</div>\n\
+             <div class=\"block paragraph inserted\"><ins>New words here.</ins></div>\n\
+             <div class=\"block paragraph\">A car ate my <del>dog</del><ins>&lt;cat&gt;</ins>.</div>\n\
+             <div class=\"block item\">- one</div>\n\
+             <div class=\"block item\">- two</div>\n\
+             <div class=\"block item inserted\"><ins>- three</ins></div>\n\
+             <div class=\"block paragraph\"><del>Old</del><ins>Totally</ins> <del>closing line</del><ins>different</ins></div>\n\
+             </div>\n"
+        );
+
+        // Edited paragraphs line up with what they were, new ones have nothing next to them
+        let side = diff(prev, curr, DiffLayout::SideBySide);
+        let rows = side
+            .lines()
+            .filter(|l| l.starts_with("<tr>"))
+            .collect::<Vec<&str>>();
+        assert_eq!(rows.len(), 7);
+        assert_eq!(
+            rows[1],
+            "<tr><td class=\"old empty\"></td><td class=\"new\">\
+             <div class=\"block paragraph\"><ins>New words here.</ins></div></td></tr>"
+        );
+        assert_eq!(
+            rows[2],
+            "<tr><td class=\"old\"><div class=\"block paragraph\">A car ate my <del>dog</del>.</div></td>\
+             <td class=\"new\"><div class=\"block paragraph\">A car ate my <ins>&lt;cat&gt;</ins>.</div></td></tr>"
+        );
+        assert_eq!(
+            rows[6],
+            "<tr><td class=\"old\"><div class=\"block paragraph\"><del>Old</del> <del>closing line</del></div></td>\
+             <td class=\"new\"><div class=\"block paragraph\"><ins>Totally</ins> <ins>different</ins></div></td></tr>"
+        );
+
+        // Joining and splitting paragraphs shows the line breaks that went or came
+        let (one, two) = ("Para one.\n\nPara two.\n", "Para one. Para two.\n");
+        assert_eq!(
+            diff(one, two, DiffLayout::Inline),
+            "<div class=\"diff inline\">\n\
+             <div class=\"block paragraph\">Para one.<del>\u{21b5}\u{21b5}</del><ins> </ins>Para two.</div>\n\
+             </div>\n"
+        );
+        assert!(diff(two, one, DiffLayout::SideBySide).contains(
+            "<td class=\"new\"><div class=\"block paragraph\">Para one.<ins>\u{21b5}\u{21b5}</ins></div>\
+             <div class=\"block paragraph\">Para two.</div></td>"
+        ));
+    }
+}
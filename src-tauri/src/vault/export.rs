@@ -0,0 +1,358 @@
+//! Static site export, a read-only copy of some notes that can be shared or put
+//! on any web server. Notes become pages under `notes/`, next to copies of the
+//! attachments they use, with an index of the notes and a page of their tags.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fs,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    files,
+    links::{folder, join, note_name, relative_path, LinkIndex},
+    markdown::slug,
+    query,
+    render::{encode_path, escape, highlight_css, render_html, LinkUrls, ANCHOR_PREFIX},
+    tags::TagIndex,
+    NOTE_EXT,
+};
+
+/// Folder of the site the notes are written to
+pub const NOTES_DIR: &str = "notes";
+
+const SITE_CSS: &str = "body {
+    max-width: 48rem;
+    margin: 0 auto;
+    padding: 1rem;
+    font-family: -apple-system, BlinkMacSystemFont, \"Segoe UI\", Helvetica, Arial, sans-serif;
+    line-height: 1.5;
+    color: #c0c5ce;
+    background-color: #1e1e1e;
+}
+a { color: #81a2be; }
+nav a { margin-right: 1rem; }
+pre { padding: 0.5rem; overflow-x: auto; }
+img { max-width: 100%; }
+table { border-collapse: collapse; }
+th, td { padding: 0.25rem 0.5rem; border: 1px solid #444; }
+.note-link.missing { color: inherit; }
+.tags a { margin-right: 0.5rem; }
+.backlinks { margin-top: 2rem; border-top: 1px solid #444; }
+";
+
+/// Which notes to export
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportSelection {
+    /// Notes matching a tag query, eg. `#public and not #draft`
+    Query(String),
+    /// Notes in a folder and the folders below it, "" for the whole vault
+    Folder(String),
+}
+
+/// What an export wrote, as vault paths
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExportSummary {
+    pub notes: Vec<String>,
+    pub attachments: Vec<String>,
+}
+
+/// Notes a selection picks, sorted by path
+pub fn select_notes(
+    tags: &TagIndex,
+    links: &LinkIndex,
+    selection: &ExportSelection,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    match selection {
+        ExportSelection::Query(q) => Ok(query::query_notes(tags, q)?),
+        ExportSelection::Folder(f) => {
+            let f = join("", f).ok_or_else(|| format!("invalid folder: {}", f))?;
+            Ok(links
+                .notes()
+                .filter(|n| f.is_empty() || n.starts_with(&format!("{}/", f)))
+                .map(|n| n.to_string())
+                .collect())
+        }
+    }
+}
+
+/// Writes the selected notes to `out` as a static site. Links to notes that
+/// weren't exported become plain text, so the site never points at anything
+/// missing. The site can't go inside the vault, where it would be picked up
+/// as attachments.
+pub fn export_site(
+    tags: &TagIndex,
+    links: &LinkIndex,
+    selection: &ExportSelection,
+    out: &Path,
+) -> Result<ExportSummary, Box<dyn Error>> {
+    let root = links.root();
+    if inside(root, out)? {
+        return Err(format!("{} is inside the vault", out.display()).into());
+    }
+
+    let notes = select_notes(tags, links, selection)?;
+    let urls = SiteUrls::new(&notes);
+    let pages = out.join(NOTES_DIR);
+    for note in notes.iter() {
+        let content = fs::read_to_string(root.join(note))?;
+        let html = note_page(tags, links, note, &content, &urls, "");
+        write_file(&pages.join(page_path(note)), html.as_bytes())?;
+    }
+
+    // Only files a page links to are copied, and only ones that are really in the vault
+    let mut attachments = Vec::<String>::new();
+    for file in urls.attachments.borrow().iter() {
+        if let Ok(src) = files::resolve_attachment(root, file) {
+            write_file(&pages.join(file), &fs::read(src)?)?;
+            attachments.push(file.clone());
+        }
+    }
+
+    write_file(&out.join("index.html"), index_page(&notes, "").as_bytes())?;
+    write_file(
+        &out.join("tags.html"),
+        tags_page(tags, &notes, "").as_bytes(),
+    )?;
+    write_file(&out.join("style.css"), site_css().as_bytes())?;
+
+    Ok(ExportSummary { notes, attachments })
+}
+
+/// Links between the pages of a site, relative so it works from any folder
+pub(crate) struct SiteUrls {
+    notes: BTreeSet<String>,
+    /// Attachments the pages use, to copy once they're all rendered
+    pub(crate) attachments: RefCell<BTreeSet<String>>,
+}
+
+impl SiteUrls {
+    /// Links for a site of the notes, any others aren't linked
+    pub(crate) fn new(notes: &[String]) -> Self {
+        SiteUrls {
+            notes: notes.iter().cloned().collect(),
+            attachments: RefCell::new(BTreeSet::new()),
+        }
+    }
+}
+
+impl LinkUrls for SiteUrls {
+    fn note(&self, from: &str, note: &str, anchor: Option<&str>) -> Option<String> {
+        if !self.notes.contains(note) {
+            return None;
+        }
+        let url = encode_path(&relative_path(folder(from), &page_path(note)));
+        Some(match anchor {
+            Some(anchor) => format!("{}#{}{}", url, ANCHOR_PREFIX, anchor),
+            None => url,
+        })
+    }
+
+    fn attachment(&self, from: &str, file: &str) -> String {
+        self.attachments.borrow_mut().insert(file.to_string());
+        encode_path(&relative_path(folder(from), file))
+    }
+}
+
+/// Page of a rendered note with its tags and the notes on the site linking to
+/// it. `head` goes at the end of the page's head.
+pub(crate) fn note_page(
+    tags: &TagIndex,
+    links: &LinkIndex,
+    note: &str,
+    content: &str,
+    urls: &SiteUrls,
+    head: &str,
+) -> String {
+    let base = "../".repeat(note.matches('/').count() + 1);
+    let mut body = render_html(links, note, content, urls);
+
+    let note_tags = tags.tags_for_note(note);
+    if !note_tags.is_empty() {
+        body.push_str("<p class=\"tags\">");
+        for tag in note_tags.iter() {
+            body.push_str(&format!(
+                "<a href=\"{}tags.html#tag-{}\">#{}</a>",
+                base,
+                escape(&slug(tag)),
+                escape(tag)
+            ));
+        }
+        body.push_str("</p>\n");
+    }
+
+    let backlinks = links
+        .backlinks(note)
+        .into_iter()
+        .map(|b| b.note)
+        .filter(|n| urls.notes.contains(n))
+        .collect::<BTreeSet<String>>();
+    if !backlinks.is_empty() {
+        body.push_str("<section class=\"backlinks\">\n<h2>Linked from</h2>\n<ul>\n");
+        for from in backlinks.iter() {
+            body.push_str(&format!(
+                "<li><a href=\"{}\">{}</a></li>\n",
+                escape(&encode_path(&relative_path(folder(note), &page_path(from)))),
+                escape(note_name(from))
+            ));
+        }
+        body.push_str("</ul>\n</section>\n");
+    }
+
+    page(note_name(note), &base, head, &body)
+}
+
+/// Front page of a site, listing its notes
+pub(crate) fn index_page(notes: &[String], head: &str) -> String {
+    let mut body = String::from("<h1>Notes</h1>\n<ul>\n");
+    for note in notes.iter() {
+        body.push_str(&format!(
+            "<li><a href=\"{}/{}\">{}</a></li>\n",
+            NOTES_DIR,
+            escape(&encode_path(&page_path(note))),
+            escape(note.strip_suffix(&format!(".{}", NOTE_EXT)).unwrap_or(note))
+        ));
+    }
+    body.push_str("</ul>\n");
+    page("Notes", "", head, &body)
+}
+
+/// Page of every tag the notes use, with the notes under it
+pub(crate) fn tags_page(tags: &TagIndex, notes: &[String], head: &str) -> String {
+    let mut by_tag = BTreeMap::<String, Vec<&str>>::new();
+    for note in notes.iter() {
+        for tag in tags.tags_for_note(note) {
+            by_tag.entry(tag).or_default().push(note);
+        }
+    }
+
+    let mut body = String::from("<h1>Tags</h1>\n");
+    for (tag, notes) in by_tag.iter() {
+        body.push_str(&format!(
+            "<h2 id=\"tag-{}\">#{}</h2>\n<ul>\n",
+            escape(&slug(tag)),
+            escape(tag)
+        ));
+        for note in notes.iter() {
+            body.push_str(&format!(
+                "<li><a href=\"{}/{}\">{}</a></li>\n",
+                NOTES_DIR,
+                escape(&encode_path(&page_path(note))),
+                escape(note_name(note))
+            ));
+        }
+        body.push_str("</ul>\n");
+    }
+    page("Tags", "", head, &body)
+}
+
+/// Stylesheet of a site, code blocks included
+pub(crate) fn site_css() -> String {
+    format!("{}{}", SITE_CSS, highlight_css())
+}
+
+fn page(title: &str, base: &str, head: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n\
+         <html lang=\"en\">\n\
+         <head>\n\
+         <meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{title}</title>\n\
+         <link rel=\"stylesheet\" href=\"{base}style.css\">\n\
+         {head}</head>\n\
+         <body>\n\
+         <nav><a href=\"{base}index.html\">Notes</a><a href=\"{base}tags.html\">Tags</a></nav>\n\
+         <main>\n{body}</main>\n\
+         </body>\n\
+         </html>\n",
+        title = escape(title),
+        base = base,
+        head = head,
+        body = body
+    )
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, data)?;
+    Ok(())
+}
+
+/// Page of a note, relative to the notes folder
+pub(crate) fn page_path(note: &str) -> String {
+    let stem = note.strip_suffix(&format!(".{}", NOTE_EXT)).unwrap_or(note);
+    format!("{}.html", stem)
+}
+
+/// Whether a path is in the vault, following symlinks as far as the path exists
+fn inside(root: &Path, path: &Path) -> Result<bool, Box<dyn Error>> {
+    let root = root.canonicalize()?;
+    let mut path = std::path::absolute(path)?;
+    while !path.exists() {
+        match path.parent() {
+            Some(parent) => path = parent.to_path_buf(),
+            None => return Ok(false),
+        }
+    }
+    Ok(path.canonicalize()?.starts_with(&root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_site() {
+        let dir = std::env::temp_dir().join(format!("tem-export-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let root = dir.join("vault");
+        fs::create_dir_all(root.join("pub/img")).unwrap();
+        fs::create_dir_all(root.join(".tem")).unwrap();
+        let write = |note: &str, content: &str| fs::write(root.join(note), content).unwrap();
+        write("pub/img/a.png", "png");
+        write(".tem/search.json", "{}");
+        write(
+            "pub/home.md",
+            "# Home #shared\nSee [[Guide#Setup]], [[secret]] and ![[a.png]] ![[.tem/search.json]]",
+        );
+        write("pub/guide.md", "## Setup\nBack [home](home.md)");
+        write("secret.md", "[[Home]]");
+
+        let tags = TagIndex::scan(&root).unwrap();
+        let links = LinkIndex::scan(&root).unwrap();
+        let out = dir.join("site");
+        let selection = ExportSelection::Folder("pub".into());
+        assert!(export_site(&tags, &links, &selection, &root.join("site")).is_err());
+
+        let summary = export_site(&tags, &links, &selection, &out).unwrap();
+        assert_eq!(summary.notes, vec!["pub/guide.md", "pub/home.md"]);
+        assert_eq!(summary.attachments, vec!["pub/img/a.png"]);
+        assert!(out.join("notes/pub/img/a.png").is_file());
+        assert!(!out.join("notes/.tem").exists());
+        assert!(out.join("style.css").is_file());
+
+        let home = fs::read_to_string(out.join("notes/pub/home.html")).unwrap();
+        for expected in [
+            "<link rel=\"stylesheet\" href=\"../../style.css\">",
+            "<a href=\"guide.html#tem-setup\" class=\"note-link\"",
+            "<span class=\"note-link\">secret</span>",
+            "<img src=\"img/a.png\"",
+            "<a href=\"../../tags.html#tag-shared\">#shared</a>",
+            "<h2>Linked from</h2>\n<ul>\n<li><a href=\"guide.html\">guide</a></li>",
+        ] {
+            assert!(home.contains(expected), "{} not in {}", expected, home);
+        }
+        let tag_page = fs::read_to_string(out.join("tags.html")).unwrap();
+        assert!(tag_page.contains("<li><a href=\"notes/pub/home.html\">home</a></li>"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,418 @@
+use std::{
+    error::Error,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::UNIX_EPOCH,
+};
+
+use serde::Serialize;
+
+use super::{note_id, walk_notes, NOTE_EXT};
+
+/// Deleted notes are moved here, relative to the vault root
+pub const TRASH_DIR: &str = ".trash";
+
+/// Errors from working with note files
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileError {
+    /// The path is malformed or points outside the vault
+    InvalidPath(String),
+    NotFound(String),
+    AlreadyExists(String),
+    Io(String),
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileError::InvalidPath(p) => write!(f, "invalid note path: {}", p),
+            FileError::NotFound(p) => write!(f, "no note at {}", p),
+            FileError::AlreadyExists(p) => write!(f, "{} already exists", p),
+            FileError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for FileError {}
+
+impl From<io::Error> for FileError {
+    fn from(e: io::Error) -> Self {
+        FileError::Io(e.to_string())
+    }
+}
+
+/// A note in the vault listing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NoteInfo {
+    /// Id of the note, its path relative to the vault root
+    pub path: String,
+    /// File name without the extension
+    pub name: String,
+    /// Last modification, in seconds since the epoch
+    pub modified: u64,
+    pub size: u64,
+}
+
+/// Turns a note id from the front end into a path inside the vault. Absolute paths,
+/// `..`, hidden files and anything that isn't a note are refused, as are symlinks
+/// that lead out of the vault.
+pub fn resolve_note(root: &Path, note: &str) -> Result<PathBuf, FileError> {
+    let path = resolve(root, note)?;
+    if path.extension().and_then(|e| e.to_str()) != Some(NOTE_EXT) {
+        return Err(FileError::InvalidPath(format!(
+            "{} is not a .{} file",
+            note, NOTE_EXT
+        )));
+    }
+    Ok(path)
+}
+
+/// Like resolve_note, but for any file in the vault, eg. an image a note embeds
+pub fn resolve_attachment(root: &Path, file: &str) -> Result<PathBuf, FileError> {
+    let path = resolve(root, file)?;
+    if !path.is_file() {
+        return Err(FileError::NotFound(file.to_string()));
+    }
+    Ok(path)
+}
+
+/// Like resolve_note, but for folders
+pub fn resolve_folder(root: &Path, folder: &str) -> Result<PathBuf, FileError> {
+    if folder.is_empty() {
+        return Ok(root.to_path_buf());
+    }
+    resolve(root, folder)
+}
+
+fn resolve(root: &Path, rel: &str) -> Result<PathBuf, FileError> {
+    let invalid = || FileError::InvalidPath(rel.to_string());
+    if rel.is_empty() || rel.contains('\\') || rel.contains('\0') {
+        return Err(invalid());
+    }
+
+    let mut path = root.to_path_buf();
+    for component in Path::new(rel).components() {
+        match component {
+            Component::Normal(part) if !part.to_string_lossy().starts_with('.') => path.push(part),
+            _ => return Err(invalid()),
+        }
+    }
+
+    // The deepest part that exists has to really be inside the vault
+    let root = root.canonicalize()?;
+    let mut existing = path.as_path();
+    while !existing.exists() {
+        existing = existing.parent().ok_or_else(invalid)?;
+    }
+    if !existing.canonicalize()?.starts_with(&root) {
+        return Err(invalid());
+    }
+
+    Ok(path)
+}
+
+/// Every note in the vault, sorted by path
+pub fn list_notes(root: &Path) -> Result<Vec<NoteInfo>, FileError> {
+    let mut out = Vec::<NoteInfo>::new();
+    for path in walk_notes(root)?.iter() {
+        let id = match note_id(root, path) {
+            Some(id) => id,
+            None => continue,
+        };
+        let meta = fs::metadata(path)?;
+        out.push(NoteInfo {
+            name: path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            path: id,
+            modified: meta
+                .modified()
+                .ok()
+                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            size: meta.len(),
+        });
+    }
+    Ok(out)
+}
+
+pub fn read_note(root: &Path, note: &str) -> Result<String, FileError> {
+    let path = resolve_note(root, note)?;
+    fs::read_to_string(&path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => FileError::NotFound(note.to_string()),
+        _ => e.into(),
+    })
+}
+
+/// Creates a new note, failing if there already is one at the path
+pub fn create_note(root: &Path, note: &str, content: &str) -> Result<(), FileError> {
+    let path = resolve_note(root, note)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => FileError::AlreadyExists(note.to_string()),
+            _ => e.into(),
+        })?;
+    io::Write::write_all(&mut f, content.as_bytes())?;
+    Ok(())
+}
+
+/// Writes a note, creating it if needed
+pub fn write_note(root: &Path, note: &str, content: &str) -> Result<(), FileError> {
+    let path = resolve_note(root, note)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_atomic(&path, content)?;
+    Ok(())
+}
+
+/// Writes to a temp file next to the target and renames it over, so readers
+/// never see a half written file. Every write gets its own new temp file, so
+/// concurrent writers don't share one and a planted symlink isn't followed.
+pub fn write_atomic(path: &Path, content: &str) -> io::Result<()> {
+    static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+    let (tmp, mut file) = loop {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(
+            ".{}-{}.tmp",
+            process::id(),
+            NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        let tmp = PathBuf::from(tmp);
+        match OpenOptions::new().write(true).create_new(true).open(&tmp) {
+            Ok(file) => break (tmp, file),
+            // Left over from an earlier run with the same pid
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    };
+
+    let written = file
+        .write_all(content.as_bytes())
+        .and_then(|_| file.sync_all())
+        .and_then(|_| fs::rename(&tmp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+/// Renames (or moves) a note to a new path in the vault
+pub fn rename_note(root: &Path, from: &str, to: &str) -> Result<(), FileError> {
+    let src = resolve_note(root, from)?;
+    let dst = resolve_note(root, to)?;
+    if !src.is_file() {
+        return Err(FileError::NotFound(from.to_string()));
+    }
+    if dst.exists() {
+        return Err(FileError::AlreadyExists(to.to_string()));
+    }
+
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&src, &dst)?;
+    Ok(())
+}
+
+/// Moves a note into a folder, keeping its file name. Returns the new note id.
+pub fn move_note(root: &Path, note: &str, folder: &str) -> Result<String, FileError> {
+    let name = note.rsplit('/').next().unwrap_or(note);
+    let folder = folder.trim_matches('/');
+    let to = match folder {
+        "" => name.to_string(),
+        f => format!("{}/{}", f, name),
+    };
+    resolve_folder(root, folder)?;
+    rename_note(root, note, &to)?;
+    Ok(to)
+}
+
+/// Moves a note to the trash, returning its path in there (relative to the trash
+/// folder). Notes with the same path get a numbered suffix.
+pub fn delete_note(root: &Path, note: &str) -> Result<String, FileError> {
+    let src = resolve_note(root, note)?;
+    if !src.is_file() {
+        return Err(FileError::NotFound(note.to_string()));
+    }
+
+    let trash = root.join(TRASH_DIR);
+    let stem = note.trim_end_matches(&format!(".{}", NOTE_EXT));
+    let mut trashed = note.to_string();
+    let mut n = 1;
+    while trash.join(&trashed).exists() {
+        trashed = format!("{} ({}).{}", stem, n, NOTE_EXT);
+        n += 1;
+    }
+
+    let dst = trash.join(&trashed);
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&src, &dst)?;
+    Ok(trashed)
+}
+
+/// Notes in the trash, as paths relative to the trash folder
+pub fn list_trash(root: &Path) -> Result<Vec<String>, FileError> {
+    let trash = root.join(TRASH_DIR);
+    if !trash.exists() {
+        return Ok(vec![]);
+    }
+    Ok(walk_notes(&trash)?
+        .iter()
+        .filter_map(|p| note_id(&trash, p))
+        .collect())
+}
+
+/// Moves a note from the trash back into the vault at the given path
+pub fn restore_note(root: &Path, trashed: &str, to: &str) -> Result<(), FileError> {
+    let src = resolve_note(&root.join(TRASH_DIR), trashed)?;
+    let dst = resolve_note(root, to)?;
+    if !src.is_file() {
+        return Err(FileError::NotFound(trashed.to_string()));
+    }
+    if dst.exists() {
+        return Err(FileError::AlreadyExists(to.to_string()));
+    }
+
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&src, &dst)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vault(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("tem-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_resolve_note() {
+        let root = vault("files-resolve");
+        assert!(resolve_note(&root, "journal/today.md").is_ok());
+
+        for bad in [
+            "",
+            "../outside.md",
+            "a/../../outside.md",
+            "/etc/passwd.md",
+            "./a.md",
+            ".trash/a.md",
+            "a\\..\\b.md",
+            "notes.txt",
+        ] {
+            assert!(
+                matches!(resolve_note(&root, bad), Err(FileError::InvalidPath(_))),
+                "{} should be refused",
+                bad
+            );
+        }
+
+        // Symlinks that lead out of the vault are refused too
+        #[cfg(unix)]
+        {
+            let outside = vault("files-outside");
+            std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+            assert!(resolve_note(&root, "link/escape.md").is_err());
+            let _ = fs::remove_dir_all(&outside);
+        }
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_note_lifecycle() {
+        let root = vault("files-lifecycle");
+
+        create_note(&root, "ideas.md", "first").unwrap();
+        assert_eq!(
+            create_note(&root, "ideas.md", "again"),
+            Err(FileError::AlreadyExists("ideas.md".into()))
+        );
+        write_note(&root, "ideas.md", "second").unwrap();
+        assert_eq!(read_note(&root, "ideas.md").unwrap(), "second");
+
+        rename_note(&root, "ideas.md", "later/plans.md").unwrap();
+        assert_eq!(move_note(&root, "later/plans.md", "").unwrap(), "plans.md");
+        let notes = list_notes(&root).unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(
+            (notes[0].path.as_str(), notes[0].name.as_str()),
+            ("plans.md", "plans")
+        );
+        assert_eq!(notes[0].size, 6);
+
+        // Deleting twice keeps both copies in the trash
+        assert_eq!(delete_note(&root, "plans.md").unwrap(), "plans.md");
+        create_note(&root, "plans.md", "new").unwrap();
+        assert_eq!(delete_note(&root, "plans.md").unwrap(), "plans (1).md");
+        assert!(list_notes(&root).unwrap().is_empty());
+        assert_eq!(list_trash(&root).unwrap(), vec!["plans (1).md", "plans.md"]);
+
+        restore_note(&root, "plans.md", "plans.md").unwrap();
+        assert_eq!(read_note(&root, "plans.md").unwrap(), "second");
+        assert_eq!(
+            read_note(&root, "missing.md"),
+            Err(FileError::NotFound("missing.md".into()))
+        );
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let root = vault("files-atomic");
+        let path = root.join("a.md");
+
+        // Concurrent writers each leave a whole file, and no temp files
+        let writers = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || write_atomic(&path, &i.to_string().repeat(1000)))
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content.len(), 1000);
+        assert!(content
+            .chars()
+            .all(|c| c == content.chars().next().unwrap()));
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 1);
+
+        // A symlink where the temp file used to go isn't written through
+        #[cfg(unix)]
+        {
+            let target = root.join("target.txt");
+            fs::write(&target, "untouched").unwrap();
+            std::os::unix::fs::symlink(&target, root.join("a.md.tmp")).unwrap();
+            write_atomic(&path, "new").unwrap();
+            assert_eq!(fs::read_to_string(&target).unwrap(), "untouched");
+            assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        }
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,524 @@
+//! Typed access to the YAML frontmatter of notes, eg.
+//!
+//! ```text
+//! ---
+//! title: Weekly sync
+//! created: 2024-05-01
+//! status: draft # until someone reviews it
+//! tags: [work, meeting]
+//! ---
+//! ```
+//!
+//! Properties are rewritten line by line, so the rest of the frontmatter keeps
+//! its formatting and comments. A vault can describe its properties in
+//! SCHEMA_FILE, notes are checked against it but never rejected for it.
+
+use std::{collections::BTreeMap, error::Error, fmt, fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::{query::Date, tags::split_frontmatter};
+
+/// Optional property schema of a vault, relative to the vault root
+pub const SCHEMA_FILE: &str = ".tem/schema.yaml";
+
+/// Value of a property, typed from its YAML
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "kebab-case")]
+pub enum PropertyValue {
+    Text(String),
+    Number(f64),
+    Checkbox(bool),
+    /// Strings written as `YYYY-MM-DD`
+    Date(Date),
+    List(Vec<String>),
+}
+
+impl PropertyValue {
+    fn from_yaml(value: &serde_yaml::Value) -> PropertyValue {
+        use serde_yaml::Value;
+        match value {
+            Value::Null => PropertyValue::Text(String::new()),
+            Value::Bool(b) => PropertyValue::Checkbox(*b),
+            Value::Number(n) => PropertyValue::Number(n.as_f64().unwrap_or_default()),
+            Value::String(s) => match Date::parse(s) {
+                Some(d) => PropertyValue::Date(d),
+                None => PropertyValue::Text(s.clone()),
+            },
+            Value::Sequence(items) => {
+                PropertyValue::List(items.iter().filter_map(scalar_text).collect())
+            }
+            other => PropertyValue::Text(
+                serde_yaml::to_string(other)
+                    .unwrap_or_default()
+                    .trim_end()
+                    .to_string(),
+            ),
+        }
+    }
+
+    fn kind(&self) -> PropertyType {
+        match self {
+            PropertyValue::Text(_) => PropertyType::Text,
+            PropertyValue::Number(_) => PropertyType::Number,
+            PropertyValue::Checkbox(_) => PropertyType::Checkbox,
+            PropertyValue::Date(_) => PropertyType::Date,
+            PropertyValue::List(_) => PropertyType::List,
+        }
+    }
+}
+
+/// A scalar as text, None for nested lists and maps
+fn scalar_text(value: &serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::String(s) => Some(s.clone()),
+        serde_yaml::Value::Number(n) => Some(n.to_string()),
+        serde_yaml::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Property {
+    pub name: String,
+    pub value: PropertyValue,
+    /// 1-based line of the property in the note
+    pub line: usize,
+}
+
+/// Errors from reading or rewriting frontmatter
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrontmatterError {
+    /// The frontmatter isn't a YAML mapping
+    Invalid(String),
+    InvalidName(String),
+}
+
+impl fmt::Display for FrontmatterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrontmatterError::Invalid(e) => write!(f, "invalid frontmatter: {}", e),
+            FrontmatterError::InvalidName(n) => write!(f, "invalid property name: '{}'", n),
+        }
+    }
+}
+
+impl Error for FrontmatterError {}
+
+/// The properties of a note, in the order they are written
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Frontmatter {
+    properties: Vec<Property>,
+}
+
+impl Frontmatter {
+    /// Reads the frontmatter of a note. Notes without one have no properties.
+    pub fn parse(content: &str) -> Result<Self, FrontmatterError> {
+        let raw = match split_frontmatter(content).0 {
+            Some(r) => r,
+            None => return Ok(Frontmatter::default()),
+        };
+        if raw.trim().is_empty() {
+            return Ok(Frontmatter::default());
+        }
+        let yaml = serde_yaml::from_str::<serde_yaml::Mapping>(raw)
+            .map_err(|e| FrontmatterError::Invalid(e.to_string()))?;
+
+        let properties = yaml
+            .iter()
+            .filter_map(|(k, v)| {
+                let name = scalar_text(k)?;
+                Some(Property {
+                    line: key_line(raw, &name).map_or(1, |l| l + 2),
+                    value: PropertyValue::from_yaml(v),
+                    name,
+                })
+            })
+            .collect();
+        Ok(Frontmatter { properties })
+    }
+
+    pub fn properties(&self) -> &[Property] {
+        &self.properties
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PropertyValue> {
+        self.properties
+            .iter()
+            .find(|p| p.name == name)
+            .map(|p| &p.value)
+    }
+}
+
+/// Sets a property in the note's frontmatter, or removes it when value is None.
+/// Only the lines of that property change, a note without frontmatter gets one.
+pub fn set_property(
+    content: &str,
+    name: &str,
+    value: Option<&PropertyValue>,
+) -> Result<String, FrontmatterError> {
+    if name.trim() != name
+        || name.is_empty()
+        || name.contains([':', '\n', '\r', '#'])
+        || name.starts_with(['-', '[', '{', '"', '\''])
+    {
+        return Err(FrontmatterError::InvalidName(name.to_string()));
+    }
+    // Refuse to edit frontmatter that doesn't parse, it would only get worse
+    Frontmatter::parse(content)?;
+
+    let newline = if content.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let raw = match split_frontmatter(content).0 {
+        Some(raw) => raw,
+        None => {
+            return Ok(match value {
+                Some(v) => format!(
+                    "---{nl}{}---{nl}{}",
+                    render(name, v, None, newline),
+                    content,
+                    nl = newline
+                ),
+                None => content.to_string(),
+            })
+        }
+    };
+    let start = content.find('\n').map_or(0, |i| i + 1);
+
+    let lines = raw.split_inclusive('\n').collect::<Vec<&str>>();
+    let mut out = String::with_capacity(content.len());
+    match key_line(raw, name) {
+        Some(key) => {
+            // The entry goes on over indented lines and block list items
+            let mut end = key + 1;
+            while end < lines.len()
+                && (lines[end].starts_with([' ', '\t']) || lines[end].starts_with('-'))
+                && !lines[end].trim().is_empty()
+            {
+                end += 1;
+            }
+            out.push_str(&lines[..key].concat());
+            if let Some(v) = value {
+                out.push_str(&render(name, v, Some(&lines[key..end]), newline));
+            }
+            out.push_str(&lines[end..].concat());
+        }
+        None => {
+            out.push_str(raw);
+            if let Some(v) = value {
+                if !raw.is_empty() && !raw.ends_with('\n') {
+                    out.push_str(newline);
+                }
+                out.push_str(&render(name, v, None, newline));
+            }
+        }
+    }
+
+    Ok(format!(
+        "{}{}{}",
+        &content[..start],
+        out,
+        &content[start + raw.len()..]
+    ))
+}
+
+/// Index of the line where a top level key is set
+fn key_line(raw: &str, name: &str) -> Option<usize> {
+    raw.split_inclusive('\n').position(|line| {
+        let key = line.split(':').next().unwrap_or("");
+        line.contains(':') && key.trim_matches(|c| c == '"' || c == '\'') == name
+    })
+}
+
+/// Writes a property entry. A list keeps the style of the old entry (block or
+/// flow), a scalar keeps the old entry's trailing comment.
+fn render(name: &str, value: &PropertyValue, old: Option<&[&str]>, newline: &str) -> String {
+    let first = old.and_then(|l| l.first()).copied().unwrap_or("");
+    let old_value = first.split_once(':').map_or("", |(_, v)| v.trim_end());
+
+    let text = match value {
+        PropertyValue::List(items) => {
+            let block_dash = old
+                .filter(|lines| old_value.trim().is_empty() && lines.len() > 1)
+                .and_then(|lines| lines[1].find('-').map(|i| &lines[1][..i]));
+            return match block_dash {
+                Some(indent) => {
+                    let mut out = format!("{}:{}", name, newline);
+                    for item in items.iter() {
+                        out.push_str(&format!("{}- {}{}", indent, scalar(item), newline));
+                    }
+                    out
+                }
+                None => {
+                    let items = items.iter().map(|i| flow_item(i)).collect::<Vec<String>>();
+                    format!("{}: [{}]{}", name, items.join(", "), newline)
+                }
+            };
+        }
+        PropertyValue::Text(s) => scalar(s),
+        PropertyValue::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => {
+            format!("{}", *n as i64)
+        }
+        PropertyValue::Number(n) => n.to_string(),
+        PropertyValue::Checkbox(b) => b.to_string(),
+        PropertyValue::Date(d) => d.to_string(),
+    };
+
+    // Quoted values can have a # in them, it's only a comment after plain ones
+    let comment = match old_value.trim_start().starts_with(['"', '\'']) {
+        true => "",
+        false => old_value.find(" #").map_or("", |i| &old_value[i..]),
+    };
+    format!("{}: {}{}{}", name, text, comment, newline)
+}
+
+/// A string as a YAML scalar, quoted only when it has to be
+fn scalar(text: &str) -> String {
+    serde_yaml::to_string(text)
+        .map(|s| s.trim_end().to_string())
+        .unwrap_or_else(|_| format!("{:?}", text))
+}
+
+/// Like scalar, but also quotes what would break a flow list
+fn flow_item(text: &str) -> String {
+    match text.contains([',', '[', ']', '{', '}']) {
+        true => format!("{:?}", text),
+        false => scalar(text),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PropertyType {
+    Text,
+    Number,
+    Checkbox,
+    Date,
+    List,
+}
+
+/// What a property should look like
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PropertySchema {
+    #[serde(rename = "type")]
+    pub kind: PropertyType,
+    #[serde(default)]
+    pub required: bool,
+    /// Allowed values, for text and list items. Empty allows anything.
+    #[serde(default)]
+    pub values: Vec<String>,
+}
+
+/// Property schema of a vault, eg.
+///
+/// ```text
+/// properties:
+///   created: { type: date, required: true }
+///   status: { type: text, values: [draft, review, done] }
+///   tags: { type: list }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Schema {
+    #[serde(default)]
+    pub properties: BTreeMap<String, PropertySchema>,
+}
+
+/// A property that doesn't fit the schema
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PropertyIssue {
+    pub name: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl Schema {
+    /// Loads the vault's schema, a vault without one has no rules
+    pub fn open(root: &Path) -> Result<Self, Box<dyn Error>> {
+        match fs::read_to_string(root.join(SCHEMA_FILE)) {
+            Ok(raw) => Ok(serde_yaml::from_str(&raw)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Schema::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Checks a single value, returning why it doesn't fit
+    pub fn check(&self, name: &str, value: &PropertyValue) -> Result<(), String> {
+        let schema = match self.properties.get(name) {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+
+        // Numbers and dates are fine as text, and a single value as a list
+        let fits = match (schema.kind, value.kind()) {
+            (want, got) if want == got => true,
+            (PropertyType::Text, PropertyType::Number | PropertyType::Date) => true,
+            (PropertyType::List, PropertyType::Text) => true,
+            _ => false,
+        };
+        if !fits {
+            return Err(format!(
+                "{} should be {}",
+                name,
+                match schema.kind {
+                    PropertyType::Text => "text",
+                    PropertyType::Number => "a number",
+                    PropertyType::Checkbox => "true or false",
+                    PropertyType::Date => "a YYYY-MM-DD date",
+                    PropertyType::List => "a list",
+                }
+            ));
+        }
+
+        let texts = match value {
+            PropertyValue::Text(s) => vec![s.clone()],
+            PropertyValue::List(items) => items.clone(),
+            _ => vec![],
+        };
+        match texts
+            .iter()
+            .find(|t| !schema.values.is_empty() && !schema.values.contains(t))
+        {
+            Some(bad) => Err(format!(
+                "'{}' is not one of {} for {}",
+                bad,
+                schema.values.join(", "),
+                name
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Every way the note's properties don't fit the schema
+    pub fn validate(&self, frontmatter: &Frontmatter) -> Vec<PropertyIssue> {
+        let mut out = Vec::<PropertyIssue>::new();
+        for property in frontmatter.properties().iter() {
+            if let Err(message) = self.check(&property.name, &property.value) {
+                out.push(PropertyIssue {
+                    name: property.name.clone(),
+                    line: property.line,
+                    message,
+                });
+            }
+        }
+        for (name, _) in self
+            .properties
+            .iter()
+            .filter(|(n, s)| s.required && frontmatter.get(n).is_none())
+        {
+            out.push(PropertyIssue {
+                name: name.clone(),
+                line: 1,
+                message: format!("{} is required", name),
+            });
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTE: &str = "---\n\
+        # Meeting notes\n\
+        title: Weekly sync\n\
+        created: 2024-05-01\n\
+        status: draft # until reviewed\n\
+        done: false\n\
+        tags:\n  - work\n  - meeting\n\
+        ---\n\
+        Body";
+
+    #[test]
+    fn test_parse_frontmatter() {
+        let fm = Frontmatter::parse(NOTE).unwrap();
+        let date = Date::parse("2024-05-01").unwrap();
+        assert_eq!(
+            fm.properties()
+                .iter()
+                .map(|p| (p.name.as_str(), p.line))
+                .collect::<Vec<_>>(),
+            vec![
+                ("title", 3),
+                ("created", 4),
+                ("status", 5),
+                ("done", 6),
+                ("tags", 7)
+            ]
+        );
+        assert_eq!(fm.get("created"), Some(&PropertyValue::Date(date)));
+        assert_eq!(fm.get("done"), Some(&PropertyValue::Checkbox(false)));
+        assert_eq!(
+            fm.get("tags"),
+            Some(&PropertyValue::List(vec!["work".into(), "meeting".into()]))
+        );
+        assert_eq!(
+            Frontmatter::parse("No frontmatter").unwrap().get("title"),
+            None
+        );
+        assert!(Frontmatter::parse("---\n: [\n---\n").is_err());
+    }
+
+    #[test]
+    fn test_set_property() {
+        let text = |s: &str| PropertyValue::Text(s.into());
+
+        // Only the property's lines change, comments and style stay
+        let out = set_property(NOTE, "status", Some(&text("done"))).unwrap();
+        assert_eq!(out, NOTE.replace("status: draft", "status: done"));
+        let list = PropertyValue::List(vec!["work".into(), "a, b".into()]);
+        let out = set_property(NOTE, "tags", Some(&list)).unwrap();
+        assert!(out.contains("tags:\n  - work\n  - a, b\n---\nBody"));
+        let out = set_property(NOTE, "title", None).unwrap();
+        assert!(!out.contains("title") && out.contains("# Meeting notes\ncreated"));
+
+        // New properties go at the end, new frontmatter at the top
+        let out = set_property(NOTE, "rating", Some(&PropertyValue::Number(4.0))).unwrap();
+        assert!(out.ends_with("  - meeting\nrating: 4\n---\nBody"));
+        assert_eq!(
+            set_property(
+                "Body",
+                "aliases",
+                Some(&PropertyValue::List(vec!["x, y".into()]))
+            )
+            .unwrap(),
+            "---\naliases: [\"x, y\"]\n---\nBody"
+        );
+        assert_eq!(
+            Frontmatter::parse(&set_property("Body", "title", Some(&text("a: b"))).unwrap())
+                .unwrap()
+                .get("title"),
+            Some(&text("a: b"))
+        );
+        assert!(set_property(NOTE, "bad: name", Some(&text("x"))).is_err());
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = serde_yaml::from_str::<Schema>(
+            "properties:\n  \
+               created: { type: date, required: true }\n  \
+               status: { type: text, values: [draft, done] }\n  \
+               rating: { type: number, required: true }\n  \
+               tags: { type: list }\n",
+        )
+        .unwrap();
+        let fm =
+            Frontmatter::parse("---\ncreated: soon\nstatus: later\ntags: work\n---\n").unwrap();
+        assert_eq!(
+            schema
+                .validate(&fm)
+                .iter()
+                .map(|i| (i.name.as_str(), i.line, i.message.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("created", 2, "created should be a YYYY-MM-DD date"),
+                ("status", 3, "'later' is not one of draft, done for status"),
+                ("rating", 1, "rating is required"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::temsync::{
+    edit::EditType,
+    meyers::get_diff,
+    names::file_component,
+    patch::{apply_patch, generate_patch, read_patch, tokens_to_string},
+    tokenizer::parse_string_tokens,
+};
+
+/// Where note revisions are kept, relative to the vault root. Each note gets a
+/// folder of numbered temsync patches, each made against the revision before.
+pub const HISTORY_DIR: &str = ".tem/history";
+
+/// Where histories go when a rename replaces them, so they aren't lost
+pub const HISTORY_ARCHIVE_DIR: &str = ".tem/history-archive";
+
+/// When a save becomes a revision
+#[derive(Debug, Clone, PartialEq)]
+pub struct RevisionPolicy {
+    /// Capture a revision once this many tokens changed since the last one
+    pub min_changed_tokens: usize,
+    /// Or once this much time passed since the last one, however small the change
+    pub max_interval: Duration,
+}
+
+impl Default for RevisionPolicy {
+    fn default() -> Self {
+        RevisionPolicy {
+            min_changed_tokens: 50,
+            max_interval: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// A captured revision, as listed by History::log
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RevisionInfo {
+    pub revision: usize,
+    /// When it was captured, in seconds since the unix epoch
+    pub at: u64,
+    /// Tokens the revision inserted and deleted
+    pub inserted: usize,
+    pub deleted: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Checkpoint {
+    revision: usize,
+    content: String,
+    at: SystemTime,
+}
+
+/// Revision history of the notes in a vault. Saves are diffed against the latest
+/// revision and only kept when the policy says the change is big or old enough,
+/// so frequent autosaves don't flood the history.
+#[derive(Debug)]
+pub struct History {
+    root: PathBuf,
+    policy: RevisionPolicy,
+    /// Latest revision of every note seen so far, loaded from disk on first use
+    checkpoints: HashMap<String, Checkpoint>,
+}
+
+impl History {
+    pub fn new(root: &Path, policy: RevisionPolicy) -> Self {
+        History {
+            root: root.to_path_buf(),
+            policy,
+            checkpoints: HashMap::new(),
+        }
+    }
+
+    /// Records a save of the note. Returns the new revision if one was captured.
+    pub fn record(
+        &mut self,
+        note: &str,
+        content: &str,
+        now: SystemTime,
+    ) -> Result<Option<usize>, String> {
+        self.save(note, content, now, false)
+    }
+
+    /// Captures a revision of the note whatever the policy says, for notes the app
+    /// rewrites on its own (eg. links to a renamed note) so the rewrite can be undone
+    pub fn capture(
+        &mut self,
+        note: &str,
+        content: &str,
+        now: SystemTime,
+    ) -> Result<Option<usize>, String> {
+        self.save(note, content, now, true)
+    }
+
+    fn save(
+        &mut self,
+        note: &str,
+        content: &str,
+        now: SystemTime,
+        force: bool,
+    ) -> Result<Option<usize>, String> {
+        let checkpoint = self.checkpoint(note)?;
+        let prev = parse_string_tokens(&checkpoint.content).map_err(|e| e.to_string())?;
+        let curr = parse_string_tokens(content).map_err(|e| e.to_string())?;
+        let edits = get_diff(&prev, &curr)?;
+
+        let changed = edits
+            .iter()
+            .filter(|e| e.edit_type != EditType::SAME)
+            .map(|e| e.tokens.len())
+            .sum::<usize>();
+        let elapsed = now.duration_since(checkpoint.at).unwrap_or_default();
+        if changed == 0
+            || (!force
+                && changed < self.policy.min_changed_tokens
+                && elapsed < self.policy.max_interval)
+        {
+            return Ok(None);
+        }
+
+        let revision = checkpoint.revision + 1;
+        let dir = self.note_dir(note);
+        fs::create_dir_all(&dir)
+            .and_then(|_| {
+                fs::write(
+                    dir.join(format!("{:08}.patch", revision)),
+                    generate_patch(&edits),
+                )
+            })
+            .map_err(|e| format!("unable to save revision: {}", e))?;
+
+        self.checkpoints.insert(
+            note.to_string(),
+            Checkpoint {
+                revision,
+                content: content.to_string(),
+                at: now,
+            },
+        );
+        Ok(Some(revision))
+    }
+
+    /// Number of revisions captured for the note
+    pub fn revisions(&mut self, note: &str) -> Result<usize, String> {
+        Ok(self.checkpoint(note)?.revision)
+    }
+
+    /// Content of the note at a revision, 0 being the empty note
+    pub fn content_at(&self, note: &str, revision: usize) -> Result<String, String> {
+        let files = self.patch_files(note)?;
+        if revision > files.len() {
+            return Err(format!("{} has no revision {}", note, revision));
+        }
+
+        let mut content = String::new();
+        for path in files.iter().take(revision) {
+            content = apply_patch_file(&content, path)?;
+        }
+        Ok(content)
+    }
+
+    /// Every revision of the note, oldest first
+    pub fn log(&self, note: &str) -> Result<Vec<RevisionInfo>, String> {
+        let mut out = Vec::<RevisionInfo>::new();
+        for (i, path) in self.patch_files(note)?.iter().enumerate() {
+            let raw = fs::read_to_string(path).map_err(|e| e.to_string())?;
+            let edits = read_patch(&raw)
+                .map_err(|e| format!("corrupt history {}: {}", path.display(), e))?;
+            let count = |kind: EditType| -> usize {
+                edits
+                    .iter()
+                    .filter(|e| e.edit_type == kind)
+                    .map(|e| e.tokens.len())
+                    .sum()
+            };
+            let at = fs::metadata(path)
+                .and_then(|m| m.modified())
+                .unwrap_or(UNIX_EPOCH);
+            out.push(RevisionInfo {
+                revision: i + 1,
+                at: at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+                inserted: count(EditType::INSERT),
+                deleted: count(EditType::DELETE),
+            });
+        }
+        Ok(out)
+    }
+
+    /// Keeps the history with the note when it is renamed. History already kept
+    /// under the new name (eg. of a note deleted earlier) is archived, not lost.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), String> {
+        let (src, dst) = (self.note_dir(from), self.note_dir(to));
+        if dst.exists() {
+            self.archive(&dst)?;
+        }
+        if src.exists() {
+            fs::rename(&src, &dst).map_err(|e| format!("unable to move history: {}", e))?;
+        }
+        self.checkpoints.remove(to);
+        if let Some(checkpoint) = self.checkpoints.remove(from) {
+            self.checkpoints.insert(to.to_string(), checkpoint);
+        }
+        Ok(())
+    }
+
+    /// Moves a note's history folder into HISTORY_ARCHIVE_DIR
+    fn archive(&self, dir: &Path) -> Result<(), String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut name = dir.file_name().unwrap_or_default().to_owned();
+        name.push(format!(".{}-{:09}", now.as_secs(), now.subsec_nanos()));
+        let archive = self.root.join(HISTORY_ARCHIVE_DIR);
+        fs::create_dir_all(&archive)
+            .and_then(|_| fs::rename(dir, archive.join(name)))
+            .map_err(|e| format!("unable to archive history: {}", e))
+    }
+
+    fn note_dir(&self, note: &str) -> PathBuf {
+        self.root.join(HISTORY_DIR).join(file_component(note))
+    }
+
+    /// Patch files of the note, oldest first
+    fn patch_files(&self, note: &str) -> Result<Vec<PathBuf>, String> {
+        let dir = self.note_dir(note);
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut files = fs::read_dir(&dir)
+            .and_then(|entries| {
+                entries
+                    .map(|e| e.map(|e| e.path()))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(|e| format!("unable to read history of {}: {}", note, e))?;
+        files.retain(|p| p.extension().and_then(|e| e.to_str()) == Some("patch"));
+        files.sort();
+        Ok(files)
+    }
+
+    fn checkpoint(&mut self, note: &str) -> Result<Checkpoint, String> {
+        if let Some(c) = self.checkpoints.get(note) {
+            return Ok(c.clone());
+        }
+
+        // Replay the history, the last patch's mtime is when it was captured
+        let files = self.patch_files(note)?;
+        let mut checkpoint = Checkpoint {
+            revision: files.len(),
+            content: String::new(),
+            at: UNIX_EPOCH,
+        };
+        for path in files.iter() {
+            checkpoint.content = apply_patch_file(&checkpoint.content, path)?;
+        }
+        if let Some(last) = files.last() {
+            checkpoint.at = fs::metadata(last)
+                .and_then(|m| m.modified())
+                .unwrap_or(UNIX_EPOCH);
+        }
+
+        self.checkpoints
+            .insert(note.to_string(), checkpoint.clone());
+        Ok(checkpoint)
+    }
+}
+
+fn apply_patch_file(content: &str, path: &Path) -> Result<String, String> {
+    let raw = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let base = parse_string_tokens(content).map_err(|e| e.to_string())?;
+    let edits = read_patch(&raw).map_err(|e| e.to_string())?;
+    let out = apply_patch(&base, &edits)
+        .map_err(|e| format!("corrupt history {}: {}", path.display(), e))?;
+    Ok(tokens_to_string(&out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_revision_policy() {
+        let root = std::env::temp_dir().join(format!("tem-history-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let policy = RevisionPolicy {
+            min_changed_tokens: 6,
+            max_interval: Duration::from_secs(60),
+        };
+        let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut history = History::new(&root, policy.clone());
+
+        // The first save is always captured
+        assert_eq!(
+            history.record("a.md", "A car ate my dog.", at(0)),
+            Ok(Some(1))
+        );
+
+        // Small quick edits are skipped, until they add up or get old enough
+        assert_eq!(history.record("a.md", "A car ate my cat.", at(5)), Ok(None));
+        assert_eq!(
+            history.record("a.md", "A car ate my cat.", at(100)),
+            Ok(Some(2))
+        );
+        assert_eq!(
+            history.record("a.md", "A car ate my cat.", at(500)),
+            Ok(None)
+        );
+        assert_eq!(
+            history.record("a.md", "A red car ate my big fat cat.", at(501)),
+            Ok(Some(3))
+        );
+
+        // History survives a restart and follows renames
+        let mut history = History::new(&root, policy);
+        history.rename("a.md", "b.md").unwrap();
+        assert_eq!(history.revisions("b.md"), Ok(3));
+        assert_eq!(
+            history.content_at("b.md", 2),
+            Ok("A car ate my cat.".into())
+        );
+        assert_eq!(history.content_at("b.md", 0), Ok("".into()));
+        assert!(history.content_at("b.md", 4).is_err());
+        assert_eq!(history.revisions("a.md"), Ok(0));
+
+        // Names too long for a file name still get a history
+        let long = format!("{}.md", "long ".repeat(100));
+        assert_eq!(history.record(&long, "Long.", at(550)), Ok(Some(1)));
+        assert_eq!(history.content_at(&long, 1), Ok("Long.".into()));
+
+        // Emoji are two UTF-16 units, which the tokenizer keeps together
+        assert_eq!(
+            history.record("trip.md", "Trip 🚗 notes", at(560)),
+            Ok(Some(1))
+        );
+        assert_eq!(
+            history.record("trip.md", "Trip 🚗🚕 notes and more notes", at(1000)),
+            Ok(Some(2))
+        );
+        assert_eq!(history.content_at("trip.md", 1), Ok("Trip 🚗 notes".into()));
+
+        // Renaming onto a note that has history archives that history
+        history.record("c.md", "Old c.", at(600)).unwrap();
+        history.rename("b.md", "c.md").unwrap();
+        assert_eq!(history.revisions("c.md"), Ok(3));
+        let archived = fs::read_dir(root.join(HISTORY_ARCHIVE_DIR))
+            .unwrap()
+            .count();
+        assert_eq!(archived, 1);
+        history.rename("c.md", "b.md").unwrap();
+
+        let log = history.log("b.md").unwrap();
+        assert_eq!(log.len(), 3);
+        assert_eq!((log[0].inserted, log[0].deleted), (10, 0));
+        assert_eq!(
+            (log[1].revision, log[1].inserted, log[1].deleted),
+            (2, 1, 1)
+        );
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,841 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fs,
+    io::ErrorKind,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+
+use super::{
+    files::write_atomic,
+    history::History,
+    markdown::{headings, Heading},
+    note_id, read_scanned,
+    tags::prose_lines,
+    walk_notes, NoteIndex, NOTE_EXT,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkKind {
+    /// `[[Note#Heading|alias]]`
+    Wiki,
+    /// `[text](path/to/note.md#heading)`
+    Markdown,
+}
+
+/// A link in a note, as written
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Link {
+    pub kind: LinkKind,
+    /// Embeds (`![[image.png]]`, `![alt](image.png)`) show the target inline
+    pub embed: bool,
+    /// Note name or path, empty for links to a heading in the same note
+    pub target: String,
+    pub heading: Option<String>,
+    /// Wikilink alias or markdown link text
+    pub alias: Option<String>,
+    /// 1-based position of the link in the note, the column counts characters
+    pub line: usize,
+    pub column: usize,
+    /// Byte range of the target as written (still percent encoded in markdown links)
+    #[serde(skip)]
+    pub target_range: Range<usize>,
+}
+
+/// A link pointing at a note, from the note it is in
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Backlink {
+    pub note: String,
+    pub link: Link,
+}
+
+/// A link going out of a note, with the note it leads to if that exists
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OutgoingLink {
+    pub link: Link,
+    pub resolved: Option<String>,
+}
+
+/// Where a note's title shows up as plain text in another note
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Mention {
+    pub note: String,
+    pub line: usize,
+    pub column: usize,
+    /// The line the mention is on
+    pub context: String,
+}
+
+#[derive(Debug, Default)]
+struct NoteLinks {
+    links: Vec<Link>,
+    headings: Vec<Heading>,
+}
+
+/// Links and headings of every note in a vault
+#[derive(Debug, Default)]
+pub struct LinkIndex {
+    root: PathBuf,
+    notes: BTreeMap<String, NoteLinks>,
+    /// Notes by lowercased file name (without the extension), for wikilinks
+    names: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl LinkIndex {
+    /// Creates an empty index for the vault
+    pub fn new(root: &Path) -> Self {
+        LinkIndex {
+            root: root.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    /// Reads every note in the vault and indexes its links
+    pub fn scan(root: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut index = LinkIndex::new(root);
+        for path in walk_notes(root)?.iter() {
+            let note = match note_id(root, path) {
+                Some(n) => n,
+                None => continue,
+            };
+            if let Some(content) = read_scanned(path) {
+                index.update_note(&note, &content);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Re-indexes a single note after it changed
+    pub fn update_note(&mut self, note: &str, content: &str) {
+        self.names
+            .entry(note_name(note).to_lowercase())
+            .or_default()
+            .insert(note.to_string());
+        self.notes.insert(
+            note.to_string(),
+            NoteLinks {
+                links: parse_links(content),
+                headings: headings(content),
+            },
+        );
+    }
+
+    /// Drops a note from the index
+    pub fn remove_note(&mut self, note: &str) {
+        if self.notes.remove(note).is_none() {
+            return;
+        }
+        let name = note_name(note).to_lowercase();
+        if let Some(notes) = self.names.get_mut(&name) {
+            notes.remove(note);
+            if notes.is_empty() {
+                self.names.remove(&name);
+            }
+        }
+    }
+
+    /// Vault this index covers
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Every indexed note, sorted by path
+    pub fn notes(&self) -> impl Iterator<Item = &str> {
+        self.notes.keys().map(|n| n.as_str())
+    }
+
+    pub fn headings(&self, note: &str) -> &[Heading] {
+        self.notes.get(note).map_or(&[], |n| &n.headings)
+    }
+
+    /// Links in the note, in order
+    pub fn outgoing(&self, note: &str) -> Vec<OutgoingLink> {
+        let links = match self.notes.get(note) {
+            Some(n) => &n.links,
+            None => return vec![],
+        };
+        links
+            .iter()
+            .map(|link| OutgoingLink {
+                link: link.clone(),
+                resolved: self.resolve(note, link),
+            })
+            .collect()
+    }
+
+    /// Links from other notes that lead to the note, sorted by note
+    pub fn backlinks(&self, note: &str) -> Vec<Backlink> {
+        let mut out = Vec::<Backlink>::new();
+        for (from, links) in self.notes.iter().filter(|(n, _)| *n != note) {
+            for link in links.links.iter() {
+                if self.resolve(from, link).as_deref() == Some(note) {
+                    out.push(Backlink {
+                        note: from.clone(),
+                        link: link.clone(),
+                    });
+                }
+            }
+        }
+        out
+    }
+
+    /// Places where other notes mention the note's name without linking to it.
+    /// Matching ignores case and only counts whole words.
+    pub fn unlinked_mentions(&self, note: &str) -> Vec<Mention> {
+        let name = note_name(note);
+        if name.trim().is_empty() {
+            return vec![];
+        }
+
+        let mut out = Vec::<Mention>::new();
+        for other in self.notes.keys().filter(|n| *n != note) {
+            let content = match fs::read_to_string(self.root.join(other)) {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+            // Text inside links is already linked, or links somewhere else
+            let linked = parse_link_spans(&content);
+            for (number, line_start, line) in prose_lines(&content, 0) {
+                for at in find_word(line, name) {
+                    let offset = line_start + at;
+                    if linked.iter().any(|r| r.contains(&offset)) || in_code(line, at) {
+                        continue;
+                    }
+                    out.push(Mention {
+                        note: other.clone(),
+                        line: number,
+                        column: line[..at].chars().count() + 1,
+                        context: line.trim().to_string(),
+                    });
+                }
+            }
+        }
+        out
+    }
+
+    /// Note a link leads to, if it exists. Wikilinks are looked up by path from
+    /// the vault root, then next to the linking note, then by name anywhere.
+    /// Markdown links are paths relative to the linking note.
+    pub fn resolve(&self, from: &str, link: &Link) -> Option<String> {
+        if link.target.is_empty() {
+            return Some(from.to_string());
+        }
+
+        match link.kind {
+            LinkKind::Wiki => {
+                let target = link.target.trim_start_matches('/');
+                let target = target
+                    .strip_suffix(&format!(".{}", NOTE_EXT))
+                    .unwrap_or(target);
+                if is_attachment(link) {
+                    return None;
+                }
+                let note = format!("{}.{}", target, NOTE_EXT);
+                for candidate in [note.clone(), join(folder(from), &note)?] {
+                    if self.notes.contains_key(&candidate) {
+                        return Some(candidate);
+                    }
+                }
+
+                // By name, a partial path has to match the end of the note's path
+                let suffix = format!("/{}", note.to_lowercase());
+                self.names
+                    .get(&note_name(&note).to_lowercase())?
+                    .iter()
+                    .filter(|n| format!("/{}", n.to_lowercase()).ends_with(&suffix))
+                    .min_by_key(|n| (n.matches('/').count(), n.to_string()))
+                    .cloned()
+            }
+            LinkKind::Markdown => {
+                let target = percent_decode(&link.target);
+                let path = match target.strip_prefix('/') {
+                    Some(abs) => join("", abs)?,
+                    None => join(folder(from), &target)?,
+                };
+                Some(path).filter(|p| self.notes.contains_key(p))
+            }
+        }
+    }
+
+    /// Rewrites links to a note that was just renamed (or moved) on disk so they
+    /// keep working, including relative links inside the moved note itself.
+    /// Notes are re-read from disk in case the index is behind, and each rewrite
+    /// is captured in the history around it so it can be undone.
+    /// Returns the notes that were changed, besides the moved one.
+    pub fn rename(
+        &mut self,
+        from: &str,
+        to: &str,
+        history: &mut History,
+    ) -> Result<Vec<String>, String> {
+        let mut rewritten = Vec::<(String, String, String)>::new();
+        for note in self.notes.keys() {
+            let (old_id, new_id) = match note.as_str() {
+                n if n == from => (from, to),
+                n => (n, n),
+            };
+            let content = match fs::read_to_string(self.root.join(new_id)) {
+                Ok(content) => content,
+                // Gone since it was indexed, the watcher will drop it
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(format!("unable to read {}: {}", new_id, e)),
+            };
+
+            let mut replacements = Vec::<(Range<usize>, String)>::new();
+            for link in parse_links(&content)
+                .iter()
+                .filter(|l| !l.target.is_empty())
+            {
+                // Wikilinks don't depend on where the linking note is, markdown links do
+                let target = match (self.resolve(old_id, link), link.kind) {
+                    (Some(t), _) if t == from => to.to_string(),
+                    (Some(t), LinkKind::Markdown) if old_id != new_id => t,
+                    _ => continue,
+                };
+                if let Some(text) = self.link_text(link, new_id, &target, from) {
+                    replacements.push((link.target_range.clone(), text));
+                }
+            }
+            if replacements.is_empty() {
+                continue;
+            }
+
+            let mut updated = content.clone();
+            for (range, text) in replacements.into_iter().rev() {
+                updated.replace_range(range, &text);
+            }
+            rewritten.push((new_id.to_string(), content, updated));
+        }
+
+        let now = SystemTime::now();
+        for (note, content, updated) in rewritten.iter() {
+            history.capture(note, content, now)?;
+            write_atomic(&self.root.join(note), updated)
+                .map_err(|e| format!("unable to update links in {}: {}", note, e))?;
+            history.capture(note, updated, now)?;
+        }
+
+        self.remove_note(from);
+        let moved = fs::read_to_string(self.root.join(to))
+            .map_err(|e| format!("unable to read {}: {}", to, e))?;
+        self.update_note(to, &moved);
+        for (note, _, updated) in rewritten.iter() {
+            self.update_note(note, updated);
+        }
+
+        Ok(rewritten
+            .into_iter()
+            .map(|(note, _, _)| note)
+            .filter(|n| n != to)
+            .collect())
+    }
+
+    /// How a link from the note should write the target, None if it can stay as is.
+    /// renamed is the old path of the target, which is still in the index.
+    fn link_text(&self, link: &Link, note: &str, target: &str, renamed: &str) -> Option<String> {
+        let stem = target
+            .strip_suffix(&format!(".{}", NOTE_EXT))
+            .unwrap_or(target);
+        let text = match link.kind {
+            // Name-only wikilinks stay that way while the name is unique
+            LinkKind::Wiki if !link.target.contains('/') => {
+                let name = note_name(target);
+                let unique = self
+                    .names
+                    .get(&name.to_lowercase())
+                    .is_none_or(|notes| notes.iter().all(|n| n == renamed || n == target));
+                match unique {
+                    true => name.to_string(),
+                    false => stem.to_string(),
+                }
+            }
+            LinkKind::Wiki => stem.to_string(),
+            LinkKind::Markdown => relative_path(folder(note), target).replace(' ', "%20"),
+        };
+
+        let text = match link.kind {
+            LinkKind::Wiki if link.target.ends_with(&format!(".{}", NOTE_EXT)) => {
+                format!("{}.{}", text, NOTE_EXT)
+            }
+            _ => text,
+        };
+        Some(text).filter(|t| *t != link.target)
+    }
+}
+
+impl NoteIndex for LinkIndex {
+    fn update_note(&mut self, note: &str, content: &str) {
+        LinkIndex::update_note(self, note, content)
+    }
+
+    fn remove_note(&mut self, note: &str) {
+        LinkIndex::remove_note(self, note)
+    }
+}
+
+/// Finds the wikilinks and markdown links in a note, skipping code
+pub fn parse_links(content: &str) -> Vec<Link> {
+    let mut out = Vec::<Link>::new();
+    for (number, line_start, line) in prose_lines(content, 0) {
+        for (span, mut link) in line_links(line) {
+            link.line = number;
+            link.column = line[..span.start].chars().count() + 1;
+            link.target_range =
+                link.target_range.start + line_start..link.target_range.end + line_start;
+            out.push(link);
+        }
+    }
+    out
+}
+
+/// Byte ranges of every link in a note
+fn parse_link_spans(content: &str) -> Vec<Range<usize>> {
+    let mut out = Vec::<Range<usize>>::new();
+    for (_, line_start, line) in prose_lines(content, 0) {
+        out.extend(
+            line_links(line)
+                .into_iter()
+                .map(|(span, _)| span.start + line_start..span.end + line_start),
+        );
+    }
+    out
+}
+
+/// Links in a single line, with the byte range of the whole link
+fn line_links(line: &str) -> Vec<(Range<usize>, Link)> {
+    let mut out = Vec::<(Range<usize>, Link)>::new();
+    let bytes = line.as_bytes();
+    let mut in_code = false;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'`' => in_code = !in_code,
+            b'[' if !in_code => {
+                let embed = i > 0 && bytes[i - 1] == b'!';
+                let start = if embed { i - 1 } else { i };
+                let found = match bytes.get(i + 1) {
+                    Some(b'[') => wikilink(line, i),
+                    _ => markdown_link(line, i),
+                };
+                if let Some((end, mut link)) = found {
+                    link.embed = embed;
+                    out.push((start..end, link));
+                    i = end;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    out
+}
+
+/// Parses `[[target#heading|alias]]` starting at the first bracket
+fn wikilink(line: &str, start: usize) -> Option<(usize, Link)> {
+    let inner_start = start + 2;
+    let len = line[inner_start..].find("]]")?;
+    let inner = &line[inner_start..inner_start + len];
+    if inner.contains('[') {
+        return None;
+    }
+
+    let (dest, alias) = match inner.split_once('|') {
+        Some((d, a)) => (d, Some(a.trim().to_string())),
+        None => (inner, None),
+    };
+    let (target, heading) = match dest.split_once('#') {
+        Some((t, h)) => (t, Some(h.trim().to_string())),
+        None => (dest, None),
+    };
+    let trimmed = target.trim();
+    let target_start = inner_start + (target.len() - target.trim_start().len());
+
+    Some((
+        inner_start + len + 2,
+        Link {
+            kind: LinkKind::Wiki,
+            embed: false,
+            target: trimmed.to_string(),
+            heading,
+            alias,
+            line: 0,
+            column: 0,
+            target_range: target_start..target_start + trimmed.len(),
+        },
+    ))
+}
+
+/// Parses `[text](target#heading "title")` starting at the bracket. Links with a
+/// scheme (`https://`, `mailto:`) aren't links between notes and are skipped.
+fn markdown_link(line: &str, start: usize) -> Option<(usize, Link)> {
+    let text_len = line[start + 1..].find(']')?;
+    let text = &line[start + 1..start + 1 + text_len];
+    let open = start + 1 + text_len + 1;
+    if line.as_bytes().get(open) != Some(&b'(') {
+        return None;
+    }
+    let dest_len = line[open + 1..].find(')')?;
+    let end = open + 1 + dest_len + 1;
+
+    let raw = &line[open + 1..open + 1 + dest_len];
+    let leading = raw.len() - raw.trim_start().len();
+    let raw = raw.trim();
+    let (dest, dest_start) = match raw.strip_prefix('<') {
+        Some(r) => (r.split('>').next().unwrap_or(r), open + 1 + leading + 1),
+        // A title may follow the destination
+        None => (
+            raw.split_whitespace().next().unwrap_or(""),
+            open + 1 + leading,
+        ),
+    };
+    if dest.is_empty() || dest.contains("://") || dest.starts_with("mailto:") {
+        return None;
+    }
+
+    let (target, heading) = match dest.split_once('#') {
+        Some((t, h)) => (t, Some(percent_decode(h))),
+        None => (dest, None),
+    };
+    Some((
+        end,
+        Link {
+            kind: LinkKind::Markdown,
+            embed: false,
+            target: target.to_string(),
+            heading,
+            alias: Some(text.to_string()).filter(|t| !t.is_empty()),
+            line: 0,
+            column: 0,
+            target_range: dest_start..dest_start + target.len(),
+        },
+    ))
+}
+
+/// File name of a note without the extension
+pub fn note_name(note: &str) -> &str {
+    let name = note.rsplit('/').next().unwrap_or(note);
+    name.strip_suffix(&format!(".{}", NOTE_EXT)).unwrap_or(name)
+}
+
+/// Folder of a note, "" at the vault root
+pub(crate) fn folder(note: &str) -> &str {
+    note.rfind('/').map_or("", |i| &note[..i])
+}
+
+/// Whether the link leads to a file that isn't a note, eg. an image
+pub fn is_attachment(link: &Link) -> bool {
+    let target = match link.kind {
+        LinkKind::Wiki => link.target.clone(),
+        LinkKind::Markdown => percent_decode(&link.target),
+    };
+    let name = target.rsplit('/').next().unwrap_or(&target);
+    name.rsplit_once('.').is_some_and(|(stem, ext)| {
+        !stem.is_empty() && ext != NOTE_EXT && ext.chars().all(|c| c.is_ascii_alphanumeric())
+    })
+}
+
+/// Path of the attachment a link points at, out of every file in the vault.
+/// Attachments are found like notes: wikilinks by path or by name anywhere,
+/// markdown links relative to the note.
+pub(crate) fn find_attachment(files: &BTreeSet<String>, note: &str, link: &Link) -> Option<String> {
+    match link.kind {
+        LinkKind::Wiki => {
+            let target = link.target.trim_start_matches('/');
+            if files.contains(target) {
+                return Some(target.to_string());
+            }
+            if let Some(path) = join(folder(note), target).filter(|p| files.contains(p)) {
+                return Some(path);
+            }
+            let suffix = format!("/{}", target.to_lowercase());
+            files
+                .iter()
+                .filter(|f| format!("/{}", f.to_lowercase()).ends_with(&suffix))
+                .min_by_key(|f| (f.matches('/').count(), f.to_string()))
+                .cloned()
+        }
+        LinkKind::Markdown => {
+            let target = percent_decode(&link.target);
+            let path = match target.strip_prefix('/') {
+                Some(abs) => join("", abs),
+                None => join(folder(note), &target),
+            };
+            path.filter(|p| files.contains(p))
+        }
+    }
+}
+
+/// Joins a relative path onto a folder, None if it climbs out of the vault
+pub(crate) fn join(folder: &str, rel: &str) -> Option<String> {
+    let mut parts = folder
+        .split('/')
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<&str>>();
+    for part in rel.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            p => parts.push(p),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// Path to a note relative to a folder
+pub(crate) fn relative_path(folder: &str, note: &str) -> String {
+    let from = folder
+        .split('/')
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<&str>>();
+    let to = note.split('/').collect::<Vec<&str>>();
+    let common = from
+        .iter()
+        .zip(to.iter())
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(to.len() - 1);
+
+    let mut parts = vec![".."; from.len() - common];
+    parts.extend(&to[common..]);
+    parts.join("/")
+}
+
+pub(crate) fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::<u8>::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = s
+            .get(i + 1..i + 3)
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// Byte offsets where the word shows up in the line, ignoring case
+fn find_word(line: &str, word: &str) -> Vec<usize> {
+    let mut out = Vec::<usize>::new();
+    for (i, _) in line.char_indices() {
+        if line[..i]
+            .chars()
+            .next_back()
+            .is_some_and(char::is_alphanumeric)
+        {
+            continue;
+        }
+        let mut rest = line[i..].chars();
+        let matched = word.chars().all(|w| {
+            rest.next()
+                .is_some_and(|c| c.to_lowercase().eq(w.to_lowercase()))
+        });
+        if matched && !rest.next().is_some_and(char::is_alphanumeric) {
+            out.push(i);
+        }
+    }
+    out
+}
+
+/// Whether a byte offset in the line is inside inline code
+fn in_code(line: &str, at: usize) -> bool {
+    line[..at].matches('`').count() % 2 == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vault::history::RevisionPolicy;
+
+    use super::*;
+
+    fn vault(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("tem-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_parse_links() {
+        let content = "See [[Note Name]] and [[folder/Other#Part two|the other]].\n\
+            ```\n[[not a link]]\n```\n\
+            ![diagram](img/My%20Chart.png) [docs](../docs/intro.md#setup \"Intro\") \
+            [site](https://example.com) `[[code]]` [[#Local]]";
+        let links = parse_links(content);
+        let summary = links
+            .iter()
+            .map(|l| {
+                (
+                    l.kind,
+                    l.embed,
+                    l.target.as_str(),
+                    l.heading.as_deref(),
+                    l.alias.as_deref(),
+                    l.line,
+                    l.column,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (LinkKind::Wiki, false, "Note Name", None, None, 1, 5),
+                (
+                    LinkKind::Wiki,
+                    false,
+                    "folder/Other",
+                    Some("Part two"),
+                    Some("the other"),
+                    1,
+                    23
+                ),
+                (
+                    LinkKind::Markdown,
+                    true,
+                    "img/My%20Chart.png",
+                    None,
+                    Some("diagram"),
+                    5,
+                    1
+                ),
+                (
+                    LinkKind::Markdown,
+                    false,
+                    "../docs/intro.md",
+                    Some("setup"),
+                    Some("docs"),
+                    5,
+                    32
+                ),
+                (LinkKind::Wiki, false, "", Some("Local"), None, 5, 110),
+            ]
+        );
+        assert_eq!(&content[links[1].target_range.clone()], "folder/Other");
+        assert_eq!(&content[links[3].target_range.clone()], "../docs/intro.md");
+    }
+
+    #[test]
+    fn test_links_and_rename() {
+        let root = vault("links");
+        fs::create_dir_all(root.join("projects")).unwrap();
+        let write = |note: &str, content: &str| fs::write(root.join(note), content).unwrap();
+        write(
+            "ideas.md",
+            "Start from [[Plan]] and [the plan](projects/plan.md#goals).",
+        );
+        write(
+            "projects/plan.md",
+            "# Goals\nBack to [ideas](../ideas.md). A plan is a plan.",
+        );
+        write(
+            "projects/notes.md",
+            "Plan for [[projects/plan|it]]. No plan yet.",
+        );
+        let mut index = LinkIndex::scan(&root).unwrap();
+
+        let backlinks = index.backlinks("projects/plan.md");
+        assert_eq!(
+            backlinks
+                .iter()
+                .map(|b| (b.note.as_str(), b.link.line, b.link.column))
+                .collect::<Vec<_>>(),
+            vec![
+                ("ideas.md", 1, 12),
+                ("ideas.md", 1, 25),
+                ("projects/notes.md", 1, 10)
+            ]
+        );
+        let outgoing = index.outgoing("projects/plan.md");
+        assert_eq!(outgoing[0].resolved.as_deref(), Some("ideas.md"));
+
+        // Mentions outside links, by whole word and in any case
+        let mentions = index.unlinked_mentions("projects/plan.md");
+        assert_eq!(
+            mentions
+                .iter()
+                .map(|m| (m.note.as_str(), m.column))
+                .collect::<Vec<_>>(),
+            vec![("projects/notes.md", 1), ("projects/notes.md", 35)]
+        );
+
+        // Moving the note rewrites the links to it, and the relative links in it.
+        // Notes are read again, so an edit the index hasn't seen yet is kept.
+        write(
+            "ideas.md",
+            "Not indexed yet. Start from [[Plan]] and [the plan](projects/plan.md#goals).",
+        );
+        let mut history = History::new(&root, RevisionPolicy::default());
+        fs::create_dir_all(root.join("archive")).unwrap();
+        fs::rename(
+            root.join("projects/plan.md"),
+            root.join("archive/Old Plan.md"),
+        )
+        .unwrap();
+        let changed = index
+            .rename("projects/plan.md", "archive/Old Plan.md", &mut history)
+            .unwrap();
+        assert_eq!(changed, vec!["ideas.md", "projects/notes.md"]);
+        let read = |note: &str| fs::read_to_string(root.join(note)).unwrap();
+        assert_eq!(
+            read("ideas.md"),
+            "Not indexed yet. Start from [[Old Plan]] and [the plan](archive/Old%20Plan.md#goals)."
+        );
+        assert_eq!(history.log("ideas.md").unwrap().len(), 2);
+        assert_eq!(
+            history.content_at("ideas.md", 1).unwrap(),
+            "Not indexed yet. Start from [[Plan]] and [the plan](projects/plan.md#goals)."
+        );
+        assert_eq!(
+            read("projects/notes.md"),
+            "Plan for [[archive/Old Plan|it]]. No plan yet."
+        );
+        assert_eq!(
+            read("archive/Old Plan.md"),
+            "# Goals\nBack to [ideas](../ideas.md). A plan is a plan."
+        );
+        assert_eq!(index.backlinks("archive/Old Plan.md").len(), 3);
+        assert!(index.backlinks("projects/plan.md").is_empty());
+
+        // Out of the folder, its relative links point one level up less
+        fs::rename(root.join("archive/Old Plan.md"), root.join("Old Plan.md")).unwrap();
+        history
+            .rename("archive/Old Plan.md", "Old Plan.md")
+            .unwrap();
+        let changed = index
+            .rename("archive/Old Plan.md", "Old Plan.md", &mut history)
+            .unwrap();
+        assert_eq!(changed, vec!["ideas.md", "projects/notes.md"]);
+        assert_eq!(
+            read("Old Plan.md"),
+            "# Goals\nBack to [ideas](ideas.md). A plan is a plan."
+        );
+        assert_eq!(
+            read("ideas.md"),
+            "Not indexed yet. Start from [[Old Plan]] and [the plan](Old%20Plan.md#goals)."
+        );
+        assert_eq!(
+            read("projects/notes.md"),
+            "Plan for [[Old Plan|it]]. No plan yet."
+        );
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,375 @@
+//! Markdown parsing, CommonMark with the GFM extensions notes use (tables, task
+//! lists, strikethrough and footnotes) and wikilinks. The YAML frontmatter is its
+//! own node, so its closing `---` never turns the line above into a heading.
+
+use std::ops::Range;
+
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag};
+use serde::Serialize;
+
+/// What a node in the tree is
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum NodeKind {
+    Frontmatter,
+    Paragraph,
+    Heading {
+        level: usize,
+    },
+    BlockQuote,
+    CodeBlock {
+        /// Language of a fenced block (```` ```rust ````), if given
+        lang: Option<String>,
+    },
+    HtmlBlock,
+    List {
+        /// First number of an ordered list, None for bullets
+        start: Option<u64>,
+    },
+    Item {
+        /// Checked state of a task list item (`- [x]`), None for plain items
+        checked: Option<bool>,
+    },
+    Table,
+    TableHead,
+    TableRow,
+    TableCell,
+    FootnoteDefinition {
+        label: String,
+    },
+    Rule,
+    Emphasis,
+    Strong,
+    Strikethrough,
+    Link {
+        dest: String,
+        title: String,
+    },
+    Image {
+        dest: String,
+        title: String,
+    },
+    Text {
+        text: String,
+    },
+    Code {
+        text: String,
+    },
+    Html {
+        html: String,
+    },
+    FootnoteReference {
+        label: String,
+    },
+    SoftBreak,
+    HardBreak,
+    /// Syntax from extensions that aren't enabled
+    Other,
+}
+
+/// A node of the syntax tree, with the byte range of its source
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Node {
+    #[serde(flatten)]
+    pub kind: NodeKind,
+    pub range: Range<usize>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    /// Text of the node and everything under it, without markup
+    pub fn text(&self) -> String {
+        match &self.kind {
+            NodeKind::Text { text } | NodeKind::Code { text } => text.clone(),
+            NodeKind::SoftBreak | NodeKind::HardBreak => " ".into(),
+            _ => self.children.iter().map(|c| c.text()).collect(),
+        }
+    }
+}
+
+/// Extensions on top of CommonMark
+pub(crate) fn options() -> Options {
+    Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_YAML_STYLE_METADATA_BLOCKS
+        | Options::ENABLE_WIKILINKS
+}
+
+/// Parses a note into its top level blocks
+pub fn parse(content: &str) -> Vec<Node> {
+    let mut root = Vec::<Node>::new();
+    let mut open = Vec::<Node>::new();
+
+    for (event, range) in Parser::new_ext(content, options()).into_offset_iter() {
+        let kind = match event {
+            Event::Start(tag) => {
+                open.push(Node {
+                    kind: tag_kind(tag),
+                    range,
+                    children: vec![],
+                });
+                continue;
+            }
+            Event::End(_) => {
+                if let Some(node) = open.pop() {
+                    open.last_mut()
+                        .map_or(&mut root, |p| &mut p.children)
+                        .push(node);
+                }
+                continue;
+            }
+            Event::TaskListMarker(checked) => {
+                if let Some(Node {
+                    kind: NodeKind::Item { checked: c },
+                    ..
+                }) = open.last_mut()
+                {
+                    *c = Some(checked);
+                }
+                continue;
+            }
+            Event::Text(text) => NodeKind::Text { text: text.into() },
+            Event::Code(text) => NodeKind::Code { text: text.into() },
+            Event::Html(html) | Event::InlineHtml(html) => NodeKind::Html { html: html.into() },
+            Event::FootnoteReference(label) => NodeKind::FootnoteReference {
+                label: label.into(),
+            },
+            Event::SoftBreak => NodeKind::SoftBreak,
+            Event::HardBreak => NodeKind::HardBreak,
+            Event::Rule => NodeKind::Rule,
+            _ => NodeKind::Other,
+        };
+        let node = Node {
+            kind,
+            range,
+            children: vec![],
+        };
+        open.last_mut()
+            .map_or(&mut root, |p| &mut p.children)
+            .push(node);
+    }
+
+    root
+}
+
+fn tag_kind(tag: Tag) -> NodeKind {
+    match tag {
+        Tag::MetadataBlock(_) => NodeKind::Frontmatter,
+        Tag::Paragraph => NodeKind::Paragraph,
+        Tag::Heading { level, .. } => NodeKind::Heading {
+            level: level as usize,
+        },
+        Tag::BlockQuote(_) => NodeKind::BlockQuote,
+        Tag::CodeBlock(CodeBlockKind::Fenced(lang)) => NodeKind::CodeBlock {
+            lang: lang.split_whitespace().next().map(|l| l.to_string()),
+        },
+        Tag::CodeBlock(CodeBlockKind::Indented) => NodeKind::CodeBlock { lang: None },
+        Tag::HtmlBlock => NodeKind::HtmlBlock,
+        Tag::List(start) => NodeKind::List { start },
+        Tag::Item => NodeKind::Item { checked: None },
+        Tag::Table(_) => NodeKind::Table,
+        Tag::TableHead => NodeKind::TableHead,
+        Tag::TableRow => NodeKind::TableRow,
+        Tag::TableCell => NodeKind::TableCell,
+        Tag::FootnoteDefinition(label) => NodeKind::FootnoteDefinition {
+            label: label.into(),
+        },
+        Tag::Emphasis => NodeKind::Emphasis,
+        Tag::Strong => NodeKind::Strong,
+        Tag::Strikethrough => NodeKind::Strikethrough,
+        Tag::Link {
+            dest_url, title, ..
+        } => NodeKind::Link {
+            dest: dest_url.into(),
+            title: title.into(),
+        },
+        Tag::Image {
+            dest_url, title, ..
+        } => NodeKind::Image {
+            dest: dest_url.into(),
+            title: title.into(),
+        },
+        _ => NodeKind::Other,
+    }
+}
+
+/// A heading of a note
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Heading {
+    pub level: usize,
+    pub text: String,
+    /// 1-based line the heading starts on
+    pub line: usize,
+    /// Byte range of the heading's source
+    pub range: Range<usize>,
+}
+
+/// Every heading of a note, in order. Headings can be nested in block quotes
+/// and lists, so the whole tree is searched.
+pub fn headings(content: &str) -> Vec<Heading> {
+    fn walk(nodes: &[Node], content: &str, out: &mut Vec<Heading>) {
+        for node in nodes.iter() {
+            match node.kind {
+                NodeKind::Heading { level } => out.push(Heading {
+                    level,
+                    text: node.text().trim().to_string(),
+                    line: content[..node.range.start].matches('\n').count() + 1,
+                    range: node.range.clone(),
+                }),
+                _ => walk(&node.children, content, out),
+            }
+        }
+    }
+
+    let mut out = Vec::<Heading>::new();
+    walk(&parse(content), content, &mut out);
+    out
+}
+
+/// Anchor for a heading, lowercase with dashes between words (`Part two` is `part-two`)
+pub(crate) fn slug(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.trim().chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() || c == '_' {
+            out.push(c);
+        } else if (c.is_whitespace() || c == '-') && !out.ends_with('-') {
+            out.push('-');
+        }
+    }
+    out
+}
+
+/// A heading in the outline, with the headings under it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OutlineItem {
+    #[serde(flatten)]
+    pub heading: Heading,
+    /// From the heading to the next heading of the same or a higher level
+    pub section: Range<usize>,
+    pub children: Vec<OutlineItem>,
+}
+
+/// The headings of a note as a tree, for a table of contents. A heading that
+/// skips levels (an h3 right under an h1) is still nested under the one before.
+pub fn outline(content: &str) -> Vec<OutlineItem> {
+    let headings = headings(content);
+    let items = headings
+        .iter()
+        .enumerate()
+        .map(|(i, h)| OutlineItem {
+            section: h.range.start
+                ..headings[i + 1..]
+                    .iter()
+                    .find(|next| next.level <= h.level)
+                    .map_or(content.len(), |next| next.range.start),
+            heading: h.clone(),
+            children: vec![],
+        })
+        .collect::<Vec<OutlineItem>>();
+
+    // Open headings, deepest last
+    let mut root = Vec::<OutlineItem>::new();
+    let mut open = Vec::<OutlineItem>::new();
+    let close = |open: &mut Vec<OutlineItem>, root: &mut Vec<OutlineItem>| {
+        if let Some(item) = open.pop() {
+            open.last_mut().map_or(root, |p| &mut p.children).push(item);
+        }
+    };
+    for item in items.into_iter() {
+        while open
+            .last()
+            .is_some_and(|o| o.heading.level >= item.heading.level)
+        {
+            close(&mut open, &mut root);
+        }
+        open.push(item);
+    }
+    while !open.is_empty() {
+        close(&mut open, &mut root);
+    }
+    root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let content = "---\ntitle: x\n---\n\
+            Some *text* and `code`[^1].\n\n\
+            - [x] done\n- [ ] todo\n\n\
+            | a | b |\n|---|---|\n| 1 | 2 |\n\n\
+            ```rust\nfn main() {}\n```\n\n\
+            [^1]: A ~~note~~.\n";
+        let nodes = parse(content);
+        let kinds = nodes.iter().map(|n| &n.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                &NodeKind::Frontmatter,
+                &NodeKind::Paragraph,
+                &NodeKind::List { start: None },
+                &NodeKind::Table,
+                &NodeKind::CodeBlock {
+                    lang: Some("rust".into())
+                },
+                &NodeKind::FootnoteDefinition { label: "1".into() },
+            ]
+        );
+        assert_eq!(nodes[1].text(), "Some text and code.");
+        assert_eq!(&content[nodes[1].children[1].range.clone()], "*text*");
+        assert_eq!(
+            nodes[2]
+                .children
+                .iter()
+                .map(|i| i.kind.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                NodeKind::Item {
+                    checked: Some(true)
+                },
+                NodeKind::Item {
+                    checked: Some(false)
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_outline() {
+        let content = "---\ntitle: Not a heading\n---\n\
+            # Title\nIntro\n\
+            ## Part `one` ##\n### Detail\n#tag\n\
+            ```\n# not a heading\n```\n\
+            Setext\n------\n\
+            # Appendix\n";
+        let summary = |items: &[OutlineItem]| {
+            items
+                .iter()
+                .map(|i| (i.heading.text.clone(), i.heading.line, i.children.len()))
+                .collect::<Vec<_>>()
+        };
+
+        let outline = outline(content);
+        assert_eq!(
+            summary(&outline),
+            vec![("Title".into(), 4, 2), ("Appendix".into(), 14, 0)]
+        );
+        assert_eq!(
+            summary(&outline[0].children),
+            vec![("Part one".into(), 6, 1), ("Setext".into(), 12, 0)]
+        );
+
+        // A section runs until the next heading at the same level or above
+        let part = &outline[0].children[0];
+        assert_eq!(
+            &content[part.section.clone()],
+            "## Part `one` ##\n### Detail\n#tag\n```\n# not a heading\n```\n"
+        );
+        assert_eq!(&content[part.heading.range.clone()], "## Part `one` ##\n");
+        assert_eq!(outline[1].section.end, content.len());
+    }
+}
@@ -0,0 +1,94 @@
+pub mod check;
+pub mod diff;
+pub mod export;
+pub mod files;
+pub mod frontmatter;
+pub mod history;
+pub mod links;
+pub mod markdown;
+pub mod query;
+pub mod refactor;
+pub mod render;
+pub mod search;
+pub mod share;
+pub mod tags;
+pub mod watcher;
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Extension of note files
+pub const NOTE_EXT: &str = "md";
+
+/// An index over the notes of a vault, kept up to date as notes change
+pub trait NoteIndex {
+    fn update_note(&mut self, note: &str, content: &str);
+    fn remove_note(&mut self, note: &str);
+}
+
+/// Vault directory to open: $TEM_VAULT if set, otherwise ~/tem
+pub fn default_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("TEM_VAULT") {
+        return PathBuf::from(dir);
+    }
+    let home = env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .unwrap_or_else(|| ".".into());
+    PathBuf::from(home).join("tem")
+}
+
+/// Finds every note in the vault, sorted by path. Hidden files and folders
+/// (eg. `.git`, `.trash`) are skipped.
+pub fn walk_notes(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut out = walk_files(root)?;
+    out.retain(|p| p.extension().and_then(|e| e.to_str()) == Some(NOTE_EXT));
+    Ok(out)
+}
+
+/// Like walk_notes, but for every file, including attachments
+pub fn walk_files(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut out = Vec::<PathBuf>::new();
+    let mut dirs = vec![root.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else {
+                out.push(path);
+            }
+        }
+    }
+
+    out.sort();
+    Ok(out)
+}
+
+/// Reads a note while scanning the vault. Notes that can't be read (or aren't
+/// UTF-8) are logged and skipped, so one bad file doesn't hide the rest.
+pub(crate) fn read_scanned(path: &Path) -> Option<String> {
+    match fs::read_to_string(path) {
+        Ok(content) => Some(content),
+        Err(e) => {
+            eprintln!("skipping {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Id of a note: its path relative to the vault root, always with `/` separators
+pub fn note_id(root: &Path, path: &Path) -> Option<String> {
+    let rel = path.strip_prefix(root).ok()?;
+    let parts = rel
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<&str>>>()?;
+    Some(parts.join("/"))
+}
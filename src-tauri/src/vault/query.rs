@@ -0,0 +1,573 @@
+//! Tag queries, eg. `#work AND (#meeting OR #standup) AND NOT #archived`.
+//!
+//! ```text
+//! query  := and ("OR" and)*
+//! and    := unary ("AND"? unary)*       terms next to each other are ANDed
+//! unary  := "NOT" unary | "(" query ")" | #tag | field op date
+//! op     := "=" | "<" | "<=" | ">" | ">="
+//! ```
+//!
+//! Keywords are case-insensitive. Tags match nested tags too, so `#project`
+//! matches a note tagged `#project/tem`. Date filters compare a frontmatter
+//! field against a `YYYY-MM-DD` date, eg. `created >= 2024-01-01`, and never
+//! match notes without that field.
+
+use std::{
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::tags::{normalize_tag, TagIndex};
+
+/// Where saved queries are kept, relative to the vault root
+pub const SAVED_QUERIES_FILE: &str = ".tem/queries.json";
+
+/// How deep `(` and NOT can nest, so the parser can't run out of stack
+const MAX_DEPTH: usize = 256;
+
+/// A calendar date, ordered chronologically
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    /// Parses `YYYY-MM-DD`, ignoring any time after it (`2024-05-01T10:00`)
+    pub fn parse(raw: &str) -> Option<Date> {
+        let raw = raw.trim();
+        if !raw.is_char_boundary(raw.len().min(10)) {
+            return None;
+        }
+        let (date, rest) = raw.split_at(raw.len().min(10));
+        if !(rest.is_empty() || rest.starts_with('T') || rest.starts_with(' ')) {
+            return None;
+        }
+
+        let mut parts = date.splitn(3, '-');
+        let mut next = |len: usize| {
+            parts
+                .next()
+                .filter(|p| p.len() == len && p.chars().all(|c| c.is_ascii_digit()))
+                .and_then(|p| p.parse::<u32>().ok())
+        };
+        let (year, month, day) = (next(4)?, next(2)?, next(2)?);
+
+        let days = match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+            2 => 28,
+            _ => return None,
+        };
+        if day == 0 || day > days {
+            return None;
+        }
+
+        Some(Date {
+            year: year as i32,
+            month,
+            day,
+        })
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Dates are written as `YYYY-MM-DD` strings
+impl Serialize for Date {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Date {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        Date::parse(&raw).ok_or_else(|| serde::de::Error::custom(format!("invalid date '{}'", raw)))
+    }
+}
+
+/// Comparison in a date filter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CmpOp {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Parsed query
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Tag(String),
+    Date {
+        field: String,
+        op: CmpOp,
+        date: Date,
+    },
+    Not(Box<Query>),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+}
+
+/// Why a query couldn't be parsed. Column is 1-based, in characters.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueryError {
+    pub message: String,
+    pub column: usize,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (column {})", self.message, self.column)
+    }
+}
+
+impl Error for QueryError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Lexeme {
+    Open,
+    Close,
+    Tag(String),
+    Op(CmpOp),
+    /// Keyword, field name or date
+    Word(String),
+}
+
+/// A lexeme and the column it starts at
+type Spanned = (Lexeme, usize);
+
+fn lex(input: &str) -> Result<Vec<Spanned>, QueryError> {
+    let chars = input.chars().collect::<Vec<char>>();
+    let mut out = Vec::<Spanned>::new();
+    let mut i = 0;
+
+    let is_word = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '/' | ':' | '.');
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                out.push((Lexeme::Open, column));
+                i += 1;
+            }
+            ')' => {
+                out.push((Lexeme::Close, column));
+                i += 1;
+            }
+            '<' | '>' | '=' => {
+                let eq = chars.get(i + 1) == Some(&'=');
+                let op = match (c, eq) {
+                    ('<', true) => CmpOp::Le,
+                    ('<', false) => CmpOp::Lt,
+                    ('>', true) => CmpOp::Ge,
+                    ('>', false) => CmpOp::Gt,
+                    _ => CmpOp::Eq,
+                };
+                out.push((Lexeme::Op(op), column));
+                i += if eq && c != '=' { 2 } else { 1 };
+            }
+            '#' => {
+                let start = i + 1;
+                i = start;
+                while i < chars.len() && is_word(chars[i]) {
+                    i += 1;
+                }
+                let tag = normalize_tag(&chars[start..i].iter().collect::<String>());
+                if tag.is_empty() {
+                    return Err(error("expected a tag name after '#'", column));
+                }
+                out.push((Lexeme::Tag(tag), column));
+            }
+            c if is_word(c) => {
+                let start = i;
+                while i < chars.len() && is_word(chars[i]) {
+                    i += 1;
+                }
+                out.push((Lexeme::Word(chars[start..i].iter().collect()), column));
+            }
+            c => return Err(error(&format!("unexpected '{}'", c), column)),
+        }
+    }
+
+    Ok(out)
+}
+
+struct Parser {
+    lexemes: Vec<Spanned>,
+    pos: usize,
+    /// Column just past the end of the input, for errors at the end
+    end: usize,
+    /// How many `(` and NOT the parser is inside
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Lexeme> {
+        self.lexemes.get(self.pos).map(|(l, _)| l)
+    }
+
+    fn column(&self) -> usize {
+        self.lexemes
+            .get(self.pos)
+            .map(|(_, c)| *c)
+            .unwrap_or(self.end)
+    }
+
+    fn next(&mut self) -> Option<Spanned> {
+        let out = self.lexemes.get(self.pos).cloned();
+        self.pos += 1;
+        out
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Lexeme::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn or(&mut self) -> Result<Query, QueryError> {
+        let mut left = self.and()?;
+        while self.is_keyword("or") {
+            self.pos += 1;
+            left = Query::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Query, QueryError> {
+        let mut left = self.unary()?;
+        loop {
+            if self.is_keyword("and") {
+                self.pos += 1;
+            } else if self.peek().is_none()
+                || self.peek() == Some(&Lexeme::Close)
+                || self.is_keyword("or")
+            {
+                return Ok(left);
+            }
+            left = Query::And(Box::new(left), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Query, QueryError> {
+        let column = self.column();
+        if self.depth >= MAX_DEPTH {
+            return Err(error("query is nested too deeply", column));
+        }
+        if self.is_keyword("not") {
+            self.pos += 1;
+            self.depth += 1;
+            let inner = self.unary();
+            self.depth -= 1;
+            return Ok(Query::Not(Box::new(inner?)));
+        }
+
+        match self.next() {
+            Some((Lexeme::Open, _)) => {
+                self.depth += 1;
+                let inner = self.or();
+                self.depth -= 1;
+                let inner = inner?;
+                match self.next() {
+                    Some((Lexeme::Close, _)) => Ok(inner),
+                    _ => Err(error("unclosed '('", column)),
+                }
+            }
+            Some((Lexeme::Tag(tag), _)) => Ok(Query::Tag(tag)),
+            Some((Lexeme::Word(field), _)) if !is_reserved(&field) => self.date_filter(field),
+            Some((Lexeme::Close, _)) => Err(error("unexpected ')'", column)),
+            Some((Lexeme::Word(w), _)) => Err(error(
+                &format!(
+                    "expected a tag or date filter before '{}'",
+                    w.to_uppercase()
+                ),
+                column,
+            )),
+            Some((Lexeme::Op(_), _)) => {
+                Err(error("expected a field name before the operator", column))
+            }
+            None => Err(error("expected a tag, date filter or '('", column)),
+        }
+    }
+
+    fn date_filter(&mut self, field: String) -> Result<Query, QueryError> {
+        let column = self.column();
+        let op = match self.next() {
+            Some((Lexeme::Op(op), _)) => op,
+            _ => {
+                return Err(error(
+                    &format!(
+                        "expected =, <, <=, > or >= after '{}' (tags start with #)",
+                        field
+                    ),
+                    column,
+                ))
+            }
+        };
+
+        let column = self.column();
+        match self.next() {
+            Some((Lexeme::Word(raw), _)) => match Date::parse(&raw) {
+                Some(date) => Ok(Query::Date { field, op, date }),
+                None => Err(error(
+                    &format!("invalid date '{}', expected YYYY-MM-DD", raw),
+                    column,
+                )),
+            },
+            _ => Err(error("expected a date (YYYY-MM-DD)", column)),
+        }
+    }
+}
+
+fn is_reserved(word: &str) -> bool {
+    ["and", "or", "not"]
+        .iter()
+        .any(|k| word.eq_ignore_ascii_case(k))
+}
+
+fn error(message: &str, column: usize) -> QueryError {
+    QueryError {
+        message: message.to_string(),
+        column,
+    }
+}
+
+/// Parses a query expression
+pub fn parse_query(input: &str) -> Result<Query, QueryError> {
+    let mut parser = Parser {
+        lexemes: lex(input)?,
+        pos: 0,
+        end: input.chars().count() + 1,
+        depth: 0,
+    };
+    let query = parser.or()?;
+
+    // The only way `or` stops early is on a stray ')'
+    match parser.peek() {
+        None => Ok(query),
+        Some(_) => Err(error("unexpected ')'", parser.column())),
+    }
+}
+
+impl Query {
+    /// Whether a note in the index matches
+    pub fn matches(&self, index: &TagIndex, note: &str) -> bool {
+        match self {
+            Query::Tag(tag) => index.has_tag(note, tag),
+            Query::Date { field, op, date } => match index.date(note, field) {
+                Some(d) => match op {
+                    CmpOp::Eq => d == *date,
+                    CmpOp::Lt => d < *date,
+                    CmpOp::Le => d <= *date,
+                    CmpOp::Gt => d > *date,
+                    CmpOp::Ge => d >= *date,
+                },
+                None => false,
+            },
+            Query::Not(q) => !q.matches(index, note),
+            Query::And(a, b) => a.matches(index, note) && b.matches(index, note),
+            Query::Or(a, b) => a.matches(index, note) || b.matches(index, note),
+        }
+    }
+}
+
+/// Parses and runs a query, returning the matching notes sorted by path
+pub fn query_notes(index: &TagIndex, input: &str) -> Result<Vec<String>, QueryError> {
+    let query = parse_query(input)?;
+    Ok(index
+        .notes()
+        .filter(|n| query.matches(index, n))
+        .map(|n| n.to_string())
+        .collect())
+}
+
+/// A named query, shown in the sidebar like a tag
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedQuery {
+    pub name: String,
+    pub query: String,
+}
+
+/// Saved queries of a vault, stored as JSON in SAVED_QUERIES_FILE
+#[derive(Debug)]
+pub struct SavedQueries {
+    path: PathBuf,
+    queries: Vec<SavedQuery>,
+}
+
+impl SavedQueries {
+    /// No saved queries yet, they are written to the vault on the first save
+    pub fn empty(root: &Path) -> Self {
+        SavedQueries {
+            path: root.join(SAVED_QUERIES_FILE),
+            queries: vec![],
+        }
+    }
+
+    /// Loads the vault's saved queries, a missing file is treated as empty
+    pub fn open(root: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = root.join(SAVED_QUERIES_FILE);
+        let queries = match fs::read_to_string(&path) {
+            Ok(raw) => serde_json::from_str(&raw)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        Ok(SavedQueries { path, queries })
+    }
+
+    pub fn list(&self) -> &[SavedQuery] {
+        &self.queries
+    }
+
+    /// Saves a query under the name, replacing any query with the same name.
+    /// Queries that don't parse are rejected.
+    pub fn save(&mut self, name: &str, query: &str) -> Result<(), Box<dyn Error>> {
+        if name.trim().is_empty() {
+            return Err("query name cannot be empty".into());
+        }
+        parse_query(query)?;
+
+        let saved = SavedQuery {
+            name: name.trim().to_string(),
+            query: query.to_string(),
+        };
+        match self.queries.iter_mut().find(|q| q.name == saved.name) {
+            Some(q) => *q = saved,
+            None => self.queries.push(saved),
+        }
+        self.write()
+    }
+
+    /// Deletes the saved query with the name, if there is one
+    pub fn remove(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        self.queries.retain(|q| q.name != name);
+        self.write()
+    }
+
+    fn write(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&self.queries)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> TagIndex {
+        let mut index = TagIndex::new(Path::new("/vault"));
+        index.update_note("a.md", "---\ncreated: 2024-03-01\n---\n#work #meeting");
+        index.update_note("b.md", "---\ncreated: 2024-06-15\n---\n#work #standup");
+        index.update_note("c.md", "#work #meeting #archived");
+        index.update_note("d.md", "#home");
+        index
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let tag = |t: &str| Box::new(Query::Tag(t.into()));
+        assert_eq!(
+            parse_query("#a OR #b and not #c"),
+            Ok(Query::Or(
+                tag("a"),
+                Box::new(Query::And(tag("b"), Box::new(Query::Not(tag("c")))))
+            ))
+        );
+        assert_eq!(parse_query("#a #b"), Ok(Query::And(tag("a"), tag("b"))));
+        assert_eq!(
+            parse_query("due < 2024-02-29"),
+            Ok(Query::Date {
+                field: "due".into(),
+                op: CmpOp::Lt,
+                date: Date {
+                    year: 2024,
+                    month: 2,
+                    day: 29
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |q: &str| parse_query(q).unwrap_err();
+        assert_eq!(err("#a AND (#b OR #c").column, 8);
+        assert_eq!(err("#a AND").message, "expected a tag, date filter or '('");
+        assert_eq!(err("#a AND").column, 7);
+        assert_eq!(err("#a)").message, "unexpected ')'");
+        assert_eq!(err("work").column, 5);
+        assert_eq!(
+            err("due > 2023-02-29").message,
+            "invalid date '2023-02-29', expected YYYY-MM-DD"
+        );
+        assert_eq!(err("#a OR OR #b").column, 7);
+        assert_eq!(err("#a & #b").message, "unexpected '&'");
+
+        // Nesting is limited instead of overflowing the stack
+        let deep = format!("{}#a{}", "(".repeat(20_000), ")".repeat(20_000));
+        assert_eq!(err(&deep).message, "query is nested too deeply");
+        assert_eq!(err(&deep).column, MAX_DEPTH + 1);
+        assert_eq!(err(&"NOT ".repeat(20_000)).column, MAX_DEPTH * 4 + 1);
+        let ok = format!("{}#a{}", "(".repeat(100), ")".repeat(100));
+        assert_eq!(parse_query(&ok), Ok(Query::Tag("a".into())));
+    }
+
+    #[test]
+    fn test_query_notes() {
+        let index = index();
+        assert_eq!(
+            query_notes(&index, "#work AND (#meeting OR #standup) AND NOT #archived"),
+            Ok(vec!["a.md".to_string(), "b.md".to_string()])
+        );
+        assert_eq!(
+            query_notes(&index, "#work created >= 2024-06-01"),
+            Ok(vec!["b.md".to_string()])
+        );
+        assert_eq!(
+            query_notes(&index, "NOT created < 2024-06-01"),
+            Ok(vec![
+                "b.md".to_string(),
+                "c.md".to_string(),
+                "d.md".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_saved_queries() {
+        let root = std::env::temp_dir().join(format!("tem-queries-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        let mut saved = SavedQueries::open(&root).expect("open failed");
+        saved.save("Open work", "#work AND NOT #archived").unwrap();
+        saved.save("Home", "#home").unwrap();
+        saved.save("Open work", "#work").unwrap();
+        assert!(saved.save("Broken", "#work AND").is_err());
+        saved.remove("Home").unwrap();
+
+        let saved = SavedQueries::open(&root).expect("reopen failed");
+        assert_eq!(
+            saved.list(),
+            &[SavedQuery {
+                name: "Open work".into(),
+                query: "#work".into()
+            }]
+        );
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
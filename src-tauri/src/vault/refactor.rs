@@ -0,0 +1,425 @@
+use std::{
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::temsync::{
+    meyers::get_diff,
+    patch::{apply_patch, generate_patch, read_patch, tokens_to_string},
+    tokenizer::parse_string_tokens,
+};
+
+use super::files::write_atomic;
+use super::frontmatter::{set_property, Frontmatter, PropertyValue};
+use super::history::History;
+use super::tags::{inline_tag_spans, is_valid_tag, normalize_tag, split_frontmatter, TagIndex};
+
+/// Where applied refactors are logged so they can be undone, relative to the vault root
+pub const REFACTOR_DIR: &str = ".tem/refactors";
+
+/// The change to a single note, as temsync patches
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NoteEdit {
+    pub note: String,
+    /// How many tag occurrences get rewritten
+    pub replacements: usize,
+    /// Patch from the current content to the rewritten one
+    pub patch: String,
+    /// Patch that reverts it
+    pub undo: String,
+}
+
+/// A change across the vault that is applied, and undone, as one operation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Refactor {
+    pub id: String,
+    pub description: String,
+    pub edits: Vec<NoteEdit>,
+}
+
+/// Plans renaming a tag (and every tag nested under it) in every note that uses it.
+/// Renaming onto a tag that is already in use is only allowed when merging.
+/// Nothing is written, pass the result to apply_refactor.
+pub fn plan_rename(
+    index: &TagIndex,
+    old: &str,
+    new: &str,
+    merge: bool,
+) -> Result<Refactor, String> {
+    let (old, new) = (normalize_tag(old), normalize_tag(new));
+    for tag in [&old, &new] {
+        if !is_valid_tag(tag) {
+            return Err(format!("'{}' is not a valid tag", tag));
+        }
+    }
+    if old == new {
+        return Err("the old and new tag are the same".into());
+    }
+
+    let notes = index.notes_for_tag(&old);
+    if notes.is_empty() {
+        return Err(format!("no notes are tagged #{}", old));
+    }
+    if !merge && !index.notes_for_tag(&new).is_empty() {
+        return Err(format!(
+            "#{} is already in use, merge the tags instead",
+            new
+        ));
+    }
+
+    let mut edits = Vec::<NoteEdit>::new();
+    for note in notes.iter() {
+        let content = fs::read_to_string(index.root().join(note))
+            .map_err(|e| format!("unable to read {}: {}", note, e))?;
+        let (rewritten, replacements) = rename_in_note(&content, &old, &new);
+        if replacements == 0 {
+            continue;
+        }
+        edits.push(NoteEdit {
+            note: note.clone(),
+            replacements,
+            patch: make_patch(&content, &rewritten)?,
+            undo: make_patch(&rewritten, &content)?,
+        });
+    }
+
+    let verb = if merge { "merge" } else { "rename" };
+    Ok(Refactor {
+        id: new_id(),
+        description: format!("{} #{} into #{}", verb, old, new),
+        edits,
+    })
+}
+
+/// Applies every edit of a planned refactor and logs it so it can be undone. If any
+/// note changed since the plan was made, nothing is written. Each note's history
+/// gets a revision before and after the rewrite.
+pub fn apply_refactor(
+    index: &mut TagIndex,
+    history: &mut History,
+    refactor: &Refactor,
+) -> Result<(), String> {
+    let root = index.root().to_path_buf();
+    let updated = patch_notes(&root, refactor.edits.iter().map(|e| (&e.note, &e.patch)))?;
+
+    // Only the notes that were written are logged, so a write that fails part way
+    // through can still be undone
+    let (written, result) = write_notes(index, history, updated);
+    if written > 0 {
+        write_log(
+            &root,
+            &Refactor {
+                edits: refactor.edits[..written].to_vec(),
+                ..refactor.clone()
+            },
+        )?;
+    }
+    result
+}
+
+/// Reverts a refactor applied earlier, returning it
+pub fn undo_refactor(
+    index: &mut TagIndex,
+    history: &mut History,
+    id: &str,
+) -> Result<Refactor, String> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(format!("invalid refactor id '{}'", id));
+    }
+
+    let root = index.root().to_path_buf();
+    let path = root.join(REFACTOR_DIR).join(format!("{}.json", id));
+    let raw = fs::read_to_string(&path).map_err(|e| format!("unknown refactor {}: {}", id, e))?;
+    let refactor = serde_json::from_str::<Refactor>(&raw)
+        .map_err(|e| format!("corrupt refactor log: {}", e))?;
+
+    let updated = patch_notes(&root, refactor.edits.iter().map(|e| (&e.note, &e.undo)))
+        .map_err(|e| format!("unable to undo, {}", e))?;
+
+    // Notes left as they were when a write fails stay logged, to undo them later
+    let (written, result) = write_notes(index, history, updated);
+    if let Err(e) = result {
+        write_log(
+            &root,
+            &Refactor {
+                edits: refactor.edits[written..].to_vec(),
+                ..refactor.clone()
+            },
+        )?;
+        return Err(e);
+    }
+    fs::remove_file(&path).map_err(|e| format!("unable to remove refactor log: {}", e))?;
+    Ok(refactor)
+}
+
+fn write_log(root: &Path, refactor: &Refactor) -> Result<(), String> {
+    let log = root.join(REFACTOR_DIR);
+    fs::create_dir_all(&log)
+        .and_then(|_| {
+            let raw = serde_json::to_string_pretty(refactor).map_err(std::io::Error::other)?;
+            write_atomic(&log.join(format!("{}.json", refactor.id)), &raw)
+        })
+        .map_err(|e| format!("unable to log refactor: {}", e))
+}
+
+/// Applies the patches in memory, so a note that doesn't match fails the whole batch.
+/// Returns each note with its content before and after.
+fn patch_notes<'a>(
+    root: &Path,
+    patches: impl Iterator<Item = (&'a String, &'a String)>,
+) -> Result<Vec<(String, String, String)>, String> {
+    let mut out = Vec::<(String, String, String)>::new();
+    for (note, patch) in patches {
+        let content = fs::read_to_string(root.join(note))
+            .map_err(|e| format!("unable to read {}: {}", note, e))?;
+        let base = parse_string_tokens(&content).map_err(|e| e.to_string())?;
+        let edits = read_patch(patch).map_err(|e| e.to_string())?;
+        let tokens =
+            apply_patch(&base, &edits).map_err(|e| format!("{} changed since ({})", note, e))?;
+        out.push((note.clone(), content, tokens_to_string(&tokens)));
+    }
+    Ok(out)
+}
+
+/// Writes the notes in order, stopping at the first that fails. Returns how many
+/// were written, with the error if not all of them were.
+fn write_notes(
+    index: &mut TagIndex,
+    history: &mut History,
+    notes: Vec<(String, String, String)>,
+) -> (usize, Result<(), String>) {
+    let now = SystemTime::now();
+    for (i, (note, before, content)) in notes.iter().enumerate() {
+        let written = history.capture(note, before, now).and_then(|_| {
+            write_atomic(&index.root().join(note), content)
+                .map_err(|e| format!("unable to write {}: {}", note, e))
+        });
+        if let Err(e) = written {
+            return (i, Err(e));
+        }
+        index.update_note(note, content);
+        // The note is written, so a missing revision doesn't stop the rest
+        if let Err(e) = history.capture(note, content, now) {
+            eprintln!("unable to capture a revision of {}: {}", note, e);
+        }
+    }
+    (notes.len(), Ok(()))
+}
+
+fn make_patch(from: &str, to: &str) -> Result<String, String> {
+    let from = parse_string_tokens(from).map_err(|e| e.to_string())?;
+    let to = parse_string_tokens(to).map_err(|e| e.to_string())?;
+    Ok(generate_patch(&get_diff(&from, &to)?))
+}
+
+fn new_id() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{}-{:09}", now.as_secs(), now.subsec_nanos())
+}
+
+/// The tag after renaming old to new, if it is old or nested under it
+fn renamed(tag: &str, old: &str, new: &str) -> Option<String> {
+    if tag == old {
+        return Some(new.to_string());
+    }
+    tag.strip_prefix(old)
+        .filter(|rest| rest.starts_with('/'))
+        .map(|rest| format!("{}{}", new, rest))
+}
+
+/// Rewrites the tag in the frontmatter and body of a note, returning the new
+/// content and how many occurrences changed
+fn rename_in_note(content: &str, old: &str, new: &str) -> (String, usize) {
+    let (content, mut count) = rename_in_frontmatter(content, old, new);
+    let body_start = content.len() - split_frontmatter(&content).1.len();
+    let mut out = String::with_capacity(content.len());
+    out.push_str(&content[..body_start]);
+
+    let mut last = body_start;
+    for (range, tag) in inline_tag_spans(&content, body_start) {
+        if let Some(tag) = renamed(&tag, old, new) {
+            out.push_str(&content[last..range.start]);
+            out.push_str(&tag);
+            last = range.end;
+            count += 1;
+        }
+    }
+    out.push_str(&content[last..]);
+
+    (out, count)
+}
+
+/// Rewrites the `tags:` property with set_property, so it keeps its style (flow
+/// list, block list or a comma/space separated string) and comments. Tags that
+/// end up listed twice are dropped. Frontmatter that doesn't parse is left alone.
+fn rename_in_frontmatter(content: &str, old: &str, new: &str) -> (String, usize) {
+    let value = match Frontmatter::parse(content)
+        .ok()
+        .and_then(|f| f.get("tags").cloned())
+    {
+        Some(v) => v,
+        None => return (content.to_string(), 0),
+    };
+
+    let mut count = 0;
+    let mut seen = Vec::<String>::new();
+    // Renames an item, keeping its `#` if it had one. None if the list already has it.
+    let mut rename_item = |item: &str| -> Option<String> {
+        let out = match renamed(&normalize_tag(item), old, new) {
+            Some(tag) if item.starts_with('#') => format!("#{}", tag),
+            Some(tag) => tag,
+            None => item.to_string(),
+        };
+        count += (out != item) as usize;
+        let value = normalize_tag(&out);
+        if seen.contains(&value) {
+            return None;
+        }
+        seen.push(value);
+        Some(out)
+    };
+
+    let value = match value {
+        PropertyValue::List(items) => {
+            PropertyValue::List(items.iter().filter_map(|i| rename_item(i)).collect())
+        }
+        PropertyValue::Text(text) => PropertyValue::Text(rename_words(&text, rename_item)),
+        PropertyValue::Number(n) => PropertyValue::Text(rename_words(&n.to_string(), rename_item)),
+        _ => return (content.to_string(), 0),
+    };
+    if count == 0 {
+        return (content.to_string(), 0);
+    }
+    match set_property(content, "tags", Some(&value)) {
+        Ok(out) => (out, count),
+        Err(_) => (content.to_string(), 0),
+    }
+}
+
+/// Renames the words of a comma/space separated string, keeping what's between
+/// them. A dropped word goes with the separator before it.
+fn rename_words(text: &str, mut rename: impl FnMut(&str) -> Option<String>) -> String {
+    let is_separator = |c: char| c == ',' || c.is_whitespace();
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (separator, tail) =
+            rest.split_at(rest.find(|c| !is_separator(c)).unwrap_or(rest.len()));
+        let (word, tail) = tail.split_at(tail.find(is_separator).unwrap_or(tail.len()));
+        if word.is_empty() {
+            out.push_str(separator);
+        } else if let Some(word) = rename(word) {
+            out.push_str(separator);
+            out.push_str(&word);
+        }
+        rest = tail;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vault::history::RevisionPolicy;
+
+    use super::*;
+
+    #[test]
+    fn test_rename_in_note() {
+        let note = "---\ntitle: x\ntags: [proj, \"#proj/tem\", other]\n---\n#proj and #project `#proj` #proj/ui\n```\n#proj\n```\n";
+        let (out, count) = rename_in_note(note, "proj", "work");
+        assert_eq!(
+            out,
+            "---\ntitle: x\ntags: [work, '#work/tem', other]\n---\n#work and #project `#proj` #work/ui\n```\n#proj\n```\n"
+        );
+        assert_eq!(count, 4);
+
+        // Block lists and strings keep their style, and merged duplicates are dropped
+        let note = "---\ntags:\n  - old\n  - new\n  - keep\nid: 1\n---\n";
+        assert_eq!(
+            rename_in_note(note, "old", "new").0,
+            "---\ntags:\n  - new\n  - keep\nid: 1\n---\n"
+        );
+        let note = "---\ntags: old, keep\n---\nbody #old";
+        assert_eq!(
+            rename_in_note(note, "old", "new").0,
+            "---\ntags: new, keep\n---\nbody #new"
+        );
+        let note = "---\ntags: keep old new # topics\n---\n";
+        assert_eq!(
+            rename_in_note(note, "old", "new").0,
+            "---\ntags: keep new # topics\n---\n"
+        );
+
+        // Flow lists over several lines, and quoted items
+        let note = "---\ntags: [alpha,\n  old, beta]\nid: 1\n---\n";
+        assert_eq!(
+            rename_in_note(note, "old", "new"),
+            ("---\ntags: [alpha, new, beta]\nid: 1\n---\n".into(), 1)
+        );
+        let note = "---\ntags:\n  - \"#old/a\"\n  - 'keep it'\n---\n";
+        assert_eq!(
+            rename_in_note(note, "old", "new").0,
+            "---\ntags:\n  - '#new/a'\n  - keep it\n---\n"
+        );
+    }
+
+    #[test]
+    fn test_rename_apply_and_undo() {
+        let root = std::env::temp_dir().join(format!("tem-refactor-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.md"), "---\ntags: [draft]\n---\nA #draft note").unwrap();
+        fs::write(root.join("b.md"), "#draft/ideas and #final 🚗").unwrap();
+        let mut index = TagIndex::scan(&root).unwrap();
+        let mut history = History::new(&root, RevisionPolicy::default());
+
+        assert!(plan_rename(&index, "draft", "final", false).is_err());
+        assert!(plan_rename(&index, "missing", "other", false).is_err());
+
+        // A dry run doesn't touch anything
+        let plan = plan_rename(&index, "draft", "final", true).unwrap();
+        assert_eq!(plan.edits.len(), 2);
+        assert_eq!(plan.edits[0].replacements, 2);
+        assert_eq!(
+            fs::read_to_string(root.join("b.md")).unwrap(),
+            "#draft/ideas and #final 🚗"
+        );
+
+        apply_refactor(&mut index, &mut history, &plan).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("b.md")).unwrap(),
+            "#final/ideas and #final 🚗"
+        );
+        assert_eq!(index.notes_for_tag("draft"), Vec::<String>::new());
+        assert_eq!(index.notes_for_tag("final"), vec!["a.md", "b.md"]);
+        assert_eq!(
+            history.content_at("b.md", 1),
+            Ok("#draft/ideas and #final 🚗".into())
+        );
+        assert_eq!(history.revisions("b.md"), Ok(2));
+
+        let undone = undo_refactor(&mut index, &mut history, &plan.id).unwrap();
+        assert_eq!(undone.description, "merge #draft into #final");
+        assert_eq!(
+            fs::read_to_string(root.join("a.md")).unwrap(),
+            "---\ntags: [draft]\n---\nA #draft note"
+        );
+        assert_eq!(index.notes_for_tag("draft"), vec!["a.md", "b.md"]);
+
+        // Plans made against older content are refused
+        let plan = plan_rename(&index, "draft", "idea", false).unwrap();
+        fs::write(root.join("b.md"), "edited #draft/ideas").unwrap();
+        assert!(apply_refactor(&mut index, &mut history, &plan).is_err());
+        assert_eq!(
+            fs::read_to_string(root.join("a.md")).unwrap(),
+            "---\ntags: [draft]\n---\nA #draft note"
+        );
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,475 @@
+//! Markdown to HTML for the preview. Links between notes are resolved through the
+//! link index, code blocks are highlighted, and everything is sanitized at the end
+//! since the webview runs without a content security policy.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashSet},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use pulldown_cmark::{html, CodeBlockKind, Event, LinkType, Parser, Tag, TagEnd};
+use syntect::{
+    highlighting::ThemeSet,
+    html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+
+use super::{
+    files::{self, FileError},
+    links::{
+        find_attachment, folder, is_attachment, join, percent_decode, Link, LinkIndex, LinkKind,
+    },
+    markdown::{options, slug},
+    note_id, walk_files, NOTE_EXT,
+};
+
+/// Custom protocol the webview loads attachments from
+pub const ATTACHMENT_SCHEME: &str = "vault";
+/// Scheme of links to other notes, which the app opens itself
+pub const NOTE_SCHEME: &str = "note";
+/// Ids in rendered notes get this prefix, so they can't clash with the app's own
+pub const ANCHOR_PREFIX: &str = "tem-";
+/// Theme for code blocks, dark like the editor
+pub const HIGHLIGHT_THEME: &str = "base16-ocean.dark";
+
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+
+/// Where links in a rendered note lead
+pub trait LinkUrls {
+    /// URL of a note, with the slug of a heading in it. Links without a URL
+    /// render as plain text.
+    fn note(&self, from: &str, note: &str, anchor: Option<&str>) -> Option<String>;
+    /// URL of a file in the vault that isn't a note
+    fn attachment(&self, from: &str, file: &str) -> String;
+}
+
+/// Links for the preview in the app, `note:` links to open and attachments over
+/// the vault protocol
+pub struct AppUrls;
+
+impl LinkUrls for AppUrls {
+    fn note(&self, _from: &str, note: &str, anchor: Option<&str>) -> Option<String> {
+        Some(match anchor {
+            Some(anchor) => format!("{}:{}#{}", NOTE_SCHEME, encode_path(note), anchor),
+            None => format!("{}:{}", NOTE_SCHEME, encode_path(note)),
+        })
+    }
+
+    fn attachment(&self, _from: &str, file: &str) -> String {
+        // Windows webviews only load custom protocols as http://<scheme>.localhost
+        if cfg!(any(windows, target_os = "android")) {
+            format!(
+                "http://{}.localhost/{}",
+                ATTACHMENT_SCHEME,
+                encode_path(file)
+            )
+        } else {
+            format!("{}://localhost/{}", ATTACHMENT_SCHEME, encode_path(file))
+        }
+    }
+}
+
+/// Renders a note to sanitized HTML. Links that don't lead anywhere still
+/// render, with a `missing` class.
+pub fn render_html(index: &LinkIndex, note: &str, content: &str, urls: &impl LinkUrls) -> String {
+    let events = Parser::new_ext(content, options()).collect::<Vec<Event>>();
+    let mut links = Resolver {
+        index,
+        note,
+        urls,
+        files: None,
+    };
+
+    let mut out = Vec::<Event>::with_capacity(events.len());
+    // How each open link or image is closed, when it was replaced
+    let mut closers = Vec::<Option<&str>>::new();
+    let mut i = 0;
+    while i < events.len() {
+        match &events[i] {
+            Event::Start(Tag::Heading {
+                level,
+                classes,
+                attrs,
+                ..
+            }) => {
+                let text = events[i + 1..]
+                    .iter()
+                    .take_while(|e| !matches!(e, Event::End(TagEnd::Heading(_))))
+                    .filter_map(|e| match e {
+                        Event::Text(t) | Event::Code(t) => Some(t.as_ref()),
+                        _ => None,
+                    })
+                    .collect::<String>();
+                out.push(Event::Start(Tag::Heading {
+                    level: *level,
+                    id: Some(slug(&text).into()),
+                    classes: classes.clone(),
+                    attrs: attrs.clone(),
+                }));
+            }
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let lang = match kind {
+                    CodeBlockKind::Fenced(info) => info.split_whitespace().next(),
+                    CodeBlockKind::Indented => None,
+                };
+                let mut code = String::new();
+                i += 1;
+                while let Some(Event::Text(text)) = events.get(i) {
+                    code.push_str(text);
+                    i += 1;
+                }
+                // i is on the end of the block
+                out.push(Event::Html(highlight(&code, lang).into()));
+            }
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                ..
+            }) => match to_link(*link_type, dest_url) {
+                Some(link) => {
+                    let (url, class) = links.url(&link);
+                    let (open, close) = anchor_tags(url.as_deref(), class, title);
+                    out.push(Event::InlineHtml(open.into()));
+                    closers.push(Some(close));
+                }
+                None => {
+                    out.push(events[i].clone());
+                    closers.push(None);
+                }
+            },
+            Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => {
+                // Embedded notes are shown as links
+                match to_link(*link_type, dest_url) {
+                    Some(link) if !is_attachment(&link) => {
+                        let (url, class) = links.url(&link);
+                        let (open, close) = anchor_tags(url.as_deref(), class, title);
+                        out.push(Event::InlineHtml(open.into()));
+                        closers.push(Some(close));
+                    }
+                    Some(link) => {
+                        out.push(Event::Start(Tag::Image {
+                            link_type: *link_type,
+                            dest_url: links.url(&link).0.unwrap_or_default().into(),
+                            title: title.clone(),
+                            id: id.clone(),
+                        }));
+                        closers.push(None);
+                    }
+                    None => {
+                        out.push(events[i].clone());
+                        closers.push(None);
+                    }
+                }
+            }
+            Event::End(TagEnd::Link | TagEnd::Image) => match closers.pop().flatten() {
+                Some(close) => out.push(Event::InlineHtml(close.into())),
+                None => out.push(events[i].clone()),
+            },
+            event => out.push(event.clone()),
+        }
+        i += 1;
+    }
+
+    let mut html = String::new();
+    html::push_html(&mut html, out.into_iter());
+    sanitize(&html)
+}
+
+/// Stylesheet for highlighted code blocks
+pub fn highlight_css() -> String {
+    ThemeSet::load_defaults()
+        .themes
+        .get(HIGHLIGHT_THEME)
+        .and_then(|theme| css_for_theme_with_class_style(theme, CLASS_STYLE).ok())
+        .unwrap_or_default()
+}
+
+/// File behind a path requested over the attachment protocol
+pub fn attachment_file(root: &Path, uri_path: &str) -> Result<PathBuf, FileError> {
+    files::resolve_attachment(root, &percent_decode(uri_path.trim_start_matches('/')))
+}
+
+/// MIME type to serve an attachment with
+pub fn content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "bmp" => "image/bmp",
+        "ico" => "image/x-icon",
+        "svg" => "image/svg+xml",
+        "pdf" => "application/pdf",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "ogg" => "audio/ogg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "txt" | "md" | "csv" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Looks up where links lead, listing the vault's files the first time an
+/// attachment needs finding
+struct Resolver<'a, U> {
+    index: &'a LinkIndex,
+    note: &'a str,
+    urls: &'a U,
+    files: Option<BTreeSet<String>>,
+}
+
+impl<U: LinkUrls> Resolver<'_, U> {
+    /// URL and class of a link
+    fn url(&mut self, link: &Link) -> (Option<String>, &'static str) {
+        if is_attachment(link) {
+            let root = self.index.root();
+            let files = self.files.get_or_insert_with(|| {
+                walk_files(root)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|p| note_id(root, p))
+                    .collect()
+            });
+            return match find_attachment(files, self.note, link) {
+                Some(file) => (Some(self.urls.attachment(self.note, &file)), "attachment"),
+                None => (
+                    Some(
+                        self.urls
+                            .attachment(self.note, &guess_path(self.note, link)),
+                    ),
+                    "attachment missing",
+                ),
+            };
+        }
+
+        let anchor = link.heading.as_deref().and_then(anchor);
+        match self.index.resolve(self.note, link) {
+            Some(target) => (
+                self.urls.note(self.note, &target, anchor.as_deref()),
+                "note-link",
+            ),
+            None => (
+                self.urls
+                    .note(self.note, &guess_path(self.note, link), anchor.as_deref()),
+                "note-link missing",
+            ),
+        }
+    }
+}
+
+/// The link between notes or to an attachment, None for links out of the vault
+fn to_link(link_type: LinkType, dest: &str) -> Option<Link> {
+    let kind = match link_type {
+        LinkType::WikiLink { .. } => LinkKind::Wiki,
+        LinkType::Autolink | LinkType::Email => return None,
+        _ if dest.is_empty() || dest.contains("://") || dest.starts_with("mailto:") => return None,
+        _ => LinkKind::Markdown,
+    };
+    let (target, heading) = match dest.split_once('#') {
+        Some((t, h)) if kind == LinkKind::Markdown => (t, Some(percent_decode(h))),
+        Some((t, h)) => (t.trim(), Some(h.trim().to_string())),
+        None => (dest, None),
+    };
+    Some(Link {
+        kind,
+        embed: false,
+        target: target.to_string(),
+        heading,
+        alias: None,
+        line: 0,
+        column: 0,
+        target_range: 0..0,
+    })
+}
+
+/// Slug of the heading a link points at. Nested wikilink headings (`#Part#Sub`)
+/// use the last one, block references (`#^id`) have none.
+fn anchor(heading: &str) -> Option<String> {
+    let heading = heading.rsplit('#').next().unwrap_or(heading);
+    if heading.is_empty() || heading.starts_with('^') {
+        return None;
+    }
+    Some(slug(heading))
+}
+
+/// Where a link that doesn't resolve would lead, if the file existed
+fn guess_path(note: &str, link: &Link) -> String {
+    match link.kind {
+        LinkKind::Wiki => {
+            let target = link.target.trim_start_matches('/');
+            if is_attachment(link) || target.ends_with(&format!(".{}", NOTE_EXT)) {
+                target.to_string()
+            } else {
+                format!("{}.{}", target, NOTE_EXT)
+            }
+        }
+        LinkKind::Markdown => {
+            let target = percent_decode(&link.target);
+            match target.strip_prefix('/') {
+                Some(abs) => join("", abs),
+                None => join(folder(note), &target),
+            }
+            .unwrap_or(target)
+        }
+    }
+}
+
+/// Opening and closing tags for a link, a span when it has no URL
+fn anchor_tags(url: Option<&str>, class: &str, title: &str) -> (String, &'static str) {
+    let title = match title.is_empty() {
+        true => String::new(),
+        false => format!(" title=\"{}\"", escape(title)),
+    };
+    match url {
+        Some(url) => (
+            format!("<a href=\"{}\" class=\"{}\"{}>", escape(url), class, title),
+            "</a>",
+        ),
+        None => (format!("<span class=\"{}\"{}>", class, title), "</span>"),
+    }
+}
+
+fn highlight(code: &str, lang: Option<&str>) -> String {
+    let syntaxes = SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines);
+    let syntax = lang
+        .and_then(|l| syntaxes.find_syntax_by_token(l))
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+    let class = lang
+        .map(|l| format!(" class=\"language-{}\"", escape(l)))
+        .unwrap_or_default();
+
+    let mut html = ClassedHTMLGenerator::new_with_class_style(syntax, syntaxes, CLASS_STYLE);
+    for line in LinesWithEndings::from(code) {
+        if html
+            .parse_html_for_line_which_includes_newline(line)
+            .is_err()
+        {
+            return format!(
+                "<pre class=\"hl-code\"><code{}>{}</code></pre>\n",
+                class,
+                escape(code)
+            );
+        }
+    }
+    format!(
+        "<pre class=\"hl-code\"><code{}>{}</code></pre>\n",
+        class,
+        html.finalize()
+    )
+}
+
+/// Strips scripts, event handlers and anything else that could run in the app.
+/// Ids get a prefix, and links within the page follow.
+fn sanitize(html: &str) -> String {
+    ammonia::Builder::default()
+        .add_tags(["input"])
+        .add_tag_attributes("input", ["type", "checked", "disabled"])
+        .add_generic_attributes(["id", "class", "style"])
+        .filter_style_properties(HashSet::from(["text-align"]))
+        .add_url_schemes([NOTE_SCHEME, ATTACHMENT_SCHEME])
+        .id_prefix(Some(ANCHOR_PREFIX))
+        .attribute_filter(|element, attribute, value| match (element, attribute) {
+            ("input", "type") if value != "checkbox" => None,
+            (_, "href") if value.starts_with('#') => {
+                Some(format!("#{}{}", ANCHOR_PREFIX, &value[1..]).into())
+            }
+            _ => Some(Cow::Borrowed(value)),
+        })
+        .clean(html)
+        .to_string()
+}
+
+/// Escapes text for HTML, including attribute values
+pub(crate) fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Percent-encodes a path for a URL, keeping the slashes
+pub(crate) fn encode_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(b as char)
+            }
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_render_html() {
+        let root = std::env::temp_dir().join(format!("tem-render-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("img")).unwrap();
+        let write = |note: &str, content: &str| fs::write(root.join(note), content).unwrap();
+        write("img/my chart.png", "");
+        write("plan.md", "## Goals");
+        let content = "# Notes\n\
+            See [[Plan#Goals|the goals]], [[Gone]] and [the top](#notes)[^1].\n\n\
+            ![[my chart.png]] [site](https://example.com)\n\n\
+            - [x] done\n\n\
+            ```rust\nlet x = 1;\n```\n\n\
+            <script>alert(1)</script><a href=\"javascript:alert(1)\" onclick=\"x()\">bad</a>\n\n\
+            [^1]: A footnote.\n";
+        write("index.md", content);
+
+        let index = LinkIndex::scan(&root).unwrap();
+        let html = render_html(&index, "index.md", content, &AppUrls);
+        for expected in [
+            "<h1 id=\"tem-notes\">Notes</h1>",
+            "<a href=\"note:plan.md#goals\" class=\"note-link\" rel=\"noopener noreferrer\">the goals</a>",
+            "<a href=\"note:Gone.md\" class=\"note-link missing\" rel=\"noopener noreferrer\">Gone</a>",
+            "<a href=\"note:index.md#notes\" class=\"note-link\"",
+            "<a href=\"#tem-1\" rel=\"noopener noreferrer\">1</a>",
+            "id=\"tem-1\"",
+            "my%20chart.png\" alt=\"my chart.png\">",
+            "<a href=\"https://example.com\" rel=\"noopener noreferrer\">site</a>",
+            "<input disabled=\"\" type=\"checkbox\" checked=\"\">",
+            "<pre class=\"hl-code\"><code class=\"language-rust\"><span class=\"hl-source hl-rust\">",
+        ] {
+            assert!(html.contains(expected), "{} not in {}", expected, html);
+        }
+        assert!(!html.contains("script"));
+        assert!(!html.contains("javascript"));
+        assert!(!html.contains("onclick"));
+        assert!(highlight_css().contains(".hl-code"));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
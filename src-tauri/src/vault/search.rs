@@ -0,0 +1,511 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::temsync::tokenizer::parse_string_tokens;
+
+use super::{files::write_atomic, note_id, read_scanned, walk_notes, NoteIndex};
+
+/// Where the index is kept between launches, relative to the vault root
+pub const SEARCH_INDEX_FILE: &str = ".tem/search.json";
+
+/// Words shown around the first match in a snippet
+const SNIPPET_BEFORE: usize = 8;
+const SNIPPET_AFTER: usize = 24;
+
+/// BM25 parameters
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexedNote {
+    /// Modification time when indexed, in milliseconds since the epoch
+    modified: u64,
+    /// Number of words
+    len: usize,
+    /// Distinct words, to find the postings when the note goes away
+    terms: Vec<String>,
+}
+
+/// Piece of a snippet, highlighted if it matched the query
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SnippetPart {
+    pub text: String,
+    pub highlight: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchHit {
+    pub note: String,
+    pub score: f64,
+    pub snippet: Vec<SnippetPart>,
+}
+
+/// Inverted index over the words of every note. Words are the alphanumeric
+/// tokens of the temsync tokenizer, lowercased, so they match what diffs see.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SearchIndex {
+    #[serde(skip)]
+    root: PathBuf,
+    /// Set when the index changed since it was loaded or saved
+    #[serde(skip)]
+    dirty: bool,
+    notes: BTreeMap<String, IndexedNote>,
+    /// Positions of each word in each note, by word number
+    postings: BTreeMap<String, BTreeMap<String, Vec<u32>>>,
+}
+
+impl SearchIndex {
+    /// Creates an empty index for the vault
+    pub fn new(root: &Path) -> Self {
+        SearchIndex {
+            root: root.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    /// Indexes every note in the vault from scratch
+    pub fn scan(root: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut index = SearchIndex::new(root);
+        index.refresh()?;
+        Ok(index)
+    }
+
+    /// Loads the index saved in the vault, then re-indexes whatever changed
+    /// while the app was closed. A missing file gives a fresh scan.
+    pub fn open(root: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut index = match fs::read_to_string(root.join(SEARCH_INDEX_FILE)) {
+            Ok(raw) => serde_json::from_str::<SearchIndex>(&raw)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => SearchIndex::default(),
+            Err(e) => return Err(e.into()),
+        };
+        index.root = root.to_path_buf();
+        index.refresh()?;
+        Ok(index)
+    }
+
+    /// Writes the index to the vault, if anything changed
+    pub fn save(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let path = self.root.join(SEARCH_INDEX_FILE);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomic(&path, &serde_json::to_string(self)?)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Re-indexes notes modified since they were indexed and drops the ones that are gone
+    fn refresh(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut seen = BTreeSet::<String>::new();
+        for path in walk_notes(&self.root)?.iter() {
+            let note = match note_id(&self.root, path) {
+                Some(n) => n,
+                None => continue,
+            };
+            let stale = match self.notes.get(&note) {
+                Some(indexed) => indexed.modified != modified_ms(path),
+                None => true,
+            };
+            // Unreadable notes are dropped with the deleted ones
+            if stale {
+                match read_scanned(path) {
+                    Some(content) => self.update_note(&note, &content),
+                    None => continue,
+                }
+            }
+            seen.insert(note);
+        }
+
+        let gone = self
+            .notes
+            .keys()
+            .filter(|n| !seen.contains(*n))
+            .cloned()
+            .collect::<Vec<String>>();
+        for note in gone.iter() {
+            self.remove_note(note);
+        }
+        Ok(())
+    }
+
+    /// Re-indexes a single note after it changed
+    pub fn update_note(&mut self, note: &str, content: &str) {
+        self.remove_note(note);
+
+        let words = words(content);
+        let len = words.len();
+        for (pos, (_, word)) in words.iter().enumerate() {
+            self.postings
+                .entry(word.clone())
+                .or_default()
+                .entry(note.to_string())
+                .or_default()
+                .push(pos as u32);
+        }
+        let terms = words
+            .into_iter()
+            .map(|(_, w)| w)
+            .collect::<BTreeSet<String>>();
+        self.notes.insert(
+            note.to_string(),
+            IndexedNote {
+                modified: modified_ms(&self.root.join(note)),
+                len,
+                terms: terms.into_iter().collect(),
+            },
+        );
+        self.dirty = true;
+    }
+
+    /// Drops a note from the index
+    pub fn remove_note(&mut self, note: &str) {
+        let old = match self.notes.remove(note) {
+            Some(n) => n,
+            None => return,
+        };
+        for term in old.terms.iter() {
+            if let Some(notes) = self.postings.get_mut(term) {
+                notes.remove(note);
+                if notes.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// Notes matching every part of the query, best first. Quoted parts are
+    /// phrases, a trailing `*` matches any word starting with what's before it.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<SearchHit> {
+        let phrases = parse_search(query);
+        if phrases.is_empty() || self.notes.is_empty() {
+            return vec![];
+        }
+
+        // Start positions of each phrase in every note it appears in
+        let matches = phrases
+            .iter()
+            .map(|p| self.phrase_matches(p))
+            .collect::<Vec<BTreeMap<&str, Vec<u32>>>>();
+
+        let total = self.notes.len() as f64;
+        let avg_len = self.notes.values().map(|n| n.len).sum::<usize>() as f64 / total;
+        let mut scored = Vec::<(f64, &str)>::new();
+        for (note, info) in self.notes.iter() {
+            let norm = 1.0 - B + B * info.len as f64 / avg_len.max(1.0);
+            let score = matches
+                .iter()
+                .map(|found| {
+                    let tf = found.get(note.as_str())?.len() as f64;
+                    let df = found.len() as f64;
+                    let idf = (1.0 + (total - df + 0.5) / (df + 0.5)).ln();
+                    Some(idf * tf * (K1 + 1.0) / (tf + K1 * norm))
+                })
+                .sum::<Option<f64>>();
+            if let Some(score) = score {
+                scored.push((score, note));
+            }
+        }
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(b.1)));
+
+        scored
+            .into_iter()
+            .take(limit)
+            .map(|(score, note)| {
+                let mut highlighted = BTreeSet::<u32>::new();
+                for (phrase, found) in phrases.iter().zip(matches.iter()) {
+                    for start in found.get(note).into_iter().flatten() {
+                        highlighted.extend(*start..*start + phrase.len() as u32);
+                    }
+                }
+                let content = fs::read_to_string(self.root.join(note)).unwrap_or_default();
+                SearchHit {
+                    note: note.to_string(),
+                    score,
+                    snippet: snippet(&content, &highlighted),
+                }
+            })
+            .collect()
+    }
+
+    /// Where the phrase starts in each note that has it
+    fn phrase_matches(&self, phrase: &[QueryWord]) -> BTreeMap<&str, Vec<u32>> {
+        // Positions of each word of the phrase, by note
+        let positions = phrase
+            .iter()
+            .map(|word| {
+                let mut found = BTreeMap::<&str, BTreeSet<u32>>::new();
+                for notes in self.expand(word) {
+                    for (note, pos) in notes.iter() {
+                        found.entry(note.as_str()).or_default().extend(pos);
+                    }
+                }
+                found
+            })
+            .collect::<Vec<BTreeMap<&str, BTreeSet<u32>>>>();
+
+        let mut out = BTreeMap::<&str, Vec<u32>>::new();
+        let (first, rest) = match positions.split_first() {
+            Some(p) => p,
+            None => return out,
+        };
+        for (note, starts) in first.iter() {
+            let starts = starts
+                .iter()
+                .filter(|start| {
+                    rest.iter().enumerate().all(|(i, word)| {
+                        word.get(note)
+                            .is_some_and(|pos| pos.contains(&(**start + i as u32 + 1)))
+                    })
+                })
+                .copied()
+                .collect::<Vec<u32>>();
+            if !starts.is_empty() {
+                out.insert(note, starts);
+            }
+        }
+        out
+    }
+
+    /// Postings of every indexed word the query word stands for
+    fn expand(&self, word: &QueryWord) -> Vec<&BTreeMap<String, Vec<u32>>> {
+        if word.prefix {
+            self.postings
+                .range(word.text.clone()..)
+                .take_while(|(term, _)| term.starts_with(&word.text))
+                .map(|(_, notes)| notes)
+                .collect()
+        } else {
+            self.postings.get(&word.text).into_iter().collect()
+        }
+    }
+}
+
+impl NoteIndex for SearchIndex {
+    fn update_note(&mut self, note: &str, content: &str) {
+        SearchIndex::update_note(self, note, content)
+    }
+
+    fn remove_note(&mut self, note: &str) {
+        SearchIndex::remove_note(self, note)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct QueryWord {
+    text: String,
+    prefix: bool,
+}
+
+/// Splits a search into phrases. Unquoted text that the tokenizer splits into
+/// several words (eg. `don't`) is a phrase too.
+fn parse_search(input: &str) -> Vec<Vec<QueryWord>> {
+    let mut chunks = Vec::<&str>::new();
+    let mut rest = input;
+    while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+        rest = &rest[start..];
+        let end = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.find('"').map_or(rest.len(), |i| i + 2),
+            None => rest.find(char::is_whitespace).unwrap_or(rest.len()),
+        };
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+
+    chunks
+        .into_iter()
+        .filter_map(|chunk| {
+            let prefix = chunk.ends_with('*');
+            let mut phrase = words(chunk)
+                .into_iter()
+                .map(|(_, text)| QueryWord {
+                    text,
+                    prefix: false,
+                })
+                .collect::<Vec<QueryWord>>();
+            if let Some(last) = phrase.last_mut() {
+                last.prefix = prefix;
+            }
+            Some(phrase).filter(|p| !p.is_empty())
+        })
+        .collect()
+}
+
+/// Byte range and lowercased text of every word in the content
+fn words(content: &str) -> Vec<(Range<usize>, String)> {
+    let mut out = Vec::<(Range<usize>, String)>::new();
+    let mut offset = 0;
+    for token in parse_string_tokens(content).unwrap_or_default().iter() {
+        let range = offset..offset + token.value.len();
+        offset = range.end;
+        if token
+            .value
+            .chars()
+            .next()
+            .is_some_and(char::is_alphanumeric)
+        {
+            out.push((range, token.value.to_lowercase()));
+        }
+    }
+    out
+}
+
+/// A few words around the first highlighted word, on one line
+fn snippet(content: &str, highlighted: &BTreeSet<u32>) -> Vec<SnippetPart> {
+    let words = words(content);
+    let first = highlighted.first().map_or(0, |p| *p as usize);
+    if first >= words.len() {
+        return vec![];
+    }
+    let start = first.saturating_sub(SNIPPET_BEFORE);
+    let end = (first + SNIPPET_AFTER).min(words.len());
+
+    let mut parts = Vec::<SnippetPart>::new();
+    let mut push = |text: &str, highlight: bool| {
+        // Line breaks and indentation become single spaces
+        let mut text = text.to_string();
+        while let Some(i) = text.find(|c: char| c.is_whitespace() && c != ' ') {
+            text.replace_range(i..i + text[i..].chars().next().unwrap().len_utf8(), " ");
+        }
+        while text.contains("  ") {
+            text = text.replace("  ", " ");
+        }
+        match parts.last_mut() {
+            Some(last) if last.highlight == highlight => last.text.push_str(&text),
+            _ => parts.push(SnippetPart { text, highlight }),
+        }
+    };
+
+    if start > 0 {
+        push("…", false);
+    }
+    let mut at = words[start].0.start;
+    for (pos, (range, _)) in words.iter().enumerate().take(end).skip(start) {
+        if highlighted.contains(&(pos as u32)) {
+            // Spaces inside a highlighted phrase stay highlighted
+            let joined = pos > start && highlighted.contains(&(pos as u32 - 1));
+            let gap = &content[at..range.start];
+            if !gap.is_empty() {
+                push(gap, joined && gap.trim().is_empty());
+            }
+            push(&content[range.clone()], true);
+            at = range.end;
+        }
+    }
+    if end < words.len() {
+        push(&content[at..words[end - 1].0.end], false);
+        push("…", false);
+    } else {
+        push(content[at..].trim_end(), false);
+    }
+    parts.retain(|p| !p.text.is_empty());
+    parts
+}
+
+fn modified_ms(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vault(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("tem-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn notes(hits: &[SearchHit]) -> Vec<&str> {
+        hits.iter().map(|h| h.note.as_str()).collect()
+    }
+
+    #[test]
+    fn test_parse_search() {
+        let word = |text: &str, prefix: bool| QueryWord {
+            text: text.into(),
+            prefix,
+        };
+        assert_eq!(
+            parse_search(r#" Rust "red car" ate* don't "#),
+            vec![
+                vec![word("rust", false)],
+                vec![word("red", false), word("car", false)],
+                vec![word("ate", true)],
+                vec![word("don", false), word("t", false)],
+            ]
+        );
+        assert_eq!(parse_search(r#""unclosed phrase"#).len(), 1);
+        assert!(parse_search(" * \"\" ").is_empty());
+    }
+
+    #[test]
+    fn test_search() {
+        let root = vault("search");
+        fs::write(
+            root.join("a.md"),
+            "A red car ate my dog. 🚗 Red cars are fast.",
+        )
+        .unwrap();
+        fs::write(root.join("b.md"), "My car is red, my carpet is not.").unwrap();
+        fs::create_dir_all(root.join("c")).unwrap();
+        fs::write(root.join("c/d.md"), "Nothing to see here.").unwrap();
+        let mut index = SearchIndex::scan(&root).unwrap();
+
+        // Every part has to match, phrases have to be in order
+        assert_eq!(notes(&index.search("car red", 10)), vec!["a.md", "b.md"]);
+        assert_eq!(notes(&index.search("\"red car\"", 10)), vec!["a.md"]);
+        assert_eq!(notes(&index.search("\"car red\"", 10)), Vec::<&str>::new());
+        assert_eq!(notes(&index.search("carp*", 10)), vec!["b.md"]);
+        assert_eq!(notes(&index.search("CAR*", 1)), vec!["b.md"]);
+        assert_eq!(notes(&index.search("fast", 10)), vec!["a.md"]);
+        assert_eq!(notes(&index.search("\"dog red\"", 10)), vec!["a.md"]);
+
+        let hits = index.search("\"red car\"", 10);
+        assert_eq!(
+            hits[0].snippet,
+            vec![
+                SnippetPart {
+                    text: "A ".into(),
+                    highlight: false
+                },
+                SnippetPart {
+                    text: "red car".into(),
+                    highlight: true
+                },
+                SnippetPart {
+                    text: " ate my dog. 🚗 Red cars are fast.".into(),
+                    highlight: false
+                },
+            ]
+        );
+
+        // Updates are incremental, and the saved index catches up with changes on disk
+        index.update_note("c/d.md", "A red car!");
+        assert_eq!(notes(&index.search("\"red car\"", 10)).len(), 2);
+        index.save().unwrap();
+        fs::remove_file(root.join("a.md")).unwrap();
+        let index = SearchIndex::open(&root).unwrap();
+        assert_eq!(notes(&index.search("red", 10)), vec!["c/d.md", "b.md"]);
+        assert!(index.search("dog", 10).is_empty());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,469 @@
+//! Read-only sharing of notes over HTTP. The app starts a server on a local
+//! port and each share gets a random token, with the notes it picked served as
+//! the same pages a static export writes under `/s/<token>/`. Open pages reload
+//! through server-sent events when their note is saved.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fs,
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response};
+
+use super::{
+    export::{
+        index_page, note_page, page_path, select_notes, site_css, tags_page, ExportSelection,
+        SiteUrls, NOTES_DIR,
+    },
+    files,
+    links::{percent_decode, LinkIndex},
+    render::{content_type, escape},
+    tags::TagIndex,
+};
+
+/// Path every share is served under, followed by its token
+pub const SHARE_PREFIX: &str = "/s/";
+
+/// Comments are sent this often on an idle event stream, to find readers that left
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Shared pages run no scripts but the live reload one, and only load files from the share
+const PAGE_CSP: &str = "default-src 'self'; img-src * data:; style-src 'self' 'unsafe-inline'";
+
+/// Reloads the page when its note is saved, or on any save for the index and tag pages
+const LIVE_JS: &str = "const script = document.currentScript;
+const events = new EventSource(script.src.replace(/live\\.js$/, \"events\"));
+events.addEventListener(\"update\", (event) => {
+    const note = script.dataset.note;
+    if (note === undefined || note === event.data) location.reload();
+});
+";
+
+/// A share as the app shows it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ShareInfo {
+    pub token: String,
+    pub notes: Vec<String>,
+    pub url: String,
+}
+
+/// Pages of one share, rendered whenever one of its notes changes
+struct Share {
+    selection: ExportSelection,
+    notes: Vec<String>,
+    /// Note pages by their path under the notes folder
+    pages: BTreeMap<String, String>,
+    index: String,
+    tags: String,
+    attachments: BTreeSet<String>,
+    /// Open event streams, dropped once their reader goes away
+    listeners: Vec<Sender<String>>,
+}
+
+impl Share {
+    fn new(selection: ExportSelection) -> Self {
+        Share {
+            selection,
+            notes: Vec::new(),
+            pages: BTreeMap::new(),
+            index: String::new(),
+            tags: String::new(),
+            attachments: BTreeSet::new(),
+            listeners: Vec::new(),
+        }
+    }
+
+    /// Renders every page from the notes the selection picks now. Notes that
+    /// can't be read, eg. ones deleted since they were indexed, are left out.
+    fn render(&mut self, tags: &TagIndex, links: &LinkIndex, notes: Vec<String>, token: &str) {
+        let notes = notes
+            .into_iter()
+            .filter_map(|n| {
+                fs::read_to_string(links.root().join(&n))
+                    .ok()
+                    .map(|c| (n, c))
+            })
+            .collect::<Vec<(String, String)>>();
+        let names = notes
+            .iter()
+            .map(|(n, _)| n.clone())
+            .collect::<Vec<String>>();
+
+        let urls = SiteUrls::new(&names);
+        let mut pages = BTreeMap::new();
+        for (note, content) in notes.iter() {
+            let head = live_script(token, Some(note));
+            let html = note_page(tags, links, note, content, &urls, &head);
+            pages.insert(page_path(note), html);
+        }
+
+        let head = live_script(token, None);
+        self.index = index_page(&names, &head);
+        self.tags = tags_page(tags, &names, &head);
+        self.pages = pages;
+        self.attachments = urls.attachments.into_inner();
+        self.notes = names;
+    }
+
+    /// Tells every open page about a saved note
+    fn notify(&mut self, note: &str) {
+        self.listeners.retain(|l| l.send(note.to_string()).is_ok());
+    }
+}
+
+type Shares = Arc<Mutex<BTreeMap<String, Share>>>;
+
+/// Server for the shared notes, read-only and without any state of its own
+/// beyond the rendered pages
+pub struct ShareServer {
+    server: Arc<tiny_http::Server>,
+    root: PathBuf,
+    shares: Shares,
+}
+
+impl ShareServer {
+    /// Binds the server to an address like `127.0.0.1:8080` (use port 0 to pick a
+    /// free one). Attachments are read from the vault at root.
+    pub fn bind(addr: &str, root: &Path) -> Result<Self, Box<dyn Error>> {
+        let server = tiny_http::Server::http(addr).map_err(|e| e.to_string())?;
+        Ok(ShareServer {
+            server: Arc::new(server),
+            root: root.to_path_buf(),
+            shares: Arc::new(Mutex::new(BTreeMap::new())),
+        })
+    }
+
+    /// Address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server
+            .server_addr()
+            .to_ip()
+            .expect("share server should be bound to an ip address")
+    }
+
+    /// Serves requests until the server is shut down
+    pub fn run(&self) {
+        for request in self.server.incoming_requests() {
+            handle_request(&self.root, &self.shares, request);
+        }
+    }
+
+    /// Serves requests on a background thread
+    pub fn spawn(self) -> ShareHandle {
+        let addr = self.local_addr();
+        let server = self.server.clone();
+        let shares = self.shares.clone();
+        let thread = thread::spawn(move || self.run());
+        ShareHandle {
+            addr,
+            server,
+            shares,
+            thread,
+        }
+    }
+}
+
+/// Handle to a share server running on a background thread, used to add and
+/// remove shares and to keep them up to date
+pub struct ShareHandle {
+    addr: SocketAddr,
+    server: Arc<tiny_http::Server>,
+    shares: Shares,
+    thread: JoinHandle<()>,
+}
+
+impl ShareHandle {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Shares the notes a selection picks under a new token. Query selections
+    /// are run again as notes change, so notes can join or leave the share.
+    pub fn share(
+        &self,
+        tags: &TagIndex,
+        links: &LinkIndex,
+        selection: &ExportSelection,
+    ) -> Result<ShareInfo, Box<dyn Error>> {
+        let notes = select_notes(tags, links, selection)?;
+        let token = new_token();
+        let mut share = Share::new(selection.clone());
+        share.render(tags, links, notes, &token);
+
+        let info = self.info(&token, &share);
+        self.shares.lock().unwrap().insert(token, share);
+        Ok(info)
+    }
+
+    /// Stops serving a share, closing its event streams. Returns false if there was no such share.
+    pub fn unshare(&self, token: &str) -> bool {
+        self.shares.lock().unwrap().remove(token).is_some()
+    }
+
+    /// Every share being served
+    pub fn shares(&self) -> Vec<ShareInfo> {
+        let shares = self.shares.lock().unwrap();
+        shares.iter().map(|(t, s)| self.info(t, s)).collect()
+    }
+
+    /// Renders the shares a note is or was in again and reloads its open pages.
+    /// Call it after the indexes have caught up with a saved, moved or deleted note.
+    pub fn refresh(&self, tags: &TagIndex, links: &LinkIndex, note: &str) {
+        let mut shares = self.shares.lock().unwrap();
+        for (token, share) in shares.iter_mut() {
+            // A share keeps its last pages if the selection can't be run
+            let notes = match select_notes(tags, links, &share.selection) {
+                Ok(notes) => notes,
+                Err(_) => continue,
+            };
+            if !share.notes.iter().chain(notes.iter()).any(|n| n == note) {
+                continue;
+            }
+            share.render(tags, links, notes, token);
+            share.notify(note);
+        }
+    }
+
+    /// Stops accepting requests, closes the event streams and waits for the server thread to exit
+    pub fn shutdown(self) {
+        self.shares.lock().unwrap().clear();
+        self.server.unblock();
+        let _ = self.thread.join();
+    }
+
+    fn info(&self, token: &str, share: &Share) -> ShareInfo {
+        ShareInfo {
+            token: token.to_string(),
+            notes: share.notes.clone(),
+            url: format!("http://{}{}{}/", self.addr, SHARE_PREFIX, token),
+        }
+    }
+}
+
+/// Random token for a share, long enough that it can't be guessed
+fn new_token() -> String {
+    let mut raw = [0u8; 32];
+    OsRng.fill_bytes(&mut raw);
+    URL_SAFE_NO_PAD.encode(raw)
+}
+
+/// Script tag for the live reload script, for one note's page or any page
+fn live_script(token: &str, note: Option<&str>) -> String {
+    let note = note
+        .map(|n| format!(" data-note=\"{}\"", escape(n)))
+        .unwrap_or_default();
+    format!(
+        "<script src=\"{}{}/live.js\"{}></script>\n",
+        SHARE_PREFIX, token, note
+    )
+}
+
+/// What a request resolved to, worked out while the shares are locked
+enum Reply {
+    Page(String),
+    File(&'static str, Vec<u8>),
+    Events(Receiver<String>),
+    NotFound,
+}
+
+fn handle_request(root: &Path, shares: &Mutex<BTreeMap<String, Share>>, request: Request) {
+    let reply = match request.method() {
+        Method::Get => route(root, shares, request.url()),
+        _ => Reply::NotFound,
+    };
+
+    let header = |field: &str, value: &str| {
+        Header::from_bytes(field, value).expect("response header should be valid")
+    };
+    let response = match reply {
+        Reply::Events(events) => {
+            thread::spawn(move || stream_events(request, events));
+            return;
+        }
+        Reply::Page(html) => Response::from_string(html)
+            .with_header(header("Content-Type", "text/html; charset=utf-8"))
+            .with_header(header("Content-Security-Policy", PAGE_CSP)),
+        // Attachments are sandboxed so scripts in an SVG can't run if one is opened directly
+        Reply::File(kind, data) => Response::from_data(data)
+            .with_header(header("Content-Type", kind))
+            .with_header(header("Content-Security-Policy", "sandbox")),
+        Reply::NotFound => Response::from_string("not found")
+            .with_status_code(404)
+            .with_header(header("Content-Type", "text/plain")),
+    };
+
+    // The reader may have gone away already, nothing to do about it here
+    let _ = request.respond(response);
+}
+
+/// Finds what a url under `/s/<token>/` points to. Attachments are only served
+/// if a page of the share uses them.
+fn route(root: &Path, shares: &Mutex<BTreeMap<String, Share>>, url: &str) -> Reply {
+    let path = url.split(['?', '#']).next().unwrap_or("");
+    let (token, rest) = match path
+        .strip_prefix(SHARE_PREFIX)
+        .and_then(|p| p.split_once('/'))
+    {
+        Some(parts) => parts,
+        None => return Reply::NotFound,
+    };
+
+    let mut shares = shares.lock().unwrap();
+    let share = match shares.get_mut(token) {
+        Some(share) => share,
+        None => return Reply::NotFound,
+    };
+    match rest {
+        "" | "index.html" => Reply::Page(share.index.clone()),
+        "tags.html" => Reply::Page(share.tags.clone()),
+        "style.css" => Reply::File("text/css", site_css().into_bytes()),
+        "live.js" => Reply::File("text/javascript", LIVE_JS.as_bytes().to_vec()),
+        "events" => {
+            let (sender, events) = mpsc::channel();
+            share.listeners.push(sender);
+            Reply::Events(events)
+        }
+        rest => {
+            let file = match rest
+                .strip_prefix(&format!("{}/", NOTES_DIR))
+                .map(percent_decode)
+            {
+                Some(file) => file,
+                None => return Reply::NotFound,
+            };
+            if let Some(page) = share.pages.get(&file) {
+                return Reply::Page(page.clone());
+            }
+            if !share.attachments.contains(&file) {
+                return Reply::NotFound;
+            }
+            match files::resolve_attachment(root, &file).and_then(|p| Ok((fs::read(&p)?, p))) {
+                Ok((data, path)) => Reply::File(content_type(&path), data),
+                Err(_) => Reply::NotFound,
+            }
+        }
+    }
+}
+
+/// Writes saved notes to an event stream until the reader or the share goes
+/// away. The response is written by hand since tiny_http buffers whole bodies.
+fn stream_events(request: Request, events: Receiver<String>) {
+    let mut writer = request.into_writer();
+    let head = "HTTP/1.1 200 OK\r\n\
+                Content-Type: text/event-stream\r\n\
+                Cache-Control: no-cache\r\n\
+                Connection: close\r\n\r\n";
+    let mut message = head.to_string();
+    loop {
+        if writer
+            .write_all(message.as_bytes())
+            .and_then(|_| writer.flush())
+            .is_err()
+        {
+            return;
+        }
+        message = match events.recv_timeout(KEEPALIVE) {
+            Ok(note) => format!("event: update\ndata: {}\n\n", note),
+            Err(RecvTimeoutError::Timeout) => ": ping\n\n".to_string(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpStream,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_share_server() {
+        let root = std::env::temp_dir().join(format!("tem-share-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("pub")).unwrap();
+        let write = |note: &str, content: &str| fs::write(root.join(note), content).unwrap();
+        write("pub/home.md", "# Home\n![[a.png]] [[secret]]");
+        write("pub/a.png", "png");
+        write("pub/b.png", "other");
+        write("secret.md", "private");
+
+        let tags = TagIndex::scan(&root).unwrap();
+        let mut links = LinkIndex::scan(&root).unwrap();
+        let handle = ShareServer::bind("127.0.0.1:0", &root).unwrap().spawn();
+        let info = handle
+            .share(&tags, &links, &ExportSelection::Folder("pub".into()))
+            .unwrap();
+        assert_eq!(info.notes, vec!["pub/home.md"]);
+
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .build()
+            .into();
+        let get = |url: String| -> (u16, String) {
+            let mut response = agent.get(&url).call().expect("request failed");
+            let body = response.body_mut().read_to_string().unwrap_or_default();
+            (response.status().as_u16(), body)
+        };
+        let (status, page) = get(format!("{}notes/pub/home.html", info.url));
+        assert_eq!(status, 200);
+        assert!(page.contains("<span class=\"note-link\">secret</span>"));
+        assert_eq!(
+            get(format!("{}notes/pub/a.png", info.url)),
+            (200, "png".into())
+        );
+        // Only files the pages use are served, and only under a known token
+        assert_eq!(get(format!("{}notes/pub/b.png", info.url)).0, 404);
+        assert_eq!(get(format!("{}notes/secret.html", info.url)).0, 404);
+        let other = format!("http://{}{}nope/index.html", handle.addr(), SHARE_PREFIX);
+        assert_eq!(get(other).0, 404);
+
+        // Saving a note reaches the open event streams
+        let mut stream = TcpStream::connect(handle.addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let request = format!(
+            "GET {}{}/events HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            SHARE_PREFIX, info.token
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+
+        write("pub/home.md", "# Home\nEdited");
+        links.update_note("pub/home.md", "# Home\nEdited");
+        handle.refresh(&tags, &links, "pub/home.md");
+        let mut event = String::new();
+        for _ in 0..2 {
+            reader.read_line(&mut event).unwrap();
+        }
+        assert_eq!(event, "event: update\ndata: pub/home.md\n");
+        let (_, page) = get(format!("{}notes/pub/home.html", info.url));
+        assert!(page.contains("Edited"));
+
+        assert!(handle.unshare(&info.token));
+        assert_eq!(get(info.url.clone()).0, 404);
+        handle.shutdown();
+        let _ = fs::remove_dir_all(&root);
+    }
+}
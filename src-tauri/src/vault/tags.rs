@@ -0,0 +1,495 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use super::{
+    frontmatter::{Frontmatter, PropertyValue},
+    note_id,
+    query::Date,
+    read_scanned, walk_notes, NoteIndex,
+};
+
+/// A tag and how many notes use it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TagCount {
+    pub name: String,
+    pub count: usize,
+}
+
+/// A tag in the nested tag tree. `project/tem/sync` is the `sync` child of
+/// `project/tem`, which is the `tem` child of `project`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TagNode {
+    /// Last segment of the tag
+    pub name: String,
+    /// Full tag, eg. `project/tem/sync`
+    pub path: String,
+    /// Notes with this tag or any tag below it
+    pub count: usize,
+    pub children: Vec<TagNode>,
+}
+
+/// Maps tags to the notes that use them, for every note in a vault
+#[derive(Debug, Default)]
+pub struct TagIndex {
+    root: PathBuf,
+    /// Tags of each note
+    notes: BTreeMap<String, BTreeSet<String>>,
+    /// Notes with each tag
+    tags: BTreeMap<String, BTreeSet<String>>,
+    /// Date fields in the frontmatter of each note, for queries
+    dates: BTreeMap<String, BTreeMap<String, Date>>,
+}
+
+impl TagIndex {
+    /// Creates an empty index for the vault
+    pub fn new(root: &Path) -> Self {
+        TagIndex {
+            root: root.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    /// Reads every note in the vault and indexes its tags
+    pub fn scan(root: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut index = TagIndex::new(root);
+        for path in walk_notes(root)?.iter() {
+            let note = match note_id(root, path) {
+                Some(n) => n,
+                None => continue,
+            };
+            if let Some(content) = read_scanned(path) {
+                index.update_note(&note, &content);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Vault this index covers
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Re-indexes a single note after it changed
+    pub fn update_note(&mut self, note: &str, content: &str) {
+        self.remove_note(note);
+
+        let tags = extract_tags(content);
+        for tag in tags.iter() {
+            self.tags
+                .entry(tag.clone())
+                .or_default()
+                .insert(note.to_string());
+        }
+        self.notes.insert(note.to_string(), tags);
+        self.dates
+            .insert(note.to_string(), frontmatter_dates(content));
+    }
+
+    /// Drops a note from the index
+    pub fn remove_note(&mut self, note: &str) {
+        self.dates.remove(note);
+        let old = match self.notes.remove(note) {
+            Some(t) => t,
+            None => return,
+        };
+        for tag in old.iter() {
+            if let Some(notes) = self.tags.get_mut(tag) {
+                notes.remove(note);
+                if notes.is_empty() {
+                    self.tags.remove(tag);
+                }
+            }
+        }
+    }
+
+    /// Every tag with its note count, sorted by name
+    pub fn tags(&self) -> Vec<TagCount> {
+        self.tags
+            .iter()
+            .map(|(name, notes)| TagCount {
+                name: name.clone(),
+                count: notes.len(),
+            })
+            .collect()
+    }
+
+    /// Every tag as a tree, parents are included even if no note uses them directly
+    pub fn tree(&self) -> Vec<TagNode> {
+        let mut paths = BTreeSet::<&str>::new();
+        for tag in self.tags.keys() {
+            let mut end = tag.len();
+            while let Some(slash) = tag[..end].rfind('/') {
+                paths.insert(&tag[..slash]);
+                end = slash;
+            }
+            paths.insert(tag);
+        }
+        self.subtree(None, &paths)
+    }
+
+    fn subtree(&self, parent: Option<&str>, paths: &BTreeSet<&str>) -> Vec<TagNode> {
+        paths
+            .iter()
+            .filter(|p| parent_tag(p) == parent)
+            .map(|p| TagNode {
+                name: p.rsplit('/').next().unwrap_or(p).to_string(),
+                path: p.to_string(),
+                count: self.notes_for_tag(p).len(),
+                children: self.subtree(Some(p), paths),
+            })
+            .collect()
+    }
+
+    /// Notes that have the tag or any tag nested under it, sorted by path
+    pub fn notes_for_tag(&self, tag: &str) -> Vec<String> {
+        let tag = normalize_tag(tag);
+        let nested = format!("{}/", tag);
+        self.tags
+            .range(tag.clone()..)
+            .take_while(|(t, _)| t.starts_with(&tag))
+            .filter(|(t, _)| **t == tag || t.starts_with(&nested))
+            .flat_map(|(_, notes)| notes.iter().cloned())
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Every indexed note, sorted by path
+    pub fn notes(&self) -> impl Iterator<Item = &str> {
+        self.notes.keys().map(|n| n.as_str())
+    }
+
+    /// Whether the note has the tag or any tag nested under it
+    pub fn has_tag(&self, note: &str, tag: &str) -> bool {
+        let tag = normalize_tag(tag);
+        let nested = format!("{}/", tag);
+        self.notes
+            .get(note)
+            .map(|tags| tags.iter().any(|t| *t == tag || t.starts_with(&nested)))
+            .unwrap_or(false)
+    }
+
+    /// A date field from the note's frontmatter
+    pub fn date(&self, note: &str, field: &str) -> Option<Date> {
+        self.dates.get(note)?.get(field).copied()
+    }
+
+    /// Tags of a single note
+    pub fn tags_for_note(&self, note: &str) -> Vec<String> {
+        match self.notes.get(note) {
+            Some(tags) => tags.iter().cloned().collect(),
+            None => vec![],
+        }
+    }
+}
+
+impl NoteIndex for TagIndex {
+    fn update_note(&mut self, note: &str, content: &str) {
+        TagIndex::update_note(self, note, content)
+    }
+
+    fn remove_note(&mut self, note: &str) {
+        TagIndex::remove_note(self, note)
+    }
+}
+
+/// Finds every tag in a note: the `tags` list in the YAML frontmatter
+/// and inline `#tag`s in the body
+pub fn extract_tags(content: &str) -> BTreeSet<String> {
+    let body = split_frontmatter(content).1;
+    let frontmatter = Frontmatter::parse(content).unwrap_or_default();
+    let mut tags = frontmatter_tags(&frontmatter)
+        .into_iter()
+        .collect::<BTreeSet<String>>();
+    tags.extend(
+        inline_tag_spans(content, content.len() - body.len())
+            .into_iter()
+            .map(|(_, tag)| tag),
+    );
+
+    tags
+}
+
+/// Finds inline `#tag`s from the offset on, returning the byte range of each tag
+/// name (without the #) together with the normalized tag
+pub(crate) fn inline_tag_spans(content: &str, offset: usize) -> Vec<(Range<usize>, String)> {
+    let mut out = Vec::<(Range<usize>, String)>::new();
+    for (_, line_start, line) in prose_lines(content, offset) {
+        out.extend(
+            inline_tags(line)
+                .into_iter()
+                .map(|(r, tag)| (r.start + line_start..r.end + line_start, tag)),
+        );
+    }
+    out
+}
+
+/// Lines from the offset on that aren't in fenced code blocks, without their line
+/// endings. Each comes with its 1-based line number and byte offset.
+pub(crate) fn prose_lines(content: &str, offset: usize) -> Vec<(usize, usize, &str)> {
+    let mut out = Vec::<(usize, usize, &str)>::new();
+    let mut start = offset;
+    let mut number = content[..offset].matches('\n').count();
+
+    let mut fence: Option<&str> = None;
+    for line in content[offset..].split_inclusive('\n') {
+        let line_start = start;
+        start += line.len();
+        number += 1;
+        let line = line.trim_end_matches(['\n', '\r']);
+        let trimmed = line.trim_start();
+
+        match fence {
+            Some(f) => {
+                if trimmed.starts_with(f) {
+                    fence = None;
+                }
+                continue;
+            }
+            None => {
+                if trimmed.starts_with("```") {
+                    fence = Some("```");
+                    continue;
+                }
+                if trimmed.starts_with("~~~") {
+                    fence = Some("~~~");
+                    continue;
+                }
+            }
+        }
+
+        out.push((number, line_start, line));
+    }
+
+    out
+}
+
+/// Splits a note into its frontmatter (without the `---` lines) and the rest
+pub(crate) fn split_frontmatter(content: &str) -> (Option<&str>, &str) {
+    let rest = match content
+        .strip_prefix("---\n")
+        .or_else(|| content.strip_prefix("---\r\n"))
+    {
+        Some(r) => r,
+        None => return (None, content),
+    };
+
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        let end = line.trim_end();
+        if end == "---" || end == "..." {
+            return (Some(&rest[..offset]), &rest[offset + line.len()..]);
+        }
+        offset += line.len();
+    }
+
+    // Never closed, so it's just a horizontal rule
+    (None, content)
+}
+
+/// Reads `tags:` from the frontmatter, as either a list or a comma/space separated string
+fn frontmatter_tags(frontmatter: &Frontmatter) -> Vec<String> {
+    let values = match frontmatter.get("tags") {
+        Some(PropertyValue::List(items)) => items.clone(),
+        Some(PropertyValue::Text(s)) => s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .map(|s| s.to_string())
+            .collect(),
+        Some(PropertyValue::Number(n)) => vec![n.to_string()],
+        _ => vec![],
+    };
+
+    values
+        .iter()
+        .map(|t| normalize_tag(t))
+        .filter(|t| is_valid_tag(t))
+        .collect()
+}
+
+/// Reads every frontmatter field that holds a date (eg. `created: 2024-05-01`)
+pub fn frontmatter_dates(content: &str) -> BTreeMap<String, Date> {
+    Frontmatter::parse(content)
+        .unwrap_or_default()
+        .properties()
+        .iter()
+        .filter_map(|p| match p.value {
+            PropertyValue::Date(d) => Some((p.name.clone(), d)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Finds `#tag`s in a line of text, skipping inline code spans
+fn inline_tags(line: &str) -> Vec<(Range<usize>, String)> {
+    let mut out = Vec::<(Range<usize>, String)>::new();
+    let chars = line.char_indices().collect::<Vec<(usize, char)>>();
+    let mut in_code = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        if c == '`' {
+            in_code = !in_code;
+        }
+
+        // A tag starts with # at the start of a word
+        let at_word_start = i == 0 || chars[i - 1].1.is_whitespace();
+        if in_code || c != '#' || !at_word_start {
+            i += 1;
+            continue;
+        }
+
+        let mut j = i + 1;
+        while j < chars.len() && is_tag_char(chars[j].1) {
+            j += 1;
+        }
+        let end = chars.get(j).map(|(e, _)| *e).unwrap_or(line.len());
+        // Stray trailing slashes aren't part of the tag
+        let raw = line[start + 1..end].trim_end_matches('/');
+        let tag = normalize_tag(raw);
+        if is_valid_tag(&tag) && !raw.starts_with('/') {
+            out.push((start + 1..start + 1 + raw.len(), tag));
+        }
+        i = j.max(i + 1);
+    }
+
+    out
+}
+
+fn is_tag_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '/'
+}
+
+/// Parent of a nested tag, None for top level tags
+fn parent_tag(tag: &str) -> Option<&str> {
+    tag.rfind('/').map(|i| &tag[..i])
+}
+
+/// Strips the leading # and any stray slashes
+pub fn normalize_tag(tag: &str) -> String {
+    tag.trim()
+        .trim_start_matches('#')
+        .trim_matches('/')
+        .to_string()
+}
+
+/// Tags need at least one non-digit, so `#123` (eg. an issue number) isn't a tag
+pub(crate) fn is_valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.chars().all(is_tag_char)
+        && tag.chars().any(|c| !c.is_ascii_digit() && c != '/')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn tags(content: &str) -> Vec<String> {
+        extract_tags(content).into_iter().collect()
+    }
+
+    #[test]
+    fn test_extract_tags() {
+        let note = "---\ntitle: Plans\ntags: [work, \"#ideas\"]\n---\n# Heading\n\nSome #todo items, not#this or #123.\n\n```\n#not-a-tag\n```\nUse `#code` for #later-today\n";
+        assert_eq!(tags(note), vec!["ideas", "later-today", "todo", "work"]);
+
+        // Tags as a plain string, and an unclosed frontmatter block
+        assert_eq!(tags("---\ntags: a, b c\n---\n"), vec!["a", "b", "c"]);
+        assert_eq!(tags("---\nno end\n#tag"), vec!["tag"]);
+    }
+
+    #[test]
+    fn test_index_updates() {
+        let mut index = TagIndex::new(Path::new("/vault"));
+        index.update_note("a.md", "#work and #home");
+        index.update_note("b.md", "#work");
+        assert_eq!(
+            index.tags(),
+            vec![
+                TagCount {
+                    name: "home".into(),
+                    count: 1
+                },
+                TagCount {
+                    name: "work".into(),
+                    count: 2
+                },
+            ]
+        );
+        assert_eq!(index.notes_for_tag("#work"), vec!["a.md", "b.md"]);
+
+        // Editing and removing notes cleans up tags nobody uses anymore
+        index.update_note("a.md", "#work only");
+        assert_eq!(index.notes_for_tag("home"), Vec::<String>::new());
+        index.remove_note("b.md");
+        assert_eq!(index.tags().len(), 1);
+        assert_eq!(index.tags_for_note("a.md"), vec!["work"]);
+    }
+
+    #[test]
+    fn test_nested_tags() {
+        let mut index = TagIndex::new(Path::new("/vault"));
+        index.update_note("a.md", "#project/tem/sync");
+        index.update_note("b.md", "#project/tem #project/tem/ui");
+        index.update_note("c.md", "#project/other #projects");
+
+        // Parents count notes from every descendant, once each
+        let tree = index.tree();
+        assert_eq!(tree.len(), 2);
+        assert_eq!((tree[0].path.as_str(), tree[0].count), ("project", 3));
+        let tem = &tree[0].children[1];
+        assert_eq!(
+            (tem.name.as_str(), tem.path.as_str()),
+            ("tem", "project/tem")
+        );
+        assert_eq!(tem.count, 2);
+        assert_eq!(
+            tem.children
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["sync", "ui"]
+        );
+
+        // Querying a parent includes descendants, but not tags that only share a prefix
+        assert_eq!(index.notes_for_tag("project/tem"), vec!["a.md", "b.md"]);
+        assert_eq!(index.notes_for_tag("project"), vec!["a.md", "b.md", "c.md"]);
+        assert_eq!(index.notes_for_tag("projects"), vec!["c.md"]);
+        assert_eq!(index.notes_for_tag("proj"), Vec::<String>::new());
+
+        // Both take tags as written, with the #
+        assert_eq!(index.notes_for_tag("#project/tem"), vec!["a.md", "b.md"]);
+        assert!(index.has_tag("a.md", "#project/tem"));
+        assert!(!index.has_tag("c.md", "#project/tem"));
+    }
+
+    #[test]
+    fn test_scan_vault() {
+        let root = std::env::temp_dir().join(format!("tem-tags-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("journal")).unwrap();
+        fs::create_dir_all(root.join(".trash")).unwrap();
+        fs::write(root.join("todo.md"), "#work").unwrap();
+        fs::write(root.join("journal/monday.md"), "---\ntags: [work]\n---\n").unwrap();
+        fs::write(root.join(".trash/old.md"), "#work").unwrap();
+        fs::write(root.join("image.png"), "#work").unwrap();
+        // Not UTF-8, skipped without failing the scan
+        fs::write(root.join("binary.md"), [0x23, 0xff, 0xfe]).unwrap();
+
+        let index = TagIndex::scan(&root).expect("scan failed");
+        assert_eq!(
+            index.notes_for_tag("work"),
+            vec!["journal/monday.md", "todo.md"]
+        );
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
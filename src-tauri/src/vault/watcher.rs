@@ -0,0 +1,434 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use serde::Serialize;
+
+use crate::temsync::{
+    merge::merge_tokens, patch::tokens_to_string, tokenizer::parse_string_tokens,
+};
+
+use super::{note_id, NoteIndex, NOTE_EXT};
+
+/// How long a note has to be quiet before its changes are reported
+pub const DEBOUNCE_WINDOW: Duration = Duration::from_millis(300);
+
+/// A change to a note on disk, after debouncing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum VaultEvent {
+    /// The note was created or its content changed
+    Changed {
+        note: String,
+    },
+    Deleted {
+        note: String,
+    },
+    Renamed {
+        from: String,
+        to: String,
+    },
+}
+
+impl VaultEvent {
+    /// Brings an index of the vault at root up to date with the change
+    pub fn apply(&self, root: &Path, index: &mut impl NoteIndex) {
+        match self {
+            VaultEvent::Changed { note } => match std::fs::read_to_string(root.join(note)) {
+                Ok(content) => index.update_note(note, &content),
+                Err(_) => index.remove_note(note),
+            },
+            VaultEvent::Deleted { note } => index.remove_note(note),
+            VaultEvent::Renamed { from, to } => {
+                index.remove_note(from);
+                VaultEvent::Changed { note: to.clone() }.apply(root, index);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Pending {
+    /// Something happened, what exactly is decided by looking at the disk
+    Touched,
+    /// Moved here from another note
+    RenamedFrom(String),
+}
+
+/// Collects raw filesystem events and turns them into one VaultEvent per note
+/// once the note has been quiet for the window. Editors often write a file in
+/// several steps, this keeps that from showing up as several changes.
+pub struct Debouncer {
+    root: PathBuf,
+    window: Duration,
+    pending: BTreeMap<String, (Pending, Instant)>,
+}
+
+impl Debouncer {
+    pub fn new(root: &Path, window: Duration) -> Self {
+        Debouncer {
+            root: root.to_path_buf(),
+            window,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Records a raw event from the watcher
+    pub fn feed(&mut self, event: &Event, now: Instant) {
+        let notes = event
+            .paths
+            .iter()
+            .map(|p| self.note(p))
+            .collect::<Vec<Option<String>>>();
+
+        match (&event.kind, notes.as_slice()) {
+            (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) => {
+                match (from, to) {
+                    (Some(from), Some(to)) => {
+                        // Renaming something that was itself just renamed keeps the original name
+                        let origin = match self.pending.remove(from) {
+                            Some((Pending::RenamedFrom(origin), _)) => origin,
+                            _ => from.clone(),
+                        };
+                        self.pending
+                            .insert(to.clone(), (Pending::RenamedFrom(origin), now));
+                    }
+                    // Moved out of the vault or into the trash, or an atomic save from a temp file
+                    (Some(note), None) | (None, Some(note)) => self.touch(note, now),
+                    (None, None) => {}
+                }
+            }
+            (EventKind::Access(_), _) => {}
+            _ => {
+                for note in notes.into_iter().flatten() {
+                    self.touch(&note, now);
+                }
+            }
+        }
+    }
+
+    fn touch(&mut self, note: &str, now: Instant) {
+        let entry = self
+            .pending
+            .entry(note.to_string())
+            .or_insert((Pending::Touched, now));
+        entry.1 = now;
+    }
+
+    /// Events for every note that has been quiet long enough
+    pub fn ready(&mut self, now: Instant) -> Vec<VaultEvent> {
+        let ready = self
+            .pending
+            .iter()
+            .filter(|(_, (_, seen))| now.duration_since(*seen) >= self.window)
+            .map(|(note, _)| note.clone())
+            .collect::<Vec<String>>();
+
+        let mut out = Vec::<VaultEvent>::new();
+        for note in ready.into_iter() {
+            let (pending, _) = self.pending.remove(&note).unwrap();
+            let exists = self.root.join(&note).is_file();
+            out.push(match (pending, exists) {
+                (Pending::RenamedFrom(from), true) => VaultEvent::Renamed { from, to: note },
+                (Pending::RenamedFrom(from), false) => VaultEvent::Deleted { note: from },
+                (Pending::Touched, true) => VaultEvent::Changed { note },
+                (Pending::Touched, false) => VaultEvent::Deleted { note },
+            });
+        }
+        out
+    }
+
+    /// Everything still pending, regardless of the window
+    pub fn flush(&mut self) -> Vec<VaultEvent> {
+        self.ready(Instant::now() + self.window)
+    }
+
+    /// Note id for a path, if it is a note in the vault (and not hidden, eg. in the trash)
+    fn note(&self, path: &Path) -> Option<String> {
+        if path.extension().and_then(|e| e.to_str()) != Some(NOTE_EXT) {
+            return None;
+        }
+        note_id(&self.root, path).filter(|id| !id.split('/').any(|part| part.starts_with('.')))
+    }
+}
+
+/// Watches a vault in the background. Stops when dropped.
+pub struct VaultWatcher {
+    watcher: Option<RecommendedWatcher>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl VaultWatcher {
+    /// Starts watching the vault, calling on_events with each batch of debounced events
+    pub fn start<F>(root: &Path, window: Duration, mut on_events: F) -> notify::Result<Self>
+    where
+        F: FnMut(Vec<VaultEvent>) + Send + 'static,
+    {
+        // Paths from the watcher are absolute, so the root has to be too
+        let root = root.canonicalize()?;
+        let (tx, rx) = mpsc::channel::<notify::Result<Event>>();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+
+        let thread = thread::spawn(move || {
+            let mut debouncer = Debouncer::new(&root, window);
+            loop {
+                match rx.recv_timeout(window / 2) {
+                    Ok(Ok(event)) => debouncer.feed(&event, Instant::now()),
+                    Ok(Err(e)) => eprintln!("vault watcher error: {}", e),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+
+                let events = debouncer.ready(Instant::now());
+                if !events.is_empty() {
+                    on_events(events);
+                }
+            }
+
+            let events = debouncer.flush();
+            if !events.is_empty() {
+                on_events(events);
+            }
+        });
+
+        Ok(VaultWatcher {
+            watcher: Some(watcher),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for VaultWatcher {
+    fn drop(&mut self) {
+        // Dropping the watcher closes the channel, which ends the thread
+        self.watcher.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Notes the app saved itself, so the events its own writes cause can be told
+/// apart from changes made by other programs
+#[derive(Debug, Default)]
+pub struct OwnWrites {
+    /// Hash of the content last saved to each note
+    written: HashMap<String, u64>,
+}
+
+impl OwnWrites {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Remembers content the app is about to write to the note
+    pub fn record(&mut self, note: &str, content: &str) {
+        self.written.insert(note.to_string(), hash(content));
+    }
+
+    /// Whether the note's content on disk is just what the app last saved. Once
+    /// another program changed it, the saved content is forgotten.
+    pub fn is_own(&mut self, note: &str, content: &str) -> bool {
+        match self.written.get(note) {
+            Some(h) if *h == hash(content) => true,
+            Some(_) => {
+                self.written.remove(note);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn forget(&mut self, note: &str) {
+        self.written.remove(note);
+    }
+}
+
+fn hash(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Merges a change made on disk into an editor buffer with unsaved edits. base is
+/// what the buffer was loaded from, disk is the new content. Returns None when
+/// both changed the same text, that is left for the user to resolve.
+pub fn rebase_buffer(base: &str, buffer: &str, disk: &str) -> Result<Option<String>, String> {
+    let parse = |s: &str| parse_string_tokens(s).map_err(|e| e.to_string());
+    let merged = merge_tokens(&parse(base)?, &parse(disk)?, &parse(buffer)?)?;
+    match merged.conflicts {
+        0 => Ok(Some(tokens_to_string(&merged.tokens))),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::mpsc::Receiver};
+
+    use notify::event::{CreateKind, DataChange, RemoveKind};
+
+    use super::*;
+
+    fn event(kind: EventKind, paths: &[&Path]) -> Event {
+        paths
+            .iter()
+            .fold(Event::new(kind), |e, p| e.add_path(p.to_path_buf()))
+    }
+
+    fn vault(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("tem-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn test_debouncer() {
+        let root = vault("watch-debounce");
+        let start = Instant::now();
+        let ms = |n: u64| start + Duration::from_millis(n);
+        let mut d = Debouncer::new(&root, Duration::from_millis(100));
+
+        // A burst of writes is reported once, after it settles
+        let a = root.join("a.md");
+        fs::write(&a, "a").unwrap();
+        let modify = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+        d.feed(&event(EventKind::Create(CreateKind::File), &[&a]), ms(0));
+        d.feed(&event(modify, &[&a]), ms(50));
+        assert_eq!(d.ready(ms(120)), vec![]);
+        assert_eq!(
+            d.ready(ms(150)),
+            vec![VaultEvent::Changed {
+                note: "a.md".into()
+            }]
+        );
+
+        // Atomic saves through a temp file look like a change, hidden files are ignored
+        let tmp = root.join("a.md.tmp");
+        let rename = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+        d.feed(&event(rename, &[&tmp, &a]), ms(200));
+        d.feed(&event(modify, &[&root.join(".trash/x.md")]), ms(200));
+        assert_eq!(
+            d.ready(ms(300)),
+            vec![VaultEvent::Changed {
+                note: "a.md".into()
+            }]
+        );
+
+        // Chained renames collapse into one, and a removed file is a delete
+        let b = root.join("b.md");
+        let c = root.join("c.md");
+        fs::rename(&a, &c).unwrap();
+        d.feed(&event(rename, &[&a, &b]), ms(400));
+        d.feed(&event(rename, &[&b, &c]), ms(410));
+        d.feed(
+            &event(
+                EventKind::Remove(RemoveKind::File),
+                &[&root.join("gone.md")],
+            ),
+            ms(410),
+        );
+        assert_eq!(
+            d.ready(ms(600)),
+            vec![
+                VaultEvent::Renamed {
+                    from: "a.md".into(),
+                    to: "c.md".into()
+                },
+                VaultEvent::Deleted {
+                    note: "gone.md".into()
+                },
+            ]
+        );
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    /// Waits for events until one matches
+    fn wait_for(rx: &Receiver<VaultEvent>, expected: VaultEvent) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if let Ok(e) = rx.recv_timeout(Duration::from_millis(100)) {
+                if e == expected {
+                    return;
+                }
+            }
+        }
+        panic!("never got {:?}", expected);
+    }
+
+    #[test]
+    fn test_watch_vault() {
+        let root = vault("watch-live");
+        let (tx, rx) = mpsc::channel::<VaultEvent>();
+        let watcher = VaultWatcher::start(&root, Duration::from_millis(50), move |events| {
+            for e in events.into_iter() {
+                let _ = tx.send(e);
+            }
+        })
+        .expect("watch failed");
+
+        fs::write(root.join("note.md"), "hello").unwrap();
+        wait_for(
+            &rx,
+            VaultEvent::Changed {
+                note: "note.md".into(),
+            },
+        );
+        fs::remove_file(root.join("note.md")).unwrap();
+        wait_for(
+            &rx,
+            VaultEvent::Deleted {
+                note: "note.md".into(),
+            },
+        );
+
+        drop(watcher);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_rebase_buffer() {
+        assert_eq!(
+            rebase_buffer(
+                "A car ate my dog.",
+                "A car ate my cat.",
+                "A red car ate my dog."
+            ),
+            Ok(Some("A red car ate my cat.".into()))
+        );
+
+        // Edits to the same words are left to the user
+        assert_eq!(
+            rebase_buffer(
+                "A car ate my dog.",
+                "A car ate my cat.",
+                "A car ate my fish."
+            ),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_own_writes() {
+        let mut own = OwnWrites::new();
+        own.record("a.md", "saved");
+        assert!(own.is_own("a.md", "saved"));
+        assert!(!own.is_own("b.md", "saved"));
+
+        // Changed by another program, even changing back isn't ours anymore
+        assert!(!own.is_own("a.md", "edited"));
+        assert!(!own.is_own("a.md", "saved"));
+    }
+}
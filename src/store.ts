@@ -1,10 +1,47 @@
 import { writable } from "svelte/store";
 
-interface OpenTab {
+export interface OpenTab {
     name: string;
+    /** Editor buffer, may have unsaved edits */
     content: string;
+    /** Content on disk when the tab was loaded or last saved */
+    saved: string;
+    /** Content on disk that conflicts with the unsaved edits, until the user picks one */
+    conflict?: string;
+}
+
+/** A tag in the sidebar tree, mirrors TagNode in vault/tags.rs */
+export interface TagNode {
+    name: string;
+    path: string;
+    count: number;
+    children: TagNode[];
+}
+
+/** A saved tag query, shown in the sidebar like a tag */
+export interface SavedQuery {
+    name: string;
+    query: string;
+}
+
+/** Parse error from query_notes, column is 1-based */
+export interface QueryError {
+    message: string;
+    column: number;
+}
+
+/** A search_notes result, mirrors SearchHit in vault/search.rs */
+export interface SearchHit {
+    note: string;
+    score: number;
+    snippet: { text: string; highlight: boolean }[];
 }
 
 export const activeDoc = writable<string | null>(null);
 
 export const openTabs = writable<OpenTab[]>([]);
+
+/** The sidebar's tag tree and saved queries, reloaded when the vault changes */
+export const tagTree = writable<TagNode[]>([]);
+
+export const savedQueries = writable<SavedQuery[]>([]);
@@ -0,0 +1,70 @@
+import { get } from "svelte/store";
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+import {
+    activeDoc,
+    openTabs,
+    savedQueries,
+    tagTree,
+    type OpenTab,
+    type SavedQuery,
+    type TagNode,
+} from "./store";
+
+interface NoteChanged {
+    note: string;
+    content: string;
+}
+
+/**
+ * Keeps open tabs in sync with notes changed by other programs. Tabs without
+ * unsaved edits just reload, otherwise the change is merged into the buffer.
+ * When both changed the same text the tab is marked as conflicting instead.
+ * The sidebar's tags and saved queries are reloaded whenever they change.
+ * Returns a function that stops listening.
+ */
+export async function watchVault(): Promise<() => void> {
+    const stops = await Promise.all([
+        listen<NoteChanged>("note-changed", async ({ payload }) => {
+            const tab = get(openTabs).find((t) => t.name === payload.note);
+            if (!tab || tab.saved === payload.content) return;
+
+            let content: string | null = payload.content;
+            if (tab.content !== tab.saved) {
+                content = await invoke<string | null>("rebase_buffer", {
+                    base: tab.saved,
+                    buffer: tab.content,
+                    disk: payload.content,
+                });
+            }
+            if (content === null) {
+                updateTab(payload.note, (t) => ({ ...t, conflict: payload.content }));
+            } else {
+                updateTab(payload.note, (t) => ({ ...t, content, saved: payload.content, conflict: undefined }));
+            }
+        }),
+        listen<{ from: string; to: string }>("note-renamed", ({ payload }) => {
+            updateTab(payload.from, (t) => ({ ...t, name: payload.to }));
+            activeDoc.update((d) => (d === payload.from ? payload.to : d));
+        }),
+        listen<{ note: string }>("note-deleted", ({ payload }) => {
+            // Keep the buffer so nothing is lost, it gets written back on the next save
+            updateTab(payload.note, (t) => ({ ...t, saved: "" }));
+        }),
+        listen("tags-changed", () => loadTags()),
+        listen("queries-changed", () => loadQueries()),
+    ]);
+    return () => stops.forEach((stop) => stop());
+}
+
+function updateTab(name: string, f: (tab: OpenTab) => OpenTab) {
+    openTabs.update((tabs) => tabs.map((t) => (t.name === name ? f(t) : t)));
+}
+
+export async function loadTags() {
+    tagTree.set(await invoke<TagNode[]>("load_tags"));
+}
+
+export async function loadQueries() {
+    savedQueries.set(await invoke<SavedQuery[]>("list_saved_queries"));
+}